@@ -1,6 +1,8 @@
 use iced::widget::{button, column, container, row, scrollable, text, text_input, Column};
 use iced::{alignment, Element, Length, Padding, Sandbox};
-use sandman_share::config::SandmanDirectory;
+use sandman_share::config::{
+    HashAlgorithm, HistoryBackend, SandmanDirectory, SpecialFilePolicy, SymlinkPolicy,
+};
 
 #[derive(Clone, Debug)]
 pub enum Inputs {
@@ -93,8 +95,29 @@ impl Sandbox for SandmanConfigApp {
                     prefix: self.prefix_entry.clone(),
                     bucket: self.bucket_entry.clone(),
                     interval: self.interval_entry.clone().parse().unwrap(),
+                    schedule: None,
+                    time_zone: None,
+                    watch: false,
+                    debounce: None,
                     start_time: self.start_entry.clone().parse().unwrap(),
                     cleanable: false,
+                    hash_algorithm: HashAlgorithm::default(),
+                    history_backend: HistoryBackend::default(),
+                    honor_gitignore: false,
+                    honor_cachedir_tag: false,
+                    include: vec![],
+                    min_size: None,
+                    max_size: None,
+                    min_age: None,
+                    max_age: None,
+                    symlinks: SymlinkPolicy::default(),
+                    special_files: SpecialFilePolicy::default(),
+                    one_file_system: false,
+                    pre_hook: None,
+                    post_hook: None,
+                    on_failure: None,
+                    hook_timeout: None,
+                    pre_hook_optional: false,
                 };
                 self.directories.push(dir);
             }
@@ -102,7 +125,7 @@ impl Sandbox for SandmanConfigApp {
         }
     }
 
-    fn view(&self) -> Element<Self::Message> {
+    fn view(&self) -> Element<'_, Self::Message> {
         container(
             column!(
                 item_list_view(&self.directories),
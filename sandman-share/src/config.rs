@@ -1,6 +1,9 @@
 use async_trait::async_trait;
 use rusoto_credential::{AwsCredentials, CredentialsError, ProvideAwsCredentials};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
 
 /// Main configuration struct for the application.
 #[derive(Deserialize)]
@@ -10,10 +13,111 @@ pub struct Config {
 
     /// Directories configuration details.
     pub directories: DirectoriesConfig,
+
+    /// Embedded HTTP listener serving metrics and health checks, disabled when absent.
+    #[serde(default)]
+    pub http: Option<HttpConfig>,
+
+    /// Notifications sent about the results of runs.
+    #[serde(default)]
+    pub notifications: NotificationsConfig,
+}
+
+/// Notifications sent about the results of runs.
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct NotificationsConfig {
+    /// Webhooks posted to after runs.
+    #[serde(default)]
+    pub webhooks: Vec<WebhookConfig>,
+}
+
+/// A webhook and the runs it is told about.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct WebhookConfig {
+    /// URL the notification is posted to.
+    pub url: String,
+
+    /// Payload the webhook expects.
+    #[serde(default)]
+    pub format: WebhookFormat,
+
+    /// Whether failed runs are notified
+    #[serde(default)]
+    pub on_failure: bool,
+
+    /// Whether successful runs are notified
+    #[serde(default)]
+    pub on_success: bool,
+
+    /// Seconds without a successful run after which a backup is notified as stale, once until it
+    /// succeeds again
+    #[serde(default)]
+    pub stale_after: Option<u64>,
+
+    /// Names of the backups notified, every backup when empty
+    #[serde(default)]
+    pub backups: Vec<String>,
+}
+
+impl WebhookConfig {
+    /// Whether this webhook is told about the runs of the backup `name`.
+    pub fn applies_to(&self, name: &str) -> bool {
+        self.backups.is_empty() || self.backups.iter().any(|backup| backup == name)
+    }
+}
+
+/// Payloads a webhook can be sent.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum WebhookFormat {
+    /// A JSON object describing the run, for custom receivers.
+    #[default]
+    Json,
+
+    /// A message for Slack incoming webhooks.
+    Slack,
+
+    /// A message for Discord webhooks.
+    Discord,
+}
+
+impl fmt::Display for WebhookFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name: &str = match self {
+            WebhookFormat::Json => "json",
+            WebhookFormat::Slack => "slack",
+            WebhookFormat::Discord => "discord",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for WebhookFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "json" => Ok(WebhookFormat::Json),
+            "slack" => Ok(WebhookFormat::Slack),
+            "discord" => Ok(WebhookFormat::Discord),
+            _ => Err(format!("Unknown webhook format: {}", s)),
+        }
+    }
+}
+
+/// Embedded HTTP listener details.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct HttpConfig {
+    /// Address and port to listen on, such as `127.0.0.1:9184`.
+    pub listen: String,
+
+    /// Seconds a run may be overdue before `/healthz` reports the backup as unhealthy
+    #[serde(default)]
+    pub overdue_grace: Option<u64>,
 }
 
 /// AWS configuration details.
-#[derive(Deserialize, Clone)]
+#[derive(Deserialize, Clone, PartialEq)]
 pub struct AwsConfig {
     /// AWS access key ID.
     pub aws_access_key_id: String,
@@ -46,7 +150,7 @@ pub struct DirectoriesConfig {
 }
 
 /// Details of a directory to be backed up.
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone, PartialEq)]
 pub struct SandmanDirectory {
     /// Designator for this backup
     pub name: String,
@@ -57,6 +161,23 @@ pub struct SandmanDirectory {
     /// Interval for backups
     pub interval: u64,
 
+    /// Cron or calendar expression deciding when backups run, replacing `interval` when set
+    #[serde(default)]
+    pub schedule: Option<String>,
+
+    /// IANA time zone `schedule` is evaluated in, the local time zone when unset
+    #[serde(default)]
+    pub time_zone: Option<String>,
+
+    /// Whether changes are backed up as soon as they are reported by the filesystem, with the
+    /// schedule rescanning the whole directory as a safety net
+    #[serde(default)]
+    pub watch: bool,
+
+    /// Seconds without further changes a watched directory waits for before backing up
+    #[serde(default)]
+    pub debounce: Option<u64>,
+
     /// Start time (unix timestamp)
     pub start_time: u64,
 
@@ -68,18 +189,219 @@ pub struct SandmanDirectory {
 
     /// Whether files in this directory should be deleted on a successful upload
     pub cleanable: bool,
+
+    /// Algorithm used to fingerprint files for change detection
+    #[serde(default)]
+    pub hash_algorithm: HashAlgorithm,
+
+    /// Storage used for this backup's history
+    #[serde(default)]
+    pub history_backend: HistoryBackend,
+
+    /// Whether `.gitignore` files are honoured alongside `.sandmanignore` files
+    #[serde(default)]
+    pub honor_gitignore: bool,
+
+    /// Whether directories containing a `CACHEDIR.TAG` marker are skipped
+    #[serde(default)]
+    pub honor_cachedir_tag: bool,
+
+    /// Globs a file must match to be backed up, every file is included when empty
+    #[serde(default)]
+    pub include: Vec<String>,
+
+    /// Smallest file size in bytes to back up
+    #[serde(default)]
+    pub min_size: Option<u64>,
+
+    /// Largest file size in bytes to back up
+    #[serde(default)]
+    pub max_size: Option<u64>,
+
+    /// Minimum time in seconds since a file was last modified for it to be backed up
+    #[serde(default)]
+    pub min_age: Option<u64>,
+
+    /// Maximum time in seconds since a file was last modified for it to be backed up
+    #[serde(default)]
+    pub max_age: Option<u64>,
+
+    /// How symbolic links inside the directory are backed up
+    #[serde(default)]
+    pub symlinks: SymlinkPolicy,
+
+    /// How FIFOs, sockets and device files inside the directory are backed up
+    #[serde(default)]
+    pub special_files: SpecialFilePolicy,
+
+    /// Whether directories on other filesystems than the backed up directory are skipped
+    #[serde(default)]
+    pub one_file_system: bool,
+
+    /// Shell command run before each full run, such as a database dump into the directory
+    #[serde(default)]
+    pub pre_hook: Option<String>,
+
+    /// Shell command run after each full run, whatever its outcome
+    #[serde(default)]
+    pub post_hook: Option<String>,
+
+    /// Shell command run after each full run which failed
+    #[serde(default)]
+    pub on_failure: Option<String>,
+
+    /// Seconds a hook may run before it is killed and counts as failed
+    #[serde(default)]
+    pub hook_timeout: Option<u64>,
+
+    /// Whether the run goes ahead when the pre-hook fails, rather than being aborted
+    #[serde(default)]
+    pub pre_hook_optional: bool,
+}
+
+/// Ways symbolic links found while scanning a backup are handled.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SymlinkPolicy {
+    /// Back up the link itself, recording its target without following it.
+    #[default]
+    Store,
+
+    /// Back up what the link points to, skipping links which lead back into a directory being scanned.
+    Follow,
+
+    /// Leave links out of the backup.
+    Skip,
+}
+
+impl fmt::Display for SymlinkPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name: &str = match self {
+            SymlinkPolicy::Store => "store",
+            SymlinkPolicy::Follow => "follow",
+            SymlinkPolicy::Skip => "skip",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for SymlinkPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "store" => Ok(SymlinkPolicy::Store),
+            "follow" => Ok(SymlinkPolicy::Follow),
+            "skip" => Ok(SymlinkPolicy::Skip),
+            _ => Err(format!("Unknown symlink policy: {}", s)),
+        }
+    }
+}
+
+/// Ways FIFOs, sockets and device files found while scanning a backup are handled.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SpecialFilePolicy {
+    /// Leave special files out of the backup.
+    #[default]
+    Skip,
+
+    /// Record the kind and device number of special files, without reading them.
+    Record,
+}
+
+impl fmt::Display for SpecialFilePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name: &str = match self {
+            SpecialFilePolicy::Skip => "skip",
+            SpecialFilePolicy::Record => "record",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for SpecialFilePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "skip" => Ok(SpecialFilePolicy::Skip),
+            "record" => Ok(SpecialFilePolicy::Record),
+            _ => Err(format!("Unknown special file policy: {}", s)),
+        }
+    }
+}
+
+/// Storage backends available for a backup's history.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum HistoryBackend {
+    /// A single JSON document rewritten on every run.
+    #[default]
+    Json,
+
+    /// An embedded SQLite database updated incrementally, suited to very large trees.
+    Sqlite,
+}
+
+impl fmt::Display for HistoryBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HistoryBackend::Json => write!(f, "json"),
+            HistoryBackend::Sqlite => write!(f, "sqlite"),
+        }
+    }
+}
+
+/// Algorithms available for fingerprinting backed up files.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    /// SHA-256, the original and default algorithm.
+    #[default]
+    Sha256,
+
+    /// BLAKE3, considerably faster than SHA-256 on modern CPUs.
+    Blake3,
+
+    /// XXH3 (128 bit), non-cryptographic and only suitable for change detection.
+    Xxh3,
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name: &str = match self {
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Blake3 => "blake3",
+            HashAlgorithm::Xxh3 => "xxh3",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "sha256" => Ok(HashAlgorithm::Sha256),
+            "blake3" => Ok(HashAlgorithm::Blake3),
+            "xxh3" => Ok(HashAlgorithm::Xxh3),
+            _ => Err(format!("Unknown hash algorithm: {}", s)),
+        }
+    }
 }
 
 pub struct SandmanUploadedFile {
     /// Local file path
-    pub path: String,
+    pub path: PathBuf,
 
     /// Remote name
     pub remote_name: String,
 }
 
 impl SandmanUploadedFile {
-    pub fn new(path: String, remote_name: String) -> Self {
+    pub fn new(path: PathBuf, remote_name: String) -> Self {
         SandmanUploadedFile { path, remote_name }
     }
 }
@@ -1,6 +1,10 @@
 pub const SANDMAN_HISTORY: &str = ".sandman_history";
+pub const SANDMAN_HISTORY_DB: &str = ".sandman_history.sqlite";
 pub const SANDMAN_CONFIG: &str = ".sandman_config.toml";
 pub const SANDMAN_IGNORE: &str = ".sandmanignore";
+pub const SANDMAN_SOCKET: &str = "sandman.sock";
+pub const SANDMAN_LOG: &str = "sandman.log";
+pub const SANDMAN_RUNS: &str = "runs.jsonl";
 pub(crate) static BASE_CONFIG: &str = r#"
 title = "Example Sandman Config"
 
@@ -28,6 +32,7 @@ start_time = 0"#;
 
 pub(crate) static BASE_GLOBAL_IGNORE: &str = r#"
 .sandman_history
+.sandman_history.sqlite
 .sandman_config.toml
 .sandmanignore
 "#;
@@ -1,4 +1,6 @@
-use crate::consts::{BASE_CONFIG, BASE_GLOBAL_IGNORE, SANDMAN_CONFIG, SANDMAN_IGNORE};
+use crate::consts::{
+    BASE_CONFIG, BASE_GLOBAL_IGNORE, SANDMAN_CONFIG, SANDMAN_IGNORE, SANDMAN_SOCKET,
+};
 use directories::ProjectDirs;
 use log::info;
 use std::ffi::OsString;
@@ -12,9 +14,67 @@ pub fn config_dir() -> OsString {
     OsString::from("./")
 }
 
+pub fn state_dir() -> OsString {
+    if let Some(project_directories) = ProjectDirs::from("", "", "Sandman") {
+        let state_dir: &Path = project_directories
+            .state_dir()
+            .unwrap_or_else(|| project_directories.data_local_dir());
+        return OsString::from(state_dir);
+    }
+    OsString::from("./")
+}
+
+/// Returns the directory holding the state (history, locks and reports) of a single backup,
+/// creating it if it does not yet exist. Characters of `name` which are not safe in a file name
+/// are replaced with `_`.
+pub fn backup_state_dir(name: &str) -> PathBuf {
+    let path: PathBuf = backup_state_path(name);
+    if !path.exists() {
+        fs::create_dir_all(&path)
+            .unwrap_or_else(|e| panic!("Error creating state directory {:?}: {}", path, e));
+    }
+    path
+}
+
+/// Returns the directory holding the state of a single backup, as `backup_state_dir` does but
+/// without creating it.
+pub fn backup_state_path(name: &str) -> PathBuf {
+    Path::new(&state_dir())
+        .join("backups")
+        .join(backup_state_key(name))
+}
+
+/// Returns the name of the state directory of a backup: `name` with the characters which are not
+/// safe in a file name replaced with `_`. Distinct names can share a key, such as `a b` and `a_b`,
+/// which the configuration check rejects.
+pub fn backup_state_key(name: &str) -> String {
+    let mut key: String = name
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' => c,
+            _ => '_',
+        })
+        .collect();
+    if key.is_empty() || key.starts_with('.') {
+        key.insert(0, '_');
+    }
+    key
+}
+
+/// Returns the default location of the control socket, in the state directory, creating the
+/// directory if it does not yet exist.
+pub fn control_socket() -> PathBuf {
+    let path: PathBuf = PathBuf::from(state_dir());
+    if !path.exists() {
+        fs::create_dir_all(&path)
+            .unwrap_or_else(|e| panic!("Error creating state directory {:?}: {}", path, e));
+    }
+    path.join(SANDMAN_SOCKET)
+}
+
 pub fn file_in_config(file_name: &str) -> PathBuf {
     let config_path: OsString = config_dir();
-    return Path::new(&config_path).join(file_name);
+    Path::new(&config_path).join(file_name)
 }
 
 pub fn verify_config_existence() {
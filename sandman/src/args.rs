@@ -1,35 +1,315 @@
-use clap_derive::Parser;
+use crate::logging::LogFormat;
+use clap_derive::{Args as ClapArgs, Parser, Subcommand};
+use sandman_share::config::{
+    HashAlgorithm, HistoryBackend, NotificationsConfig, SandmanDirectory, SpecialFilePolicy,
+    SymlinkPolicy, WebhookConfig,
+};
+use std::path::PathBuf;
 
 /// Command-line arguments for the Sandman application.
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 pub(crate) struct Args {
-    /// Local directory to process.
-    #[arg(long, default_value_t = String::new())]
-    pub(crate) local_directory: String,
+    /// Verbosity flag for logging.
+    #[arg(short, long, default_value_t = false, global = true)]
+    pub(crate) verbosity: bool,
 
-    /// File path to store SHA file.
-    #[arg(long, default_value_t = String::new())]
-    sha_file: String,
+    /// Location of the configuration file, the system default unless given.
+    #[arg(long, default_value_t = String::new(), global = true)]
+    pub(crate) config_path: String,
 
-    /// S3 bucket name for backup.
-    #[arg(long, default_value_t = String::new())]
-    pub(crate) s3_bucket: String,
+    /// Format of log lines (text or json).
+    #[arg(long, default_value_t = LogFormat::Text, global = true)]
+    pub(crate) log_format: LogFormat,
 
-    /// S3 bucket prefix for backup.
-    #[arg(long, default_value_t = String::new())]
-    pub(crate) bucket_prefix: String,
+    /// Also write the log of every backup to a rotating file in its state directory.
+    #[arg(long, default_value_t = false, global = true)]
+    pub(crate) log_files: bool,
 
-    /// Verbosity flag for logging.
-    #[arg(short, long, default_value_t = false)]
-    pub(crate) verbosity: bool,
+    /// Location of the control socket, in the state directory unless given.
+    #[arg(long, global = true)]
+    pub(crate) control_socket: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub(crate) command: Command,
+}
 
-    /// Flag to indicate if configuration file should be used.
+/// Flags narrowing which files a backup described on the command line includes. Configured
+/// backups take these from the configuration file instead.
+#[derive(ClapArgs, Debug)]
+pub(crate) struct SelectionArgs {
+    /// Honour `.gitignore` files alongside `.sandmanignore` files.
     #[arg(long, default_value_t = false)]
-    pub(crate) with_config: bool,
+    pub(crate) honor_gitignore: bool,
 
-    #[arg(long, default_value_t = String::new())]
-    pub(crate) config_path: String,
+    /// Skip directories marked with a `CACHEDIR.TAG` file.
+    #[arg(long, default_value_t = false)]
+    pub(crate) honor_cachedir_tag: bool,
+
+    /// Only back up files matching this glob, may be repeated.
+    #[arg(long)]
+    pub(crate) include: Vec<String>,
+
+    /// Smallest file size in bytes to back up.
+    #[arg(long)]
+    pub(crate) min_size: Option<u64>,
+
+    /// Largest file size in bytes to back up.
+    #[arg(long)]
+    pub(crate) max_size: Option<u64>,
+
+    /// Only back up files last modified at least this many seconds ago.
+    #[arg(long)]
+    pub(crate) min_age: Option<u64>,
+
+    /// Only back up files last modified at most this many seconds ago.
+    #[arg(long)]
+    pub(crate) max_age: Option<u64>,
+
+    /// How symbolic links are backed up (store, follow or skip).
+    #[arg(long, default_value_t = SymlinkPolicy::Store)]
+    pub(crate) symlinks: SymlinkPolicy,
+
+    /// How FIFOs, sockets and device files are backed up (skip or record).
+    #[arg(long, default_value_t = SpecialFilePolicy::Skip)]
+    pub(crate) special_files: SpecialFilePolicy,
+
+    /// Do not descend into directories on other filesystems.
+    #[arg(long, default_value_t = false)]
+    pub(crate) one_file_system: bool,
+}
+
+impl SelectionArgs {
+    /// Applies the flags to the `GatherArgs` of a backup described on the command line.
+    pub(crate) fn apply(&self, gather_args: &mut GatherArgs) {
+        gather_args.honor_gitignore = self.honor_gitignore;
+        gather_args.honor_cachedir_tag = self.honor_cachedir_tag;
+        gather_args.include = self.include.clone();
+        gather_args.min_size = self.min_size;
+        gather_args.max_size = self.max_size;
+        gather_args.min_age = self.min_age;
+        gather_args.max_age = self.max_age;
+        gather_args.symlinks = self.symlinks;
+        gather_args.special_files = self.special_files;
+        gather_args.one_file_system = self.one_file_system;
+    }
+}
+
+/// The commands of Sandman.
+#[derive(Subcommand, Debug)]
+pub(crate) enum Command {
+    /// Run every configured backup on its schedule until stopped, reloading the configuration file
+    /// whenever it changes.
+    Daemon {
+        /// Seconds to wait for in-flight backups to finish when shutting down.
+        #[arg(long, default_value_t = 30)]
+        shutdown_timeout: u64,
+    },
+
+    /// Run configured backups, or a directory given with `--local-directory`, once and exit.
+    Run {
+        /// Names of backups in the configuration file, every configured backup when none are given.
+        #[arg(conflicts_with_all = ["local_directory", "SelectionArgs"])]
+        names: Vec<String>,
+
+        /// Local directory to back up instead of configured backups.
+        #[arg(long)]
+        local_directory: Option<String>,
+
+        /// S3 bucket to upload the local directory to.
+        #[arg(long, requires = "local_directory")]
+        s3_bucket: Option<String>,
+
+        /// S3 bucket prefix the local directory is uploaded under.
+        #[arg(long, default_value_t = String::new(), requires = "local_directory")]
+        bucket_prefix: String,
+
+        /// File to keep the history of the local directory in, in the state directory unless given.
+        #[arg(long, requires = "local_directory")]
+        sha_file: Option<PathBuf>,
+
+        /// Hash algorithm used for change detection (sha256, blake3 or xxh3).
+        #[arg(long, requires = "local_directory")]
+        hash_algorithm: Option<HashAlgorithm>,
+
+        #[command(flatten)]
+        selection: SelectionArgs,
+
+        /// Wait for another instance backing up the same directory instead of failing.
+        #[arg(long, default_value_t = false)]
+        wait_for_lock: bool,
+
+        /// Report what would be uploaded, without uploading, writing the history or removing files.
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
+
+        /// Seconds to wait for in-flight backups to finish when interrupted.
+        #[arg(long, default_value_t = 30)]
+        shutdown_timeout: u64,
+    },
+
+    /// Restore the files of a backup as they were at a snapshot.
+    Restore {
+        /// Name of a backup in the configuration file.
+        name: String,
+
+        /// Snapshot to restore, the most recent one unless given.
+        #[arg(long)]
+        snapshot: Option<String>,
+
+        /// Directory to restore the files into.
+        #[arg(long)]
+        to: PathBuf,
+
+        /// Only restore this file or directory, relative to the backup root, may be repeated.
+        #[arg(long)]
+        path: Vec<PathBuf>,
+
+        /// Replace files which already exist in the target directory.
+        #[arg(long, default_value_t = false)]
+        overwrite: bool,
+    },
+
+    /// List the snapshots of a backup in its bucket, with the files and bytes uploaded in each.
+    Snapshots {
+        /// Name of a backup in the configuration file.
+        name: String,
+
+        /// Print the snapshots as JSON.
+        #[arg(long, default_value_t = false)]
+        json: bool,
+    },
+
+    /// Check that every file recorded in the history of backups is present in their bucket.
+    Verify {
+        /// Names of backups in the configuration file, every configured backup when none are given.
+        names: Vec<String>,
+    },
+
+    /// Delete the objects of a backup which no longer belong to its most recent snapshots.
+    Prune {
+        /// Name of a backup in the configuration file.
+        name: String,
+
+        /// Number of most recent snapshots which stay restorable.
+        #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+        keep: u64,
+
+        /// Report what would be deleted without deleting anything.
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
+    },
+
+    /// List every backup of a running daemon with its state, next run and last result.
+    Status {
+        /// Print the status as JSON.
+        #[arg(long, default_value_t = false)]
+        json: bool,
+    },
+
+    /// Inspect the configuration file.
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+
+    /// List the files a backup would include, with their sizes and totals.
+    LsFiles {
+        /// Name of a backup in the configuration file.
+        #[arg(long, required_unless_present = "local_directory", conflicts_with_all = ["local_directory", "SelectionArgs"])]
+        name: Option<String>,
+
+        /// Local directory to list instead of a configured backup.
+        #[arg(long)]
+        local_directory: Option<String>,
+
+        #[command(flatten)]
+        selection: SelectionArgs,
+    },
+
+    /// Report which ignore file, line and pattern decides whether each path is backed up.
+    CheckIgnore {
+        /// Name of a backup in the configuration file.
+        #[arg(long, required_unless_present = "local_directory", conflicts_with_all = ["local_directory", "SelectionArgs"])]
+        name: Option<String>,
+
+        /// Local directory the paths belong to instead of a configured backup.
+        #[arg(long)]
+        local_directory: Option<String>,
+
+        #[command(flatten)]
+        selection: SelectionArgs,
+
+        /// Paths to check, absolute or relative to the backup root.
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+    },
+
+    /// List the past runs of backups, or show every detail of one run.
+    History {
+        /// Name of a backup in the configuration file, every configured backup otherwise.
+        #[arg(long)]
+        name: Option<String>,
+
+        /// Show the run with this ID, or start of an ID, instead of listing runs.
+        #[arg(long)]
+        run: Option<String>,
+
+        /// Number of most recent runs listed per backup.
+        #[arg(long, default_value_t = 20)]
+        limit: usize,
+
+        /// Print the runs as JSON.
+        #[arg(long, default_value_t = false)]
+        json: bool,
+
+        /// Show when these files, absolute or relative to the backup root, were last uploaded
+        /// instead of listing runs. Needs the sqlite history backend.
+        #[arg(long, num_args = 1.., requires = "name", conflicts_with_all = ["run", "json"])]
+        file: Vec<PathBuf>,
+    },
+
+    /// Inspect and steer a running `sandman daemon` through its control socket.
+    Ctl {
+        #[command(subcommand)]
+        command: CtlCommand,
+    },
+}
+
+/// Inspections of the configuration file run by `sandman config`.
+#[derive(Subcommand, Debug)]
+pub(crate) enum ConfigCommand {
+    /// Validate the configuration file, reporting every problem found.
+    Check,
+
+    /// Print every configured backup as Sandman understands it.
+    Show,
+
+    /// Print the location of the configuration file.
+    Path,
+}
+
+/// Requests sent to a running Sandman by `sandman ctl`.
+#[derive(Subcommand, Debug)]
+pub(crate) enum CtlCommand {
+    /// List every backup with its state, next run and last result.
+    Status {
+        /// Print the status as JSON.
+        #[arg(long, default_value_t = false)]
+        json: bool,
+    },
+
+    /// Run a backup now instead of waiting for its schedule.
+    Trigger { name: String },
+
+    /// Stop a backup from starting runs until it is resumed.
+    Pause { name: String },
+
+    /// Let a paused backup start runs again.
+    Resume { name: String },
+
+    /// End the run in progress of a backup, keeping the files uploaded so far.
+    Cancel { name: String },
 }
 
 #[derive(Clone)]
@@ -41,6 +321,36 @@ pub(crate) struct GatherArgs {
     pub(crate) interval: u64,
     pub(crate) start_time: u64,
     pub(crate) cleanable: bool,
+    /// Cron or calendar expression replacing `interval`.
+    pub(crate) schedule: Option<String>,
+    pub(crate) time_zone: Option<String>,
+    /// Whether changes reported by the filesystem trigger backups of the changed paths.
+    pub(crate) watch: bool,
+    pub(crate) debounce: Option<u64>,
+    pub(crate) hash_algorithm: HashAlgorithm,
+    pub(crate) history_backend: HistoryBackend,
+    /// Explicit location of the history, otherwise kept in the backup's state directory.
+    pub(crate) history_path: Option<PathBuf>,
+    /// Whether to wait for another instance holding this backup's lock rather than giving up.
+    pub(crate) wait_for_lock: bool,
+    pub(crate) honor_gitignore: bool,
+    pub(crate) honor_cachedir_tag: bool,
+    pub(crate) include: Vec<String>,
+    pub(crate) min_size: Option<u64>,
+    pub(crate) max_size: Option<u64>,
+    pub(crate) min_age: Option<u64>,
+    pub(crate) max_age: Option<u64>,
+    pub(crate) symlinks: SymlinkPolicy,
+    pub(crate) special_files: SpecialFilePolicy,
+    pub(crate) one_file_system: bool,
+    /// Shell command run before each full run, aborting it when it fails unless optional.
+    pub(crate) pre_hook: Option<String>,
+    pub(crate) post_hook: Option<String>,
+    pub(crate) on_failure: Option<String>,
+    pub(crate) hook_timeout: Option<u64>,
+    pub(crate) pre_hook_optional: bool,
+    /// Webhooks told about this backup's runs.
+    pub(crate) webhooks: Vec<WebhookConfig>,
 }
 
 impl GatherArgs {
@@ -61,6 +371,89 @@ impl GatherArgs {
             interval,
             start_time,
             cleanable,
+            schedule: None,
+            time_zone: None,
+            watch: false,
+            debounce: None,
+            hash_algorithm: HashAlgorithm::default(),
+            history_backend: HistoryBackend::default(),
+            history_path: None,
+            wait_for_lock: true,
+            honor_gitignore: false,
+            honor_cachedir_tag: false,
+            include: vec![],
+            min_size: None,
+            max_size: None,
+            min_age: None,
+            max_age: None,
+            symlinks: SymlinkPolicy::default(),
+            special_files: SpecialFilePolicy::default(),
+            one_file_system: false,
+            pre_hook: None,
+            post_hook: None,
+            on_failure: None,
+            hook_timeout: None,
+            pre_hook_optional: false,
+            webhooks: vec![],
+        }
+    }
+
+    /// Builds the `GatherArgs` of a configured backup along with the webhooks told about its runs.
+    ///
+    /// # Arguments
+    ///
+    /// * `directory` - The backup's `SandmanDirectory`.
+    /// * `notifications` - The `[notifications]` section of the configuration file.
+    pub(crate) fn configured(
+        directory: SandmanDirectory,
+        notifications: &NotificationsConfig,
+    ) -> Self {
+        let webhooks: Vec<WebhookConfig> = notifications
+            .webhooks
+            .iter()
+            .filter(|webhook| webhook.applies_to(&directory.name))
+            .cloned()
+            .collect();
+        GatherArgs {
+            webhooks,
+            ..GatherArgs::from(directory)
+        }
+    }
+}
+
+impl From<SandmanDirectory> for GatherArgs {
+    fn from(directory: SandmanDirectory) -> Self {
+        GatherArgs {
+            schedule: directory.schedule,
+            time_zone: directory.time_zone,
+            watch: directory.watch,
+            debounce: directory.debounce,
+            hash_algorithm: directory.hash_algorithm,
+            history_backend: directory.history_backend,
+            honor_gitignore: directory.honor_gitignore,
+            honor_cachedir_tag: directory.honor_cachedir_tag,
+            include: directory.include,
+            min_size: directory.min_size,
+            max_size: directory.max_size,
+            min_age: directory.min_age,
+            max_age: directory.max_age,
+            symlinks: directory.symlinks,
+            special_files: directory.special_files,
+            one_file_system: directory.one_file_system,
+            pre_hook: directory.pre_hook,
+            post_hook: directory.post_hook,
+            on_failure: directory.on_failure,
+            hook_timeout: directory.hook_timeout,
+            pre_hook_optional: directory.pre_hook_optional,
+            ..GatherArgs::new(
+                directory.name,
+                directory.directory,
+                directory.bucket,
+                directory.prefix,
+                directory.interval,
+                directory.start_time,
+                directory.cleanable,
+            )
         }
     }
 }
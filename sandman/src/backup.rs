@@ -1,81 +1,186 @@
 use crate::args::GatherArgs;
+use crate::entry::{EntryKind, SparseReader};
+use crate::keys::{decode_key, mark_encoded};
+use crate::remote::s3_client;
 use crate::sha::ShaFile;
+use bytes::Bytes;
 use chrono::prelude::*;
-use log::debug;
-use log::error;
-use rusoto_core::{HttpClient, Region, RusotoError};
+use futures::stream::{self, Stream};
+use rusoto_core::RusotoError;
 use rusoto_s3::PutObjectError;
 use rusoto_s3::{PutObjectOutput, PutObjectRequest, S3Client, StreamingBody, S3};
 use sandman_share::config::{AwsConfig, SandmanUploadedFile};
 use std::error::Error;
-use std::str::FromStr;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
 use tokio::fs::File;
 use tokio::io::AsyncReadExt;
+use tokio_util::sync::CancellationToken;
+use tracing::debug;
+use tracing::error;
+use tracing::info;
+
+/// Size of the chunks the objects of sparse files are streamed in.
+const SPARSE_CHUNK: usize = 1 << 20;
+
+/// Creates the name of a new snapshot from the current time, used as the key segment that every
+/// file uploaded in one run shares.
+pub(crate) fn snapshot_name() -> String {
+    let now: DateTime<Utc> = Utc::now();
+    now.format("%Y-%m-%d--%H-%M-%S").to_string()
+}
+
+/// The outcome of the uploads of a run.
+pub(crate) struct Uploads {
+    /// The files which were uploaded.
+    pub(crate) files: Vec<SandmanUploadedFile>,
+    /// Local paths of the uploaded regular and sparse files, which cleanup may remove.
+    pub(crate) removable: Vec<PathBuf>,
+    /// Bytes of the uploaded objects.
+    pub(crate) bytes: u64,
+    /// Number of uploads which failed.
+    pub(crate) errors: usize,
+    /// Files which could not be read or uploaded, with the reason.
+    pub(crate) failures: Vec<String>,
+}
 
 /// Performs a backup of the files in the given SHA file difference to the specified S3 bucket.
+/// Files which could not be uploaded are removed from `diff`, leaving only those the history may
+/// record. Once `stop` is cancelled the upload in flight is finished and no further ones are
+/// started.
 ///
 /// # Arguments
 ///
 /// * `diff` - A `ShaFile` representing the differences in files.
+/// * `snapshot` - Name of the snapshot the files are uploaded under.
 /// * `args` - GatherArgs carrying the target bucket location for backup.
 /// * `credentials` - Optional AWS credentials configuration.
+/// * `stop` - Token cancelled when the run is cancelled or Sandman is shutting down.
 ///
 /// # Returns
 ///
-/// A `Result` which is `Ok` if the backup was successful, or an error if it failed.
+/// A `Result` holding the `Uploads` of the backup, or an error if it failed.
 pub(crate) async fn backup(
-    diff: ShaFile,
+    diff: &mut ShaFile,
+    snapshot: &str,
     args: &GatherArgs,
     credentials: &Option<AwsConfig>,
-) -> Result<Vec<SandmanUploadedFile>, Box<dyn Error>> {
-    let now: DateTime<Utc> = Utc::now();
-    let formatted_time = now.format("%Y-%m-%d--%H-%M-%S").to_string();
-
+    stop: &CancellationToken,
+) -> Result<Uploads, Box<dyn Error>> {
     // Create the S3 client using provided credentials or default region
-    let client: S3Client = match credentials {
-        None => S3Client::new(Region::UsEast1),
-        Some(credentials) => {
-            let region: Region =
-                Region::from_str(&credentials.aws_default_region).expect("Invalid region string");
-            S3Client::new_with(HttpClient::new().unwrap(), credentials.clone(), region)
-        }
-    };
+    let client: S3Client = s3_client(credentials);
 
     // Iterate over the files in the SHA file difference and upload them to S3
     // If a file is successfully uploaded store it's remote name and local file path
-    let mut uploaded_files: Vec<SandmanUploadedFile> = vec![];
+    let mut uploads: Uploads = Uploads {
+        files: vec![],
+        removable: vec![],
+        bytes: 0,
+        errors: 0,
+        failures: vec![],
+    };
 
-    for (file_path, _) in diff.files {
-        let bucket_location: String =
-            format!("{}/{}/{}", args.bucket_prefix, formatted_time, file_path);
+    // Files are recorded relative to the backup root, keeping remote keys free of host paths
+    let root: &Path = Path::new(&args.local_directory);
+    // Links and special files are uploaded as their target or nothing, described by object metadata
+    let mut pending: Vec<(String, String)> = diff.files.drain().collect();
+    while let Some((file_path, record)) = pending.pop() {
+        if stop.is_cancelled() {
+            info!(
+                "Stopping, leaving {} files for the next run",
+                pending.len() + 1
+            );
+            break;
+        }
+        let bucket_location: String = format!("{}/{}/{}", args.bucket_prefix, snapshot, file_path);
+        let local_path: PathBuf = root.join(decode_key(&file_path));
+        let kind: EntryKind = EntryKind::of_record(&record);
 
-        let mut file: File = File::open(&file_path).await?;
-        let mut buffer: Vec<u8> = Vec::new();
-        file.read_to_end(&mut buffer).await?;
+        let (body, size): (StreamingBody, u64) = match object_body(&kind, &local_path).await {
+            Ok(body) => body,
+            Err(e) => {
+                error!(path = %local_path.display(), "Error reading file: {}", e);
+                uploads
+                    .failures
+                    .push(format!("{}: {}", local_path.display(), e));
+                continue;
+            }
+        };
 
         let upload_result: Result<PutObjectOutput, RusotoError<PutObjectError>> = client
             .put_object(PutObjectRequest {
                 bucket: args.bucket.clone(),
                 key: bucket_location.clone(),
-                body: Some(StreamingBody::from(buffer)),
+                body: Some(body),
+                metadata: Some(mark_encoded(kind.object_metadata())),
                 ..Default::default()
             })
             .await;
 
         match upload_result {
             Ok(_) => {
-                debug!(
-                    "[Gatherer - {}] Successfully uploaded: {}",
-                    args.name, bucket_location
-                );
-                uploaded_files.push(SandmanUploadedFile::new(file_path, bucket_location))
+                debug!(path = %bucket_location, bytes = size, "Successfully uploaded");
+                if kind.is_removable() {
+                    uploads.removable.push(local_path.clone());
+                }
+                uploads
+                    .files
+                    .push(SandmanUploadedFile::new(local_path, bucket_location));
+                uploads.bytes += size;
+                diff.files.insert(file_path, record);
+            }
+            Err(e) => {
+                error!(path = %bucket_location, bytes = size, "Error uploading: {}", e);
+                uploads.errors += 1;
+                uploads
+                    .failures
+                    .push(format!("{}: {}", local_path.display(), e));
             }
-            Err(e) => error!(
-                "[Gatherer - {}] Error uploading {}: {}",
-                args.name, bucket_location, e
-            ),
         }
     }
 
-    Ok(uploaded_files)
+    Ok(uploads)
+}
+
+/// Creates the body of the uploaded object of an entry and its size. Regular files are read
+/// whole, links and special files hold what `EntryKind::read` returns, and sparse files are
+/// streamed from disk one extent at a time as described by `SparseReader`.
+async fn object_body(kind: &EntryKind, local_path: &Path) -> io::Result<(StreamingBody, u64)> {
+    let contents: Vec<u8> = match kind {
+        EntryKind::File => {
+            let mut file: File = File::open(local_path).await?;
+            let mut buffer: Vec<u8> = Vec::new();
+            file.read_to_end(&mut buffer).await?;
+            buffer
+        }
+        EntryKind::Sparse => {
+            let reader: SparseReader = SparseReader::object(local_path)?;
+            let size: u64 = reader.size();
+            return Ok((
+                StreamingBody::new_with_size(sparse_stream(reader), size as usize),
+                size,
+            ));
+        }
+        _ => kind.read(local_path)?,
+    };
+    let size: u64 = contents.len() as u64;
+    Ok((StreamingBody::from(contents), size))
+}
+
+/// Streams what a `SparseReader` yields, reading each chunk on the blocking thread pool.
+fn sparse_stream(reader: SparseReader) -> impl Stream<Item = io::Result<Bytes>> + Send + 'static {
+    stream::try_unfold(reader, |mut reader| async move {
+        let (reader, chunk) = tokio::task::spawn_blocking(move || {
+            let mut chunk: Vec<u8> = vec![0; SPARSE_CHUNK];
+            let read: usize = reader.read(&mut chunk)?;
+            chunk.truncate(read);
+            Ok::<_, io::Error>((reader, chunk))
+        })
+        .await
+        .map_err(io::Error::other)??;
+        Ok(match chunk.is_empty() {
+            true => None,
+            false => Some((Bytes::from(chunk), reader)),
+        })
+    })
 }
@@ -0,0 +1,251 @@
+use crate::args::GatherArgs;
+use crate::gatherer::DEFAULT_DEBOUNCE;
+use crate::schedule::Schedule;
+use crate::store::HistoryStore;
+use hyper::Uri;
+use sandman_share::config::{Config, SandmanDirectory};
+use sandman_share::paths::backup_state_key;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::path::Path;
+
+/// The problems found in a configuration by `check_config`.
+#[derive(Default)]
+pub(crate) struct ConfigProblems {
+    /// Problems which stop the configuration from being used.
+    pub(crate) errors: Vec<String>,
+    /// Problems a run would run into, such as a backed up directory which is missing, which may
+    /// be temporary.
+    pub(crate) warnings: Vec<String>,
+}
+
+/// Checks every part of a configuration Sandman can check without contacting S3.
+///
+/// # Arguments
+///
+/// * `config` - The `Config` to check.
+///
+/// # Returns
+///
+/// The `ConfigProblems` found, none for a sound configuration.
+pub(crate) fn check_config(config: &Config) -> ConfigProblems {
+    let mut problems: ConfigProblems = ConfigProblems::default();
+    let mut names: HashSet<&str> = HashSet::new();
+    // Backups by the name of their state directory, which must not be shared
+    let mut state_keys: HashMap<String, &str> = HashMap::new();
+    for directory in &config.directories.backups {
+        if !names.insert(&directory.name) {
+            problems
+                .errors
+                .push(format!("Backup {} is configured twice", directory.name));
+        } else if let Some(other) =
+            state_keys.insert(backup_state_key(&directory.name), &directory.name)
+        {
+            problems.errors.push(format!(
+                "Backups {} and {} would share a history, rename one of them",
+                other, directory.name
+            ));
+        }
+        check_backup(directory, &mut problems);
+    }
+
+    if let Some(http) = &config.http {
+        if let Err(e) = http.listen.parse::<SocketAddr>() {
+            problems.errors.push(format!(
+                "Invalid [http] listen address {:?}: {}",
+                http.listen, e
+            ));
+        }
+    }
+    for webhook in &config.notifications.webhooks {
+        let valid: bool = webhook
+            .url
+            .parse::<Uri>()
+            .is_ok_and(|uri| matches!(uri.scheme_str(), Some("http") | Some("https")));
+        if !valid {
+            problems
+                .errors
+                .push("Webhook URL is not an http or https URL".to_string());
+        }
+        for name in &webhook.backups {
+            if !names.contains(name.as_str()) {
+                problems
+                    .warnings
+                    .push(format!("Webhook names unknown backup {}", name));
+            }
+        }
+    }
+    problems
+}
+
+/// Checks one configured backup, adding what is wrong with it to `problems`.
+fn check_backup(directory: &SandmanDirectory, problems: &mut ConfigProblems) {
+    let name: &str = &directory.name;
+    if name.is_empty() {
+        problems.errors.push("A backup has no name".to_string());
+    }
+    if directory.bucket.is_empty() {
+        problems
+            .errors
+            .push(format!("Backup {}: no bucket configured", name));
+    }
+    if let Err(e) = Schedule::new(&GatherArgs::from(directory.clone())) {
+        problems.errors.push(format!("Backup {}: {}", name, e));
+    }
+    if directory.schedule.is_none() && directory.interval == 0 && !directory.watch {
+        problems.warnings.push(format!(
+            "Backup {}: interval is 0, the backup runs continuously",
+            name
+        ));
+    }
+    if !Path::new(&directory.directory).is_dir() {
+        problems.warnings.push(format!(
+            "Backup {}: directory {:?} does not exist",
+            name, directory.directory
+        ));
+    }
+}
+
+/// Checks a configuration before it is used, as `check_config` but stopping at its first error.
+///
+/// # Returns
+///
+/// The configuration, or a description of the first error found in it.
+pub(crate) fn validate(config: Config) -> Result<Config, String> {
+    match check_config(&config).errors.into_iter().next() {
+        Some(error) => Err(error),
+        None => Ok(config),
+    }
+}
+
+/// Prints every configured backup as Sandman understands it, with the defaults applied. Secrets
+/// such as AWS keys and webhook URLs are left out.
+///
+/// # Arguments
+///
+/// * `config` - The `Config` to print.
+/// * `path` - Location the configuration was read from.
+pub(crate) fn print_config(config: &Config, path: &Path) {
+    println!("Configuration: {}", path.display());
+    println!("S3:            AWS ({})", config.aws.aws_default_region);
+    if let Some(http) = &config.http {
+        println!("HTTP:          {}", http.listen);
+    }
+    println!("Webhooks:      {}", config.notifications.webhooks.len());
+
+    for directory in &config.directories.backups {
+        let gather_args: GatherArgs =
+            GatherArgs::configured(directory.clone(), &config.notifications);
+        println!();
+        println!("{}", gather_args.name);
+        println!("  directory:  {}", gather_args.local_directory);
+        println!(
+            "  bucket:     s3://{}/{}/",
+            gather_args.bucket, gather_args.bucket_prefix
+        );
+        let schedule: String = match (&gather_args.schedule, &gather_args.time_zone) {
+            (Some(schedule), Some(time_zone)) => format!("{} ({})", schedule, time_zone),
+            (Some(schedule), None) => format!("{} (local time)", schedule),
+            (None, _) => format!("every {}s", gather_args.interval),
+        };
+        println!("  schedule:   {}", schedule);
+        if gather_args.watch {
+            println!(
+                "  watch:      yes, debounce {}s",
+                gather_args.debounce.unwrap_or(DEFAULT_DEBOUNCE)
+            );
+        }
+        println!("  cleanable:  {}", yes_no(gather_args.cleanable));
+        println!("  hash:       {}", gather_args.hash_algorithm);
+        println!(
+            "  history:    {} at {}",
+            gather_args.history_backend,
+            HistoryStore::location(&gather_args).display()
+        );
+        let hooks: Vec<&str> = [
+            ("pre_hook", &gather_args.pre_hook),
+            ("post_hook", &gather_args.post_hook),
+            ("on_failure", &gather_args.on_failure),
+        ]
+        .into_iter()
+        .filter(|(_, command)| command.is_some())
+        .map(|(hook, _)| hook)
+        .collect();
+        if !hooks.is_empty() {
+            println!("  hooks:      {}", hooks.join(", "));
+        }
+        println!("  webhooks:   {}", gather_args.webhooks.len());
+    }
+}
+
+/// Formats a flag as `yes` or `no`.
+fn yes_no(flag: bool) -> &'static str {
+    match flag {
+        true => "yes",
+        false => "no",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a configuration backing up `/tmp` under each of `names`.
+    fn config(names: &[&str]) -> Config {
+        let backups: String = names
+            .iter()
+            .map(|name| {
+                format!(
+                    r#"
+                    [[directories.backups]]
+                    name = "{}"
+                    directory = "/tmp"
+                    interval = 3600
+                    start_time = 0
+                    prefix = "documents"
+                    bucket = "bucket"
+                    cleanable = false
+                    "#,
+                    name
+                )
+            })
+            .collect();
+        toml::from_str(&format!(
+            r#"
+            [aws]
+            aws_access_key_id = "key"
+            aws_secret_access_key = "secret"
+            aws_default_region = "us-east-1"
+            {}
+            "#,
+            backups
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn distinct_names_are_accepted() {
+        assert!(check_config(&config(&["Documents", "Photos"]))
+            .errors
+            .is_empty());
+    }
+
+    #[test]
+    fn names_sharing_a_state_directory_are_rejected() {
+        assert_eq!(
+            check_config(&config(&["a b", "a_b", "a:b"])).errors,
+            [
+                "Backups a b and a_b would share a history, rename one of them",
+                "Backups a_b and a:b would share a history, rename one of them",
+            ]
+        );
+    }
+
+    #[test]
+    fn duplicate_names_are_reported_once() {
+        assert_eq!(
+            check_config(&config(&["Documents", "Documents"])).errors,
+            ["Backup Documents is configured twice"]
+        );
+    }
+}
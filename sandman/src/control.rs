@@ -0,0 +1,407 @@
+use crate::clock::now;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{watch, Notify};
+use tokio_util::sync::CancellationToken;
+
+/// What a gatherer is doing at the moment.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum GathererState {
+    /// Waiting for the next run, or for changes when watching.
+    Sleeping,
+
+    /// Waiting for another Sandman process to release the backup's lock.
+    Waiting,
+
+    /// Hashing the backup's files to find what changed.
+    Scanning,
+
+    /// Uploading the changed files.
+    Uploading,
+
+    /// The gatherer has returned and runs no further backups.
+    Stopped,
+}
+
+impl fmt::Display for GathererState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GathererState::Sleeping => write!(f, "sleeping"),
+            GathererState::Waiting => write!(f, "waiting"),
+            GathererState::Scanning => write!(f, "scanning"),
+            GathererState::Uploading => write!(f, "uploading"),
+            GathererState::Stopped => write!(f, "stopped"),
+        }
+    }
+}
+
+/// How a run ended.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum RunOutcome {
+    /// Every changed file was uploaded.
+    Success,
+
+    /// The run was cancelled or Sandman shut down, the remaining files are left for the next run.
+    Cancelled,
+
+    /// The run could not be completed, or some files could not be read or uploaded.
+    Failed,
+}
+
+impl fmt::Display for RunOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RunOutcome::Success => write!(f, "success"),
+            RunOutcome::Cancelled => write!(f, "cancelled"),
+            RunOutcome::Failed => write!(f, "failed"),
+        }
+    }
+}
+
+/// Summary of a finished run, kept in the backup's run log.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct RunReport {
+    /// Identifies the run in the log.
+    pub(crate) run_id: String,
+    /// Time the run started in seconds since the Unix epoch.
+    pub(crate) started: u64,
+    /// Time the run finished in seconds since the Unix epoch.
+    pub(crate) finished: u64,
+    /// How long the run took in seconds.
+    pub(crate) duration: f64,
+    pub(crate) outcome: RunOutcome,
+    /// Number of files hashed.
+    pub(crate) scanned: usize,
+    /// Number of files found changed.
+    pub(crate) changed: usize,
+    /// Number of changed files uploaded.
+    pub(crate) uploaded: usize,
+    /// Number of changed files which could not be read or uploaded.
+    pub(crate) failed: usize,
+    /// Bytes of the uploaded objects.
+    pub(crate) bytes: u64,
+    /// Name of the snapshot the files were uploaded under.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) snapshot: Option<String>,
+    /// Why the run failed, if it did.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) error: Option<String>,
+    /// The first files which could not be read or uploaded, with the reason.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) errors: Vec<String>,
+}
+
+/// What a run found and did, gathered as it goes.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct RunStats {
+    /// Number of files hashed.
+    pub(crate) scanned: usize,
+    /// Number of files found changed.
+    pub(crate) changed: usize,
+    /// Number of changed files uploaded.
+    pub(crate) uploaded: usize,
+    /// Bytes of the uploaded objects.
+    pub(crate) bytes: u64,
+    /// Number of uploads which failed.
+    pub(crate) upload_errors: usize,
+    /// Number of entries recorded by the history once the run is over.
+    pub(crate) history_entries: Option<usize>,
+    /// Name of the snapshot the files were uploaded under, once uploading started.
+    pub(crate) snapshot: Option<String>,
+    /// Files which could not be read or uploaded, with the reason.
+    pub(crate) failures: Vec<String>,
+}
+
+/// Totals of a gatherer's runs, exported as metrics.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct BackupMetrics {
+    pub(crate) runs: u64,
+    pub(crate) failed_runs: u64,
+    pub(crate) files_scanned: u64,
+    pub(crate) files_changed: u64,
+    pub(crate) bytes_uploaded: u64,
+    pub(crate) upload_errors: u64,
+    /// Duration of the last run in seconds.
+    pub(crate) last_duration: Option<f64>,
+    /// Time the last successful run finished in seconds since the Unix epoch.
+    pub(crate) last_success: Option<u64>,
+    pub(crate) history_entries: Option<u64>,
+}
+
+/// Status of a gatherer as reported over the control socket.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct GathererStatus {
+    pub(crate) name: String,
+    pub(crate) state: GathererState,
+    pub(crate) paused: bool,
+    /// Time of the next scheduled run in seconds since the Unix epoch, if known.
+    pub(crate) next_run: Option<u64>,
+    pub(crate) last_run: Option<RunReport>,
+}
+
+/// Shared between a gatherer and the control socket, reporting what the gatherer is doing and
+/// letting its runs be triggered, paused, resumed and cancelled.
+pub(crate) struct GathererControl {
+    name: String,
+    status: Mutex<GathererStatus>,
+    metrics: Mutex<BackupMetrics>,
+    trigger: Notify,
+    paused: watch::Sender<bool>,
+    /// Token of the run in progress, cancelled to end it early.
+    run: Mutex<Option<CancellationToken>>,
+    /// Time the gatherer started in seconds since the Unix epoch, standing in for the last
+    /// successful run until there is one.
+    started: u64,
+    /// Time the gatherer's last full run finished in seconds since the Unix epoch, whatever its
+    /// outcome.
+    last_attempt: Mutex<Option<u64>>,
+    /// Webhooks, by index, already told the backup is stale since its last successful run.
+    stale_notified: Mutex<HashSet<usize>>,
+}
+
+impl GathererControl {
+    /// Creates the control of a sleeping, unpaused gatherer.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Name of the backup the gatherer runs.
+    pub(crate) fn new(name: String) -> Arc<Self> {
+        let status: GathererStatus = GathererStatus {
+            name: name.clone(),
+            state: GathererState::Sleeping,
+            paused: false,
+            next_run: None,
+            last_run: None,
+        };
+        Arc::new(GathererControl {
+            name,
+            status: Mutex::new(status),
+            metrics: Mutex::new(BackupMetrics::default()),
+            trigger: Notify::new(),
+            paused: watch::Sender::new(false),
+            run: Mutex::new(None),
+            started: now(),
+            last_attempt: Mutex::new(None),
+            stale_notified: Mutex::new(HashSet::new()),
+        })
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns a snapshot of the gatherer's status.
+    pub(crate) fn status(&self) -> GathererStatus {
+        let mut status: GathererStatus = self.status.lock().unwrap().clone();
+        status.paused = self.is_paused();
+        status
+    }
+
+    pub(crate) fn set_state(&self, state: GathererState) {
+        self.status.lock().unwrap().state = state;
+    }
+
+    pub(crate) fn set_next_run(&self, next_run: Option<u64>) {
+        self.status.lock().unwrap().next_run = next_run;
+    }
+
+    /// Returns a snapshot of the totals of the gatherer's runs.
+    pub(crate) fn metrics(&self) -> BackupMetrics {
+        self.metrics.lock().unwrap().clone()
+    }
+
+    /// Restores the last run and the time of the last successful run from a backup's run log, so
+    /// that they outlive restarts.
+    ///
+    /// # Arguments
+    ///
+    /// * `runs` - The reports of the backup's past runs, oldest first.
+    pub(crate) fn restore(&self, runs: &[RunReport]) {
+        self.metrics.lock().unwrap().last_success = runs
+            .iter()
+            .rev()
+            .find(|report| report.outcome == RunOutcome::Success)
+            .map(|report| report.finished);
+        self.status.lock().unwrap().last_run = runs.last().cloned();
+    }
+
+    /// Records a finished run in the gatherer's status and metrics.
+    ///
+    /// # Arguments
+    ///
+    /// * `report` - The `RunReport` of the run.
+    /// * `stats` - What the run found and did.
+    /// * `duration` - How long the run took in seconds.
+    pub(crate) fn finish_run(&self, report: RunReport, stats: &RunStats, duration: f64) {
+        {
+            let mut metrics = self.metrics.lock().unwrap();
+            metrics.runs += 1;
+            if report.outcome == RunOutcome::Failed {
+                metrics.failed_runs += 1;
+            } else if report.outcome == RunOutcome::Success {
+                metrics.last_success = Some(report.finished);
+                self.stale_notified.lock().unwrap().clear();
+            }
+            metrics.files_scanned += stats.scanned as u64;
+            metrics.files_changed += stats.changed as u64;
+            metrics.bytes_uploaded += stats.bytes;
+            metrics.upload_errors += stats.upload_errors as u64;
+            metrics.last_duration = Some(duration);
+            if let Some(entries) = stats.history_entries {
+                metrics.history_entries = Some(entries as u64);
+            }
+        }
+        self.status.lock().unwrap().last_run = Some(report);
+    }
+
+    /// Records that a full run finished, also when it was cancelled or failed and left the history
+    /// untouched.
+    ///
+    /// # Arguments
+    ///
+    /// * `finished` - Time the run finished in seconds since the Unix epoch.
+    pub(crate) fn record_attempt(&self, finished: u64) {
+        *self.last_attempt.lock().unwrap() = Some(finished);
+    }
+
+    /// Returns the time the gatherer's last full run finished in seconds since the Unix epoch,
+    /// `None` if it has not run one yet.
+    pub(crate) fn last_attempt(&self) -> Option<u64> {
+        *self.last_attempt.lock().unwrap()
+    }
+
+    /// Describes why the gatherer is unhealthy, if it is: it stopped while Sandman is still
+    /// running, or it is neither paused nor running while its next run is more than `grace`
+    /// seconds overdue.
+    ///
+    /// # Arguments
+    ///
+    /// * `now` - The current time in seconds since the Unix epoch.
+    /// * `grace` - Seconds a run may be late before the gatherer counts as overdue.
+    pub(crate) fn health_problem(&self, now: u64, grace: u64) -> Option<String> {
+        let status: GathererStatus = self.status();
+        match (status.state, status.next_run) {
+            (GathererState::Stopped, _) => Some(format!("Backup {} has stopped", self.name)),
+            (GathererState::Sleeping, Some(next_run))
+                if !status.paused && now > next_run.saturating_add(grace) =>
+            {
+                Some(format!(
+                    "Backup {} is overdue by {}s",
+                    self.name,
+                    now - next_run
+                ))
+            }
+            _ => None,
+        }
+    }
+
+    /// Checks whether the backup has gone more than `stale_after` seconds without a successful run
+    /// and `webhook` has not been told so yet, marking it told.
+    ///
+    /// # Arguments
+    ///
+    /// * `now` - The current time in seconds since the Unix epoch.
+    /// * `stale_after` - Seconds without a successful run after which the backup is stale.
+    /// * `webhook` - Index of the webhook to be told.
+    ///
+    /// # Returns
+    ///
+    /// Seconds since the last successful run, or since the gatherer started, if `webhook` is to be
+    /// told the backup is stale.
+    pub(crate) fn newly_stale(&self, now: u64, stale_after: u64, webhook: usize) -> Option<u64> {
+        let since: u64 = self.metrics().last_success.unwrap_or(self.started);
+        let age: u64 = now.saturating_sub(since);
+        match age > stale_after && self.stale_notified.lock().unwrap().insert(webhook) {
+            true => Some(age),
+            false => None,
+        }
+    }
+
+    /// Asks the gatherer to run now instead of waiting for its schedule.
+    ///
+    /// # Returns
+    ///
+    /// A description of why the run cannot be triggered, if it cannot.
+    pub(crate) fn trigger(&self) -> Result<(), String> {
+        if self.is_paused() {
+            return Err(format!("Backup {} is paused", self.name));
+        }
+        match self.status.lock().unwrap().state {
+            GathererState::Sleeping => {}
+            GathererState::Stopped => return Err(format!("Backup {} is stopped", self.name)),
+            _ => return Err(format!("Backup {} is already running", self.name)),
+        }
+        self.trigger.notify_one();
+        Ok(())
+    }
+
+    /// Waits until a run is triggered.
+    pub(crate) async fn triggered(&self) {
+        self.trigger.notified().await
+    }
+
+    pub(crate) fn is_paused(&self) -> bool {
+        *self.paused.borrow()
+    }
+
+    /// Stops the gatherer from starting runs until it is resumed. A run in progress is finished.
+    ///
+    /// # Returns
+    ///
+    /// Whether the gatherer was paused before.
+    pub(crate) fn pause(&self) -> bool {
+        self.paused.send_replace(true)
+    }
+
+    /// Lets the gatherer start runs again, running straight away any it missed while paused.
+    ///
+    /// # Returns
+    ///
+    /// Whether the gatherer was paused before.
+    pub(crate) fn resume(&self) -> bool {
+        self.paused.send_replace(false)
+    }
+
+    /// Waits until the gatherer is paused, returning straight away if it is.
+    pub(crate) async fn until_paused(&self) {
+        let _ = self.paused.subscribe().wait_for(|paused| *paused).await;
+    }
+
+    /// Waits until the gatherer is not paused, returning straight away if it is not.
+    pub(crate) async fn until_resumed(&self) {
+        let _ = self.paused.subscribe().wait_for(|paused| !*paused).await;
+    }
+
+    /// Registers the start of a run, which ends early once `shutdown` or the returned token is
+    /// cancelled.
+    pub(crate) fn start_run(&self, shutdown: &CancellationToken) -> CancellationToken {
+        let run: CancellationToken = shutdown.child_token();
+        *self.run.lock().unwrap() = Some(run.clone());
+        run
+    }
+
+    /// Registers the end of the run in progress.
+    pub(crate) fn end_run(&self) {
+        *self.run.lock().unwrap() = None;
+    }
+
+    /// Cancels the run in progress. The upload in flight is finished and the history records the
+    /// files uploaded so far, leaving the rest for the next run.
+    ///
+    /// # Returns
+    ///
+    /// A description of why there is nothing to cancel, if there is not.
+    pub(crate) fn cancel(&self) -> Result<(), String> {
+        match &*self.run.lock().unwrap() {
+            Some(run) => {
+                run.cancel();
+                Ok(())
+            }
+            None => Err(format!("Backup {} is not running", self.name)),
+        }
+    }
+}
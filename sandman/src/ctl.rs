@@ -0,0 +1,302 @@
+use crate::args::CtlCommand;
+use crate::control::{GathererControl, GathererStatus};
+use chrono::{Local, TimeZone};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info};
+
+/// Controls of the gatherers a daemon is running, shared with its control socket.
+pub(crate) type Registry = Arc<Mutex<Vec<Arc<GathererControl>>>>;
+
+/// A request sent to the control socket, one JSON object per line.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub(crate) enum Request {
+    /// Lists every gatherer and its status.
+    Status,
+
+    /// Runs a backup now instead of waiting for its schedule.
+    Trigger { name: String },
+
+    /// Stops a backup from starting runs until it is resumed.
+    Pause { name: String },
+
+    /// Lets a paused backup start runs again.
+    Resume { name: String },
+
+    /// Ends the run in progress of a backup, keeping what was uploaded so far.
+    Cancel { name: String },
+}
+
+/// The answer to a `Request`, one JSON object per line.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub(crate) struct Response {
+    pub(crate) ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) message: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) error: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) gatherers: Vec<GathererStatus>,
+}
+
+impl Response {
+    fn message(message: String) -> Self {
+        Response {
+            ok: true,
+            message: Some(message),
+            ..Default::default()
+        }
+    }
+
+    fn error(error: String) -> Self {
+        Response {
+            ok: false,
+            error: Some(error),
+            ..Default::default()
+        }
+    }
+}
+
+/// Carries out a request against the gatherers of `registry`.
+fn handle(registry: &Registry, request: Request) -> Response {
+    let controls: Vec<Arc<GathererControl>> = registry.lock().unwrap().clone();
+    let find = |name: &str| -> Result<Arc<GathererControl>, String> {
+        controls
+            .iter()
+            .find(|control| control.name() == name)
+            .cloned()
+            .ok_or_else(|| format!("No backup named {}", name))
+    };
+    let result: Result<String, String> = match request {
+        Request::Status => {
+            return Response {
+                ok: true,
+                gatherers: controls.iter().map(|control| control.status()).collect(),
+                ..Default::default()
+            }
+        }
+        Request::Trigger { name } => find(&name)
+            .and_then(|control| control.trigger())
+            .map(|_| format!("Triggered a run of {}", name)),
+        Request::Pause { name } => find(&name).map(|control| match control.pause() {
+            true => format!("Backup {} was already paused", name),
+            false => format!("Paused {}", name),
+        }),
+        Request::Resume { name } => find(&name).map(|control| match control.resume() {
+            true => format!("Resumed {}", name),
+            false => format!("Backup {} was not paused", name),
+        }),
+        Request::Cancel { name } => find(&name)
+            .and_then(|control| control.cancel())
+            .map(|_| format!("Cancelled the run of {}", name)),
+    };
+    match result {
+        Ok(message) => {
+            info!("Control socket: {}", message);
+            Response::message(message)
+        }
+        Err(e) => Response::error(e),
+    }
+}
+
+/// Listens on the control socket at `path` until `shutdown` is cancelled, answering requests about
+/// the gatherers of `registry`. The socket is only accessible to the user running Sandman, and is
+/// removed once Sandman shuts down.
+#[cfg(unix)]
+pub(crate) async fn serve(path: PathBuf, registry: Registry, shutdown: CancellationToken) {
+    use std::os::unix::fs::PermissionsExt;
+    use tokio::net::{UnixListener, UnixStream};
+
+    if std::os::unix::net::UnixStream::connect(&path).is_ok() {
+        error!(
+            "Control socket {:?} is in use by another Sandman instance, not listening",
+            path
+        );
+        return;
+    }
+    let _ = std::fs::remove_file(&path);
+    let listener: UnixListener = match UnixListener::bind(&path) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Unable to listen on control socket {:?}: {}", path, e);
+            return;
+        }
+    };
+    if let Err(e) = std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)) {
+        error!("Unable to restrict control socket {:?}: {}", path, e);
+    }
+    info!("Listening for control requests on {:?}", path);
+
+    loop {
+        let stream: UnixStream = tokio::select! {
+            _ = shutdown.cancelled() => break,
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    error!("Error accepting control connection: {}", e);
+                    continue;
+                }
+            },
+        };
+        tokio::spawn(answer(stream, registry.clone()));
+    }
+    let _ = std::fs::remove_file(&path);
+}
+
+#[cfg(not(unix))]
+pub(crate) async fn serve(path: PathBuf, _registry: Registry, _shutdown: CancellationToken) {
+    error!(
+        "Control sockets are only supported on Unix, not listening on {:?}",
+        path
+    );
+}
+
+/// Answers the requests of one control connection, one line each, until it is closed.
+#[cfg(unix)]
+async fn answer(stream: tokio::net::UnixStream, registry: Registry) {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        debug!("Control request: {}", line);
+        let response: Response = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => handle(&registry, request),
+            Err(e) => Response::error(format!("Invalid request: {}", e)),
+        };
+        let mut body: String =
+            serde_json::to_string(&response).expect("Unable to serialize control response");
+        body.push('\n');
+        if writer.write_all(body.as_bytes()).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Sends a request to the control socket at `path` and waits for the answer.
+///
+/// # Returns
+///
+/// The `Response`, or a description of why the socket could not be reached.
+#[cfg(unix)]
+fn send(path: &Path, request: &Request) -> Result<Response, String> {
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::net::UnixStream;
+
+    let mut stream: UnixStream = UnixStream::connect(path).map_err(|e| {
+        format!(
+            "Unable to connect to {:?}, is `sandman daemon` running? {}",
+            path, e
+        )
+    })?;
+    let mut body: String = serde_json::to_string(request).expect("Unable to serialize request");
+    body.push('\n');
+    stream
+        .write_all(body.as_bytes())
+        .map_err(|e| format!("Error sending to {:?}: {}", path, e))?;
+
+    let mut line: String = String::new();
+    BufReader::new(stream)
+        .read_line(&mut line)
+        .map_err(|e| format!("Error reading from {:?}: {}", path, e))?;
+    serde_json::from_str(&line).map_err(|e| format!("Invalid response from {:?}: {}", path, e))
+}
+
+#[cfg(not(unix))]
+fn send(_path: &Path, _request: &Request) -> Result<Response, String> {
+    Err("Control sockets are only supported on Unix".to_string())
+}
+
+/// Runs a `sandman ctl` command against the control socket at `path`, printing the answer.
+///
+/// # Returns
+///
+/// Whether the request succeeded.
+pub(crate) fn ctl(path: &Path, command: &CtlCommand) -> bool {
+    let (request, json): (Request, bool) = match command {
+        CtlCommand::Status { json } => (Request::Status, *json),
+        CtlCommand::Trigger { name } => (Request::Trigger { name: name.clone() }, false),
+        CtlCommand::Pause { name } => (Request::Pause { name: name.clone() }, false),
+        CtlCommand::Resume { name } => (Request::Resume { name: name.clone() }, false),
+        CtlCommand::Cancel { name } => (Request::Cancel { name: name.clone() }, false),
+    };
+    let response: Response = match send(path, &request) {
+        Ok(response) => response,
+        Err(e) => {
+            eprintln!("{}", e);
+            return false;
+        }
+    };
+
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&response.gatherers)
+                .expect("Unable to serialize gatherers")
+        );
+    } else if let Some(error) = &response.error {
+        eprintln!("{}", error);
+    } else if let Some(message) = &response.message {
+        println!("{}", message);
+    } else {
+        print_status(&response.gatherers);
+    }
+    response.ok
+}
+
+/// Prints the status of every gatherer as a table.
+fn print_status(gatherers: &[GathererStatus]) {
+    let statuses: Vec<[String; 4]> = gatherers
+        .iter()
+        .map(|status| {
+            let state: String = match status.paused {
+                true => format!("{} (paused)", status.state),
+                false => status.state.to_string(),
+            };
+            let last_run: String = match &status.last_run {
+                Some(report) => format!(
+                    "{} at {}, {}/{} files uploaded{}",
+                    report.outcome,
+                    local_time(report.finished),
+                    report.uploaded,
+                    report.changed,
+                    report
+                        .error
+                        .as_ref()
+                        .map(|e| format!(": {}", e))
+                        .unwrap_or_default()
+                ),
+                None => "-".to_string(),
+            };
+            [
+                status.name.clone(),
+                state,
+                status.next_run.map(local_time).unwrap_or("-".to_string()),
+                last_run,
+            ]
+        })
+        .collect();
+
+    let headers: [String; 4] = ["NAME", "STATE", "NEXT RUN", "LAST RUN"].map(String::from);
+    let rows: Vec<[String; 4]> = [headers].into_iter().chain(statuses).collect();
+    let width = |column: usize| rows.iter().map(|row| row[column].len()).max().unwrap_or(0);
+    let (name, state, next_run): (usize, usize, usize) = (width(0), width(1), width(2));
+    for row in &rows {
+        println!(
+            "{:<name$}  {:<state$}  {:<next_run$}  {}",
+            row[0], row[1], row[2], row[3]
+        );
+    }
+}
+
+/// Formats seconds since the Unix epoch as a local time.
+pub(crate) fn local_time(seconds: u64) -> String {
+    Local
+        .timestamp_opt(seconds as i64, 0)
+        .single()
+        .map(|time| time.format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_default()
+}
@@ -0,0 +1,353 @@
+use crate::args::GatherArgs;
+use crate::configuration::validate;
+use crate::ctl::{serve, Registry};
+use crate::gatherer::{join_gatherers, Gatherer};
+use crate::http::serve_http;
+use crate::notifications::notify_stale;
+use crate::sandman::read_config;
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use sandman_share::config::{AwsConfig, Config, HttpConfig, NotificationsConfig, SandmanDirectory};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinSet;
+use tokio::time::{interval, Interval, MissedTickBehavior};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, info_span, Instrument};
+
+/// Time the configuration file must stay unchanged before it is reloaded, editors often write a
+/// file in several steps.
+const RELOAD_DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Time between checks whether backups went too long without a successful run.
+const STALE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A gatherer started by the daemon, alongside the configuration it was started from.
+struct RunningGatherer {
+    directory: SandmanDirectory,
+    gatherer: Gatherer,
+    /// Cancelled to stop this gatherer alone, a child of the daemon's shutdown token.
+    stop: CancellationToken,
+}
+
+/// Runs a gatherer for every backup of the configuration file, reloading the file whenever it
+/// changes or SIGHUP is received, and answering requests on a control socket and, when
+/// configured, over HTTP.
+pub(crate) struct Daemon {
+    config_path: PathBuf,
+    aws: AwsConfig,
+    /// The HTTP listener's configuration, only applied when Sandman starts.
+    http: Option<HttpConfig>,
+    notifications: NotificationsConfig,
+    running: Vec<RunningGatherer>,
+    /// Gatherers stopped by a reload which are finishing their runs, each returning its backup's
+    /// name once stopped.
+    stopping: JoinSet<String>,
+    /// Number of gatherers still stopping for each backup name.
+    stopping_names: HashMap<String, usize>,
+    /// Backups started once the gatherer they replace has stopped, so the two never run at once.
+    pending: Vec<SandmanDirectory>,
+    /// Controls of the running gatherers, shared with the control socket.
+    registry: Registry,
+    socket_path: PathBuf,
+    shutdown: CancellationToken,
+    /// Time gatherers being stopped have to finish their runs.
+    timeout: Duration,
+}
+
+impl Daemon {
+    /// Creates the daemon and starts a gatherer for every backup of `config`.
+    ///
+    /// # Arguments
+    ///
+    /// * `config_path` - Location of the configuration file, watched for changes.
+    /// * `config` - The configuration read from `config_path`.
+    /// * `socket_path` - Location of the control socket.
+    /// * `shutdown` - Token cancelled when Sandman is shutting down.
+    /// * `timeout` - Time gatherers have to finish their runs when stopped.
+    pub(crate) fn new(
+        config_path: PathBuf,
+        config: Config,
+        socket_path: PathBuf,
+        shutdown: CancellationToken,
+        timeout: Duration,
+    ) -> Self {
+        let mut daemon: Daemon = Daemon {
+            config_path,
+            aws: config.aws,
+            http: config.http,
+            notifications: config.notifications,
+            running: vec![],
+            stopping: JoinSet::new(),
+            stopping_names: HashMap::new(),
+            pending: vec![],
+            registry: Registry::default(),
+            socket_path,
+            shutdown,
+            timeout,
+        };
+        for directory in config.directories.backups {
+            daemon.start(directory);
+        }
+        daemon
+    }
+
+    /// Supervises the gatherers until Sandman shuts down, then waits for them to stop.
+    pub(crate) async fn run(mut self) {
+        let (sender, mut reloads): (UnboundedSender<()>, UnboundedReceiver<()>) =
+            unbounded_channel();
+        let _watcher: Option<RecommendedWatcher> = self.watch_config(sender.clone());
+        reload_on_hangup(sender);
+        let server = tokio::spawn(serve(
+            self.socket_path.clone(),
+            self.registry.clone(),
+            self.shutdown.clone(),
+        ));
+        let http_server = self.http.clone().map(|http| {
+            tokio::spawn(serve_http(
+                http,
+                self.registry.clone(),
+                self.shutdown.clone(),
+            ))
+        });
+        let mut stale_checks: Interval = interval(STALE_CHECK_INTERVAL);
+        stale_checks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                _ = self.shutdown.cancelled() => break,
+                _ = stale_checks.tick() => self.check_stale(),
+                Some(()) = reloads.recv() => {
+                    while let Ok(Some(())) =
+                        tokio::time::timeout(RELOAD_DEBOUNCE, reloads.recv()).await
+                    {}
+                    self.reload();
+                }
+                Some(stopped) = self.stopping.join_next() => match stopped {
+                    Ok(name) => self.stopped(name),
+                    Err(e) => error!("Stopping a backup failed: {}", e),
+                },
+            }
+        }
+
+        let gatherers: Vec<Gatherer> = self
+            .running
+            .into_iter()
+            .map(|running| running.gatherer)
+            .collect();
+        join_gatherers(gatherers, &self.shutdown, self.timeout).await;
+        while self.stopping.join_next().await.is_some() {}
+        let _ = server.await;
+        if let Some(http_server) = http_server {
+            let _ = http_server.await;
+        }
+    }
+
+    /// Starts a gatherer for a backup.
+    fn start(&mut self, directory: SandmanDirectory) {
+        let stop: CancellationToken = self.shutdown.child_token();
+        let gather_args: GatherArgs =
+            GatherArgs::configured(directory.clone(), &self.notifications);
+        let mut gatherer: Gatherer = Gatherer::new(gather_args, Some(self.aws.clone()));
+        gatherer.gather(false, stop.clone());
+        self.registry.lock().unwrap().push(gatherer.control());
+        self.running.push(RunningGatherer {
+            directory,
+            gatherer,
+            stop,
+        });
+    }
+
+    /// Tells webhooks with `stale_after` set about running backups which went that long without a
+    /// successful run. The webhooks are posted to in the background, so slow ones cannot hold up
+    /// the daemon.
+    fn check_stale(&self) {
+        for running in &self.running {
+            let gather_args: &GatherArgs = running.gatherer.args();
+            if gather_args
+                .webhooks
+                .iter()
+                .all(|webhook| webhook.stale_after.is_none())
+            {
+                continue;
+            }
+            let gather_args: GatherArgs = gather_args.clone();
+            let control = running.gatherer.control();
+            let span = info_span!("gatherer", backup = %gather_args.name);
+            tokio::spawn(
+                async move { notify_stale(&gather_args, &control).await }.instrument(span),
+            );
+        }
+    }
+
+    /// Starts the backups waiting for the gatherer of `name` once no gatherer of that name is
+    /// stopping anymore.
+    fn stopped(&mut self, name: String) {
+        match self.stopping_names.get_mut(&name) {
+            Some(count) if *count > 1 => {
+                *count -= 1;
+                return;
+            }
+            _ => self.stopping_names.remove(&name),
+        };
+        let (ready, waiting): (Vec<SandmanDirectory>, Vec<SandmanDirectory>) =
+            std::mem::take(&mut self.pending)
+                .into_iter()
+                .partition(|directory| directory.name == name);
+        self.pending = waiting;
+        for directory in ready {
+            info!(backup = %directory.name, "Starting backup");
+            self.start(directory);
+        }
+    }
+
+    /// Rereads the configuration file and brings the running gatherers in line with it. Gatherers
+    /// of backups whose configuration is unchanged keep running, those of removed or changed
+    /// backups are stopped, and gatherers are started for new or changed backups. Stopped
+    /// gatherers finish their runs in the background, a changed backup being started once its
+    /// previous gatherer stopped. A configuration which cannot be read or is invalid is rejected,
+    /// keeping the current one.
+    fn reload(&mut self) {
+        let config: Config = match read_config(&self.config_path).and_then(validate) {
+            Ok(config) => config,
+            Err(e) => {
+                error!(
+                    "Rejected new configuration {:?}, keeping the current one: {}",
+                    self.config_path, e
+                );
+                return;
+            }
+        };
+
+        if config.http != self.http {
+            info!(
+                "The [http] section of {:?} changed, restart Sandman to apply it",
+                self.config_path
+            );
+        }
+        // Every gatherer is restarted when the settings they share change
+        let shared_changed: bool =
+            config.aws != self.aws || config.notifications != self.notifications;
+        let mut previous: Vec<RunningGatherer> = std::mem::take(&mut self.running);
+        let mut waiting: Vec<SandmanDirectory> = std::mem::take(&mut self.pending);
+        let mut added: Vec<SandmanDirectory> = vec![];
+        for directory in config.directories.backups {
+            let unchanged = |current: &SandmanDirectory| !shared_changed && *current == directory;
+            if let Some(index) = previous
+                .iter()
+                .position(|running| unchanged(&running.directory))
+            {
+                self.running.push(previous.swap_remove(index));
+            } else if let Some(index) = waiting.iter().position(unchanged) {
+                self.pending.push(waiting.swap_remove(index));
+            } else {
+                added.push(directory);
+            }
+        }
+        self.aws = config.aws;
+        self.notifications = config.notifications;
+
+        if previous.is_empty() && added.is_empty() && waiting.is_empty() {
+            info!("Reloaded {:?}, no backups changed", self.config_path);
+            return;
+        }
+        info!(
+            "Reloaded {:?}: {} backups unchanged, stopping {}, starting {}",
+            self.config_path,
+            self.running.len(),
+            previous.len(),
+            added.len()
+        );
+        for running in previous {
+            info!(backup = %running.directory.name, "Stopping backup");
+            running.stop.cancel();
+            let control = running.gatherer.control();
+            self.registry
+                .lock()
+                .unwrap()
+                .retain(|registered| !Arc::ptr_eq(registered, &control));
+            let name: String = running.directory.name;
+            *self.stopping_names.entry(name.clone()).or_default() += 1;
+            let timeout: Duration = self.timeout;
+            self.stopping.spawn(async move {
+                join_gatherers(vec![running.gatherer], &running.stop, timeout).await;
+                name
+            });
+        }
+        for directory in added {
+            if self.stopping_names.contains_key(&directory.name) {
+                info!(backup = %directory.name, "Starting backup once its previous run stopped");
+                self.pending.push(directory);
+            } else {
+                info!(backup = %directory.name, "Starting backup");
+                self.start(directory);
+            }
+        }
+    }
+
+    /// Watches the directory of the configuration file, signalling `sender` whenever the file is
+    /// written, replaced or removed.
+    ///
+    /// # Returns
+    ///
+    /// The watcher, which stops watching when dropped, or `None` if the file cannot be watched.
+    fn watch_config(&self, sender: UnboundedSender<()>) -> Option<RecommendedWatcher> {
+        let file_name = self.config_path.file_name()?.to_os_string();
+        let directory: &Path = self
+            .config_path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+
+        let watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+            if let Ok(event) = event {
+                let written: bool = matches!(
+                    event.kind,
+                    EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)
+                );
+                if written
+                    && event
+                        .paths
+                        .iter()
+                        .any(|path| path.file_name() == Some(file_name.as_os_str()))
+                {
+                    let _ = sender.send(());
+                }
+            }
+        });
+        match watcher.and_then(|mut watcher| {
+            watcher
+                .watch(directory, RecursiveMode::NonRecursive)
+                .map(|_| watcher)
+        }) {
+            Ok(watcher) => Some(watcher),
+            Err(e) => {
+                error!(
+                    "Unable to watch {:?} for changes, reload with SIGHUP instead: {}",
+                    self.config_path, e
+                );
+                None
+            }
+        }
+    }
+}
+
+/// Signals `sender` whenever SIGHUP is received.
+#[cfg(unix)]
+fn reload_on_hangup(sender: UnboundedSender<()>) {
+    use tokio::signal::unix::{signal, Signal, SignalKind};
+    let mut hangup: Signal = signal(SignalKind::hangup()).expect("Unable to listen for SIGHUP");
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            info!("Received SIGHUP, reloading the configuration");
+            if sender.send(()).is_err() {
+                break;
+            }
+        }
+    });
+}
+
+#[cfg(not(unix))]
+fn reload_on_hangup(_sender: UnboundedSender<()>) {}
@@ -0,0 +1,659 @@
+use crate::sha::{digest, digest_reader};
+use sandman_share::config::HashAlgorithm;
+use serde::Deserialize;
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File, Metadata};
+use std::io::{self, BufRead, BufReader, Cursor, Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// Prefix of the records of stored symbolic links, followed by the digest of the link's target.
+const SYMLINK_RECORD: &str = "symlink:";
+
+/// Prefix of the records of special files, followed by their kind and device number.
+const SPECIAL_RECORD: &str = "special:";
+
+/// Prefix of the records of hard links, followed by the key of the path the file is stored under.
+const HARDLINK_RECORD: &str = "hardlink:";
+
+/// Prefix of the records of sparse files, followed by the digest of their full contents.
+const SPARSE_RECORD: &str = "sparse:";
+
+/// Key of the object metadata describing what kind of entry an uploaded object holds.
+const TYPE_METADATA: &str = "sandman-type";
+
+/// The JSON line heading the uploaded object of a sparse file.
+#[derive(Deserialize)]
+struct SparseHeader {
+    /// Size of the file including its holes.
+    size: u64,
+    /// The `[offset, length]` pairs of the data following the header.
+    extents: Vec<(u64, usize)>,
+}
+
+/// Kinds of entries a backup records. Regular files are recorded by the digest of their contents
+/// alone, so histories written before other kinds existed remain valid.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum EntryKind {
+    /// A regular file, or the target of a followed symbolic link.
+    File,
+
+    /// A symbolic link stored as a link, its contents being the link's target.
+    Symlink,
+
+    /// A FIFO, socket or device file, described by its kind and device number.
+    Special(String),
+
+    /// A further hard link to a file stored once, under the key held.
+    Hardlink(String),
+
+    /// A regular file with holes, uploaded without them alongside a map of its data extents.
+    Sparse,
+}
+
+impl EntryKind {
+    /// Determines the kind of the entry at `path` described by `metadata`, confirming that a file
+    /// with fewer blocks allocated than its size has holes rather than being compressed by its
+    /// filesystem.
+    pub(crate) fn of_file(path: &Path, metadata: &Metadata) -> Self {
+        match EntryKind::of(metadata) {
+            EntryKind::Sparse if !has_holes(path) => EntryKind::File,
+            kind => kind,
+        }
+    }
+
+    /// Determines the kind of entry described by `metadata`, which is not followed for stored
+    /// symbolic links. Files with fewer blocks allocated than their size are taken to be sparse.
+    pub(crate) fn of(metadata: &Metadata) -> Self {
+        if metadata.is_file() && is_sparse(metadata) {
+            EntryKind::Sparse
+        } else if metadata.is_file() {
+            EntryKind::File
+        } else if metadata.file_type().is_symlink() {
+            EntryKind::Symlink
+        } else {
+            EntryKind::Special(describe_special(metadata))
+        }
+    }
+
+    /// Whether the entry holds file data of its own, which cleanup may remove once uploaded. Links
+    /// and special files are left in place.
+    pub(crate) fn is_removable(&self) -> bool {
+        matches!(self, EntryKind::File | EntryKind::Sparse)
+    }
+
+    /// Whether the entry is a FIFO, socket or device file.
+    pub(crate) fn is_special(&self) -> bool {
+        matches!(self, EntryKind::Special(_))
+    }
+
+    /// Determines the kind of entry from its record in a history.
+    pub(crate) fn of_record(record: &str) -> Self {
+        if record.starts_with(SYMLINK_RECORD) {
+            EntryKind::Symlink
+        } else if let Some(description) = record.strip_prefix(SPECIAL_RECORD) {
+            EntryKind::Special(description.to_string())
+        } else if let Some(key) = record.strip_prefix(HARDLINK_RECORD) {
+            EntryKind::Hardlink(key.to_string())
+        } else if record.starts_with(SPARSE_RECORD) {
+            EntryKind::Sparse
+        } else {
+            EntryKind::File
+        }
+    }
+
+    /// Reads the contents recorded for the entry at `path`: the bytes of a file, the target of a
+    /// symbolic link, the key a hard link refers to, or nothing for special files, which are never
+    /// opened.
+    pub(crate) fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        match self {
+            EntryKind::File | EntryKind::Sparse => fs::read(path),
+            EntryKind::Hardlink(key) => fs::symlink_metadata(path).map(|_| key.as_bytes().to_vec()),
+            EntryKind::Symlink => Ok(fs::read_link(path)?.into_os_string().into_encoded_bytes()),
+            EntryKind::Special(_) => fs::symlink_metadata(path).map(|_| vec![]),
+        }
+    }
+
+    /// Creates the record of the entry kept in a history, so that a change to either its contents
+    /// or its kind is detected.
+    ///
+    /// # Arguments
+    ///
+    /// * `algorithm` - The `HashAlgorithm` to digest the contents with.
+    /// * `bytes` - The contents returned by `read`.
+    pub(crate) fn record(&self, algorithm: HashAlgorithm, bytes: &[u8]) -> String {
+        match self {
+            EntryKind::File => digest(algorithm, bytes),
+            EntryKind::Symlink => format!("{}{}", SYMLINK_RECORD, digest(algorithm, bytes)),
+            EntryKind::Special(description) => format!("{}{}", SPECIAL_RECORD, description),
+            EntryKind::Hardlink(key) => format!("{}{}", HARDLINK_RECORD, key),
+            EntryKind::Sparse => format!("{}{}", SPARSE_RECORD, digest(algorithm, bytes)),
+        }
+    }
+
+    /// Reads the entry at `path` and creates its record, as `record` does with the contents
+    /// returned by `read`. Sparse files are digested one extent at a time rather than read whole.
+    pub(crate) fn record_of(&self, algorithm: HashAlgorithm, path: &Path) -> io::Result<String> {
+        match self {
+            EntryKind::Sparse => {
+                let contents: SparseReader = SparseReader::contents(path)?;
+                Ok(format!(
+                    "{}{}",
+                    SPARSE_RECORD,
+                    digest_reader(algorithm, contents)?
+                ))
+            }
+            _ => Ok(self.record(algorithm, &self.read(path)?)),
+        }
+    }
+
+    /// Returns the metadata attached to the uploaded object of the entry, allowing a restore to
+    /// recreate links and special files. Regular files carry none.
+    pub(crate) fn object_metadata(&self) -> Option<HashMap<String, String>> {
+        let kind: String = match self {
+            EntryKind::File => return None,
+            EntryKind::Symlink => "symlink".to_string(),
+            EntryKind::Special(description) => description.clone(),
+            EntryKind::Hardlink(_) => "hardlink".to_string(),
+            EntryKind::Sparse => "sparse".to_string(),
+        };
+        Some(HashMap::from([(TYPE_METADATA.to_string(), kind)]))
+    }
+
+    /// Determines the kind of entry an uploaded object holds from its metadata and body, the
+    /// inverse of `object_metadata`. The body is only read for hard links, whose object holds the
+    /// key they link to, and left for `restore` otherwise.
+    pub(crate) fn of_object(
+        metadata: Option<&HashMap<String, String>>,
+        body: &mut impl Read,
+    ) -> io::Result<Self> {
+        Ok(
+            match metadata.and_then(|metadata| metadata.get(TYPE_METADATA)) {
+                None => EntryKind::File,
+                Some(kind) => match kind.as_str() {
+                    "symlink" => EntryKind::Symlink,
+                    "hardlink" => {
+                        let mut target: Vec<u8> = vec![];
+                        body.read_to_end(&mut target)?;
+                        EntryKind::Hardlink(String::from_utf8_lossy(&target).into_owned())
+                    }
+                    "sparse" => EntryKind::Sparse,
+                    description => EntryKind::Special(description.to_string()),
+                },
+            },
+        )
+    }
+
+    /// Recreates the entry at `path` from the body of its uploaded object, the inverse of
+    /// `object_body`. The body is written as it is read, so large files are never held in memory,
+    /// and sparse files are written with their holes. Hard links are left to the caller, which
+    /// knows where the file they link to was restored, and special files are not recreated.
+    pub(crate) fn restore(&self, mut body: impl Read, path: &Path) -> io::Result<()> {
+        match self {
+            EntryKind::File => io::copy(&mut body, &mut File::create(path)?).map(|_| ()),
+            EntryKind::Symlink => {
+                let mut target: Vec<u8> = vec![];
+                body.read_to_end(&mut target)?;
+                create_symlink(target, path)
+            }
+            EntryKind::Sparse => restore_sparse(body, path),
+            EntryKind::Hardlink(_) | EntryKind::Special(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "entry cannot be recreated from its object",
+            )),
+        }
+    }
+}
+
+/// A run of a sparse file read by `SparseReader`.
+enum Segment {
+    /// `length` bytes of data at `offset` in the file.
+    Data { offset: u64, length: u64 },
+
+    /// A hole of `length` bytes, read as zeros.
+    Hole { length: u64 },
+}
+
+/// Streams a sparse file one extent at a time without holding it in memory, either as its
+/// uploaded object, a JSON line holding the file's size and data extents as `[offset, length]`
+/// pairs followed by the data of those extents, or as its full contents with the holes read as
+/// zeros.
+pub(crate) struct SparseReader {
+    file: File,
+    header: Cursor<Vec<u8>>,
+    segments: VecDeque<Segment>,
+    /// The segment being read and how many of its bytes are left.
+    current: Option<(Segment, u64)>,
+    size: u64,
+}
+
+impl SparseReader {
+    /// Opens the uploaded object of the sparse file at `path`.
+    pub(crate) fn object(path: &Path) -> io::Result<Self> {
+        let file: File = File::open(path)?;
+        let file_size: u64 = file.metadata()?.len();
+        let extents: Vec<(u64, u64)> = data_extents(&file, file_size)?;
+        let header: String =
+            serde_json::json!({ "size": file_size, "extents": extents }).to_string();
+        let mut header: Vec<u8> = header.into_bytes();
+        header.push(b'\n');
+
+        let size: u64 = header.len() as u64 + extents.iter().map(|(_, length)| length).sum::<u64>();
+        let segments: VecDeque<Segment> = extents
+            .into_iter()
+            .map(|(offset, length)| Segment::Data { offset, length })
+            .collect();
+        Ok(SparseReader {
+            file,
+            header: Cursor::new(header),
+            segments,
+            current: None,
+            size,
+        })
+    }
+
+    /// Opens the full contents of the sparse file at `path`, its holes read as zeros.
+    pub(crate) fn contents(path: &Path) -> io::Result<Self> {
+        let file: File = File::open(path)?;
+        let size: u64 = file.metadata()?.len();
+        let mut segments: VecDeque<Segment> = VecDeque::new();
+        let mut position: u64 = 0;
+        for (offset, length) in data_extents(&file, size)? {
+            if offset > position {
+                segments.push_back(Segment::Hole {
+                    length: offset - position,
+                });
+            }
+            segments.push_back(Segment::Data { offset, length });
+            position = offset + length;
+        }
+        if size > position {
+            segments.push_back(Segment::Hole {
+                length: size - position,
+            });
+        }
+        Ok(SparseReader {
+            file,
+            header: Cursor::new(vec![]),
+            segments,
+            current: None,
+            size,
+        })
+    }
+
+    /// Returns the number of bytes the reader yields.
+    pub(crate) fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+impl Read for SparseReader {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let read: usize = self.header.read(buffer)?;
+        if read > 0 || buffer.is_empty() {
+            return Ok(read);
+        }
+        loop {
+            match self.current.take() {
+                Some((_, 0)) | None => match self.segments.pop_front() {
+                    None => return Ok(0),
+                    Some(segment @ Segment::Data { offset, length }) => {
+                        self.file.seek(SeekFrom::Start(offset))?;
+                        self.current = Some((segment, length));
+                    }
+                    Some(segment @ Segment::Hole { length }) => {
+                        self.current = Some((segment, length))
+                    }
+                },
+                Some((segment, left)) => {
+                    let limit: usize = left.min(buffer.len() as u64) as usize;
+                    let read: usize = match segment {
+                        Segment::Data { .. } => self.file.read(&mut buffer[..limit])?,
+                        Segment::Hole { .. } => {
+                            buffer[..limit].fill(0);
+                            limit
+                        }
+                    };
+                    if read == 0 {
+                        return Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            "sparse file shrank while being read",
+                        ));
+                    }
+                    self.current = Some((segment, left - read as u64));
+                    return Ok(read);
+                }
+            }
+        }
+    }
+}
+
+/// Whether the file at `path` has holes, as reported by its filesystem.
+fn has_holes(path: &Path) -> bool {
+    let extents = File::open(path).and_then(|file| {
+        let size: u64 = file.metadata()?.len();
+        Ok((data_extents(&file, size)?, size))
+    });
+    match extents {
+        Ok((extents, size)) => extents.iter().map(|(_, length)| length).sum::<u64>() < size,
+        Err(_) => false,
+    }
+}
+
+/// Finds the extents of `file` holding data as `(offset, length)` pairs with `SEEK_DATA` and
+/// `SEEK_HOLE`, everything between them being holes. Where the filesystem cannot report holes
+/// the whole file is a single extent.
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+fn data_extents(file: &File, size: u64) -> io::Result<Vec<(u64, u64)>> {
+    use std::os::unix::io::AsRawFd;
+    let seek = |offset: u64, whence: libc::c_int| -> io::Result<Option<u64>> {
+        // SAFETY: lseek only moves the offset of the descriptor `file` owns for its lifetime
+        match unsafe { libc::lseek(file.as_raw_fd(), offset as libc::off_t, whence) } {
+            -1 => match io::Error::last_os_error() {
+                e if e.raw_os_error() == Some(libc::ENXIO) => Ok(None),
+                e => Err(e),
+            },
+            position => Ok(Some(position as u64)),
+        }
+    };
+
+    let mut extents: Vec<(u64, u64)> = vec![];
+    let mut offset: u64 = 0;
+    while offset < size {
+        let start: u64 = match seek(offset, libc::SEEK_DATA) {
+            Ok(Some(start)) if start < size => start,
+            Ok(_) => break,
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => return Ok(vec![(0, size)]),
+            Err(e) => return Err(e),
+        };
+        let end: u64 = seek(start, libc::SEEK_HOLE)?.unwrap_or(size).min(size);
+        if end <= start {
+            break;
+        }
+        extents.push((start, end - start));
+        offset = end;
+    }
+    Ok(extents)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+fn data_extents(_file: &File, size: u64) -> io::Result<Vec<(u64, u64)>> {
+    Ok(match size {
+        0 => vec![],
+        size => vec![(0, size)],
+    })
+}
+
+/// Writes a sparse file from its uploaded object as it is read, seeking over the holes between
+/// its extents.
+fn restore_sparse(body: impl Read, path: &Path) -> io::Result<()> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+    let mut body: BufReader<_> = BufReader::new(body);
+    let mut header: Vec<u8> = vec![];
+    body.read_until(b'\n', &mut header)?;
+    if header.pop() != Some(b'\n') {
+        return Err(invalid("sparse object has no header"));
+    }
+    let header: SparseHeader = serde_json::from_slice(&header)
+        .map_err(|e| invalid(&format!("invalid sparse header: {}", e)))?;
+
+    let mut file: File = File::create(path)?;
+    file.set_len(header.size)?;
+    for (offset, length) in header.extents {
+        file.seek(SeekFrom::Start(offset))?;
+        let length: u64 = length as u64;
+        if io::copy(&mut body.by_ref().take(length), &mut file)? < length {
+            return Err(invalid("sparse object is shorter than its extents"));
+        }
+    }
+    Ok(())
+}
+
+/// Creates a symbolic link at `path` to the target held in `body`.
+#[cfg(unix)]
+fn create_symlink(body: Vec<u8>, path: &Path) -> io::Result<()> {
+    use std::ffi::OsString;
+    use std::os::unix::ffi::OsStringExt;
+    std::os::unix::fs::symlink(OsString::from_vec(body), path)
+}
+
+#[cfg(not(unix))]
+fn create_symlink(_body: Vec<u8>, _path: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "symbolic links are only restored on Unix",
+    ))
+}
+
+/// Whether the file described by `metadata` has fewer blocks allocated than its size requires.
+#[cfg(unix)]
+fn is_sparse(metadata: &Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    metadata.blocks() * 512 < metadata.len()
+}
+
+#[cfg(not(unix))]
+fn is_sparse(_metadata: &Metadata) -> bool {
+    false
+}
+
+/// Returns the device and inode identifying the file described by `metadata` when it has further
+/// hard links, `None` otherwise.
+#[cfg(unix)]
+pub(crate) fn link_identity(metadata: &Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    (metadata.is_file() && metadata.nlink() > 1).then(|| (metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn link_identity(_metadata: &Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// Describes a special file as its kind, followed by its device number for device files.
+#[cfg(unix)]
+fn describe_special(metadata: &Metadata) -> String {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+    let file_type = metadata.file_type();
+    if file_type.is_fifo() {
+        "fifo".to_string()
+    } else if file_type.is_socket() {
+        "socket".to_string()
+    } else if file_type.is_char_device() {
+        format!("char:{}", metadata.rdev())
+    } else if file_type.is_block_device() {
+        format!("block:{}", metadata.rdev())
+    } else {
+        "unknown".to_string()
+    }
+}
+
+#[cfg(not(unix))]
+fn describe_special(_metadata: &Metadata) -> String {
+    "unknown".to_string()
+}
+
+/// Returns the device the entry described by `metadata` resides on, `None` where this is unknown.
+#[cfg(unix)]
+pub(crate) fn device_of(metadata: &Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(metadata.dev())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn device_of(_metadata: &Metadata) -> Option<u64> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::path::PathBuf;
+    use tempfile::TempDir;
+
+    const MIB: u64 = 1 << 20;
+
+    /// Creates a file of `size` bytes holding only the given data, the rest left as holes where
+    /// the filesystem supports them.
+    fn sparse_file(path: &Path, size: u64, data: &[(u64, &[u8])]) {
+        let mut file: File = File::create(path).unwrap();
+        file.set_len(size).unwrap();
+        for (offset, bytes) in data {
+            file.seek(SeekFrom::Start(*offset)).unwrap();
+            file.write_all(bytes).unwrap();
+        }
+    }
+
+    fn read_all(mut reader: impl Read) -> Vec<u8> {
+        let mut bytes: Vec<u8> = vec![];
+        reader.read_to_end(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn sparse_objects_restore_identical_files() {
+        let directory: TempDir = TempDir::new().unwrap();
+        let source: PathBuf = directory.path().join("disk.img");
+        let restored: PathBuf = directory.path().join("restored.img");
+        sparse_file(
+            &source,
+            8 * MIB,
+            &[(0, b"head"), (3 * MIB, &[7; 5000]), (8 * MIB - 4, b"tail")],
+        );
+
+        let reader: SparseReader = SparseReader::object(&source).unwrap();
+        let size: u64 = reader.size();
+        let body: Vec<u8> = read_all(reader);
+        EntryKind::Sparse.restore(&body[..], &restored).unwrap();
+
+        assert_eq!(body.len() as u64, size);
+        assert_eq!(fs::read(&restored).unwrap(), fs::read(&source).unwrap());
+        if has_holes(&source) {
+            assert!(size < MIB);
+            assert!(has_holes(&restored));
+        }
+    }
+
+    /// Reads at most 7 bytes at a time, as a download arriving in small pieces does.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+            let length: usize = buffer.len().min(7);
+            self.0.read(&mut buffer[..length])
+        }
+    }
+
+    #[test]
+    fn sparse_objects_are_restored_as_they_arrive() {
+        let directory: TempDir = TempDir::new().unwrap();
+        let source: PathBuf = directory.path().join("disk.img");
+        let restored: PathBuf = directory.path().join("restored.img");
+        sparse_file(&source, 2 * MIB, &[(5, b"head"), (MIB, &[3; 100])]);
+
+        let body: Vec<u8> = read_all(SparseReader::object(&source).unwrap());
+        EntryKind::Sparse
+            .restore(Trickle(&body), &restored)
+            .unwrap();
+        EntryKind::File
+            .restore(
+                Trickle(b"plain contents"),
+                &directory.path().join("plain.txt"),
+            )
+            .unwrap();
+
+        assert_eq!(fs::read(&restored).unwrap(), fs::read(&source).unwrap());
+        assert_eq!(
+            fs::read(directory.path().join("plain.txt")).unwrap(),
+            b"plain contents"
+        );
+    }
+
+    #[test]
+    fn sparse_files_of_holes_alone_are_restored() {
+        let directory: TempDir = TempDir::new().unwrap();
+        let source: PathBuf = directory.path().join("empty.img");
+        let restored: PathBuf = directory.path().join("restored.img");
+        sparse_file(&source, 2 * MIB, &[]);
+
+        let body: Vec<u8> = read_all(SparseReader::object(&source).unwrap());
+        restore_sparse(&body[..], &restored).unwrap();
+
+        assert_eq!(fs::metadata(&restored).unwrap().len(), 2 * MIB);
+        assert_eq!(fs::read(&restored).unwrap(), fs::read(&source).unwrap());
+    }
+
+    #[test]
+    fn sparse_contents_read_holes_as_zeros() {
+        let directory: TempDir = TempDir::new().unwrap();
+        let source: PathBuf = directory.path().join("disk.img");
+        sparse_file(&source, 4 * MIB, &[(MIB + 3, b"data")]);
+
+        let reader: SparseReader = SparseReader::contents(&source).unwrap();
+
+        assert_eq!(reader.size(), 4 * MIB);
+        assert_eq!(read_all(reader), fs::read(&source).unwrap());
+    }
+
+    #[test]
+    fn sparse_records_digest_the_full_contents() {
+        let directory: TempDir = TempDir::new().unwrap();
+        let source: PathBuf = directory.path().join("disk.img");
+        sparse_file(&source, 3 * MIB, &[(2 * MIB, b"data")]);
+        let contents: Vec<u8> = fs::read(&source).unwrap();
+
+        for algorithm in [
+            HashAlgorithm::Sha256,
+            HashAlgorithm::Blake3,
+            HashAlgorithm::Xxh3,
+        ] {
+            assert_eq!(
+                EntryKind::Sparse.record_of(algorithm, &source).unwrap(),
+                EntryKind::Sparse.record(algorithm, &contents)
+            );
+        }
+    }
+
+    #[test]
+    fn malformed_sparse_objects_are_rejected() {
+        let directory: TempDir = TempDir::new().unwrap();
+        let path: PathBuf = directory.path().join("restored.img");
+
+        assert!(restore_sparse(&b"no header"[..], &path).is_err());
+        assert!(restore_sparse(&b"{\"size\": 8}\n"[..], &path).is_err());
+        assert!(restore_sparse(&b"{\"size\": 8, \"extents\": [[0, 4]]}\nab"[..], &path).is_err());
+    }
+
+    #[test]
+    fn kinds_survive_their_records_and_objects() {
+        let kinds: Vec<EntryKind> = vec![
+            EntryKind::File,
+            EntryKind::Symlink,
+            EntryKind::Special("fifo".to_string()),
+            EntryKind::Hardlink("docs/a.txt".to_string()),
+            EntryKind::Sparse,
+        ];
+        for kind in kinds {
+            let record: String = kind.record(HashAlgorithm::Sha256, b"docs/a.txt");
+            let mut body: &[u8] = match &kind {
+                EntryKind::Hardlink(key) => key.as_bytes(),
+                _ => b"",
+            };
+
+            assert_eq!(EntryKind::of_record(&record), kind);
+            assert_eq!(
+                EntryKind::of_object(kind.object_metadata().as_ref(), &mut body).unwrap(),
+                kind
+            );
+        }
+    }
+
+    #[test]
+    fn only_files_are_removable() {
+        assert!(EntryKind::File.is_removable());
+        assert!(EntryKind::Sparse.is_removable());
+        assert!(!EntryKind::Symlink.is_removable());
+        assert!(!EntryKind::Hardlink("a".to_string()).is_removable());
+        assert!(!EntryKind::Special("fifo".to_string()).is_removable());
+    }
+}
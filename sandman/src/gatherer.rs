@@ -1,195 +1,901 @@
 use crate::args::GatherArgs;
-use crate::backup::backup;
-use crate::sha::{
-    generate_shas, get_prior_shas, get_sha_diff, merge_diff_old, write_file_shas, ShaFile,
-};
-use crate::sandman::get_ignore;
-use ignore::gitignore::Gitignore;
-use log::{error, info};
-use sandman_share::config::{AwsConfig, SandmanUploadedFile};
-use sandman_share::consts::SANDMAN_HISTORY;
-use std::ffi::OsStr;
-use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use tokio::task::JoinHandle;
+use crate::backup::{backup, snapshot_name, Uploads};
+use crate::clock::now;
+use crate::control::{GathererControl, GathererState, RunOutcome, RunReport, RunStats};
+use crate::hooks::{run_env, run_hook, HookKind};
+use crate::lock::BackupLock;
+use crate::notifications::notify_run;
+use crate::runs::{load_runs, record_run, MAX_REPORTED_ERRORS};
+use crate::schedule::Schedule;
+use crate::selection::Selection;
+use crate::sha::{generate_shas, get_sha_diff, merge_diff_old, rebaseline_shas, ShaFile};
+use crate::store::HistoryStore;
+use chrono::{DateTime, Utc};
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+use sandman_share::config::AwsConfig;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::unbounded_channel;
+use tokio::task::{block_in_place, JoinHandle};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, info_span, Instrument, Span};
+use uuid::Uuid;
+
+/// Seconds without further changes a watched backup waits for before backing up, unless configured.
+pub(crate) const DEFAULT_DEBOUNCE: u64 = 5;
+
+/// Debounce periods a watched backup waits for at most after the first change, so that a
+/// directory which never stops changing is still backed up.
+const MAX_DEBOUNCE_PERIODS: u32 = 12;
+
+/// Reasons a gather run could not be completed.
+#[derive(Debug)]
+pub(crate) enum GatherError {
+    /// Another process holds the lock of this backup.
+    Locked(PathBuf),
+
+    /// The lock file of this backup could not be opened.
+    Lock(PathBuf, io::Error),
+
+    /// The schedule expression or time zone of this backup is invalid.
+    Schedule(String),
+
+    /// The schedule of this backup has no further runs.
+    Unscheduled,
+
+    /// The directory of this backup could not be watched for changes.
+    Watch(notify::Error),
+
+    /// The pre-hook of this backup failed, aborting the run.
+    PreHook(String),
+
+    /// The history of this backup could not be opened, read or written.
+    History(String),
+
+    /// The changed files of this backup could not be uploaded.
+    Upload(String),
+
+    /// The gatherer's task panicked.
+    Panicked(String),
+
+    /// The gatherer was aborted for not stopping within the shutdown timeout.
+    Aborted,
+}
+
+/// What the task of a gatherer returns: the report of its run when it ran once, `None` when it
+/// ran on its schedule until stopped.
+pub(crate) type GatherResult = Result<Option<RunReport>, GatherError>;
+
+impl fmt::Display for GatherError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GatherError::Locked(path) => write!(
+                f,
+                "Another Sandman instance is already backing up this directory (lock {:?})",
+                path
+            ),
+            GatherError::Lock(path, e) => write!(f, "Unable to open lock {:?}: {}", path, e),
+            GatherError::Schedule(e) => write!(f, "{}", e),
+            GatherError::Unscheduled => write!(f, "The schedule has no further runs"),
+            GatherError::Watch(e) => write!(f, "Unable to watch for changes: {}", e),
+            GatherError::PreHook(e) => write!(f, "Aborted, {}", e),
+            GatherError::History(e) => write!(f, "{}", e),
+            GatherError::Upload(e) => write!(f, "Unable to upload: {}", e),
+            GatherError::Panicked(e) => write!(f, "The gatherer stopped unexpectedly: {}", e),
+            GatherError::Aborted => write!(f, "Aborted after the shutdown timeout"),
+        }
+    }
+}
 
 pub(crate) struct Gatherer {
     args: GatherArgs,
     aws: Option<AwsConfig>,
-    pub(crate) handle: Option<JoinHandle<()>>,
+    control: Arc<GathererControl>,
+    handle: Option<JoinHandle<GatherResult>>,
 }
 
 impl Gatherer {
     pub(crate) fn new(args: GatherArgs, aws: Option<AwsConfig>) -> Self {
+        let control: Arc<GathererControl> = GathererControl::new(args.name.clone());
+        control.restore(&load_runs(&args.name));
         Gatherer {
+            control,
             args,
             aws,
             handle: None,
         }
     }
 
-    pub(crate) fn gather(&mut self, oneshot: bool, exit_flag: Option<Arc<Mutex<bool>>>) {
-        let handle = tokio::task::spawn(start_gathering(
-            self.args.clone(),
-            self.aws.clone(),
-            oneshot,
-            exit_flag,
-        ));
+    /// Returns the control reporting this gatherer's status and steering its runs.
+    pub(crate) fn control(&self) -> Arc<GathererControl> {
+        self.control.clone()
+    }
+
+    /// Returns the `GatherArgs` this gatherer was created with.
+    pub(crate) fn args(&self) -> &GatherArgs {
+        &self.args
+    }
+
+    pub(crate) fn gather(&mut self, oneshot: bool, shutdown: CancellationToken) {
+        let handle = tokio::task::spawn(
+            start_gathering(
+                self.args.clone(),
+                self.aws.clone(),
+                self.control.clone(),
+                oneshot,
+                shutdown,
+            )
+            .instrument(info_span!("gatherer", backup = %self.args.name)),
+        );
         self.handle = Option::from(handle)
     }
 }
 
+/// Waits for every gatherer to return. Once `shutdown` is cancelled gatherers have `timeout` to
+/// finish their uploads and write their histories, after which they are aborted.
+///
+/// # Returns
+///
+/// The name of every started gatherer with its result, in order. Gatherers which had to be
+/// aborted return `GatherError::Aborted`.
+pub(crate) async fn join_gatherers(
+    gatherers: Vec<Gatherer>,
+    shutdown: &CancellationToken,
+    timeout: Duration,
+) -> Vec<(String, GatherResult)> {
+    let (names, mut handles): (Vec<String>, Vec<JoinHandle<GatherResult>>) = gatherers
+        .into_iter()
+        .filter_map(|gatherer| Some((gatherer.args.name, gatherer.handle?)))
+        .unzip();
+    let mut results: Vec<GatherResult> = vec![];
+
+    tokio::select! {
+        _ = join_all(&mut handles, &mut results) => {}
+        _ = shutdown.cancelled() => {
+            if tokio::time::timeout(timeout, join_all(&mut handles, &mut results)).await.is_err() {
+                error!("Backups still running after {:?}, aborting them", timeout);
+                handles.iter().for_each(JoinHandle::abort);
+            }
+        }
+    }
+    results.resize_with(handles.len(), || Err(GatherError::Aborted));
+    names.into_iter().zip(results).collect()
+}
+
+/// Awaits the handles in order, collecting their results. Handles whose result was already
+/// collected by an earlier, interrupted call are skipped, and a gatherer which panicked is
+/// reported as failed rather than taking down the caller.
+async fn join_all(handles: &mut [JoinHandle<GatherResult>], results: &mut Vec<GatherResult>) {
+    let collected: usize = results.len();
+    for handle in handles.iter_mut().skip(collected) {
+        let result: GatherResult = handle.await.unwrap_or_else(|e| {
+            error!("Gatherer task failed: {}", e);
+            Err(GatherError::Panicked(e.to_string()))
+        });
+        results.push(result);
+    }
+}
+
 /// Gathers and processes SHA files, and performs backup.
 ///
 /// # Arguments
 ///
 /// * `gather_args` - Arguments for gathering and backing up.
 /// * `aws_config` - Optional AWS configuration.
-/// * `oneshot` - bool flag indicating whether the function should return after one run
-/// * `exit_flag` - Optional bool used as a signal for when the function should exit
+/// * `control` - The gatherer's `GathererControl`, marked stopped once the function returns.
+/// * `oneshot` - bool flag indicating whether the function should run once straight away, ignoring
+///   the schedule, and return
+/// * `shutdown` - Token cancelled when the function should stop scheduling runs and return
 async fn start_gathering(
     gather_args: GatherArgs,
     aws_config: Option<AwsConfig>,
+    control: Arc<GathererControl>,
     oneshot: bool,
-    exit_flag: Option<Arc<Mutex<bool>>>,
-) {
+    shutdown: CancellationToken,
+) -> GatherResult {
+    let _stopped: StoppedGuard = StoppedGuard(&control);
+    run_gatherer(&gather_args, &aws_config, &control, oneshot, &shutdown).await
+}
+
+/// Marks a gatherer stopped once dropped, also when its task panics or is aborted.
+struct StoppedGuard<'a>(&'a GathererControl);
+
+impl Drop for StoppedGuard<'_> {
+    fn drop(&mut self) {
+        self.0.set_state(GathererState::Stopped);
+        self.0.set_next_run(None);
+    }
+}
+
+/// Runs the backups of a gatherer on its schedule, or as its directory changes when watched,
+/// until `shutdown` is cancelled.
+///
+/// # Returns
+///
+/// The report of the run when `oneshot` is set, otherwise `None` once stopped.
+async fn run_gatherer(
+    gather_args: &GatherArgs,
+    aws_config: &Option<AwsConfig>,
+    control: &GathererControl,
+    oneshot: bool,
+    shutdown: &CancellationToken,
+) -> GatherResult {
+    let schedule: Schedule = match Schedule::new(gather_args) {
+        Ok(schedule) => schedule,
+        Err(e) => {
+            error!("{}", e);
+            return Err(GatherError::Schedule(e));
+        }
+    };
     if oneshot {
-        return gather(&gather_args, &aws_config).await;
+        return Ok(run_backup(gather_args, aws_config, None, control, shutdown).await);
     }
-    if exit_flag.is_some() {
-        info!(
-            "[Gatherer - {}] Starting to watch for backup with {} - every {}s",
-            gather_args.name, gather_args.local_directory, gather_args.interval
-        );
-        let exit = exit_flag.unwrap();
-        'gathering: loop {
-            if *exit.lock().unwrap() {
-                break 'gathering;
-            };
-            gather(&gather_args, &aws_config).await;
-            async_std::task::sleep(Duration::from_secs(1)).await;
+    if gather_args.watch {
+        match watch_gathering(gather_args, aws_config, &schedule, control, shutdown).await {
+            Err(GatherError::Watch(e)) => error!(
+                "Unable to watch {}: {}, falling back to the schedule",
+                gather_args.local_directory, e
+            ),
+            result => return result.map(|()| None),
+        }
+    }
+    info!(
+        "Starting to watch for backup with {} - {}",
+        gather_args.local_directory,
+        match &gather_args.schedule {
+            Some(expression) => format!("on {:?}", expression),
+            None => format!("every {}s", gather_args.interval),
+        }
+    );
+    while !shutdown.is_cancelled() {
+        // Failed runs are logged by `run_backup`
+        if let Err(GatherError::Unscheduled) =
+            gather(gather_args, aws_config, &schedule, control, shutdown).await
+        {
+            return Err(GatherError::Unscheduled);
         }
+        async_std::task::sleep(Duration::from_secs(1)).await;
     }
+    info!("Stopped");
+    Ok(None)
 }
 
-
-/// Asynchronously checks if the current time has reached or surpassed the configured start time for the gatherer.
-/// If the current time is earlier than the start time, the function calculates the wait time and sleeps for that
-/// duration before proceeding.
+/// Runs the backups of a watched directory. Changed paths reported by the filesystem are collected
+/// until no further change arrived for the debounce period, or at most `MAX_DEBOUNCE_PERIODS`
+/// periods, and then only those paths are backed up. The whole directory is still rescanned on its
+/// schedule, as a safety net for missed events.
 ///
 /// # Arguments
 ///
-/// * `gather_args` - A reference to the `GatherArgs` struct that contains the gatherer's configuration,
-/// including the start time in seconds since the UNIX epoch and the gatherer's name.
+/// * `gather_args` - `GatherArgs` of the backup.
+/// * `aws_config` - Optional arguments for the target Aws interface.
+/// * `schedule` - The `Schedule` of the full rescans.
+/// * `control` - The gatherer's `GathererControl`. While paused, changes are collected but not
+///   backed up until the gatherer is resumed.
+/// * `shutdown` - Token cancelled when the function should return.
 ///
-/// # Panics
+/// # Returns
 ///
-/// This function will panic if the system time is earlier than the UNIX epoch, which should not normally occur.
-async fn check_start_time(gather_args: &GatherArgs) {
-    let current_time: Duration = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .expect("SystemTime before UNIX EPOCH!");
-    if current_time < Duration::from_secs(gather_args.start_time) {
-        let wait_time: Duration = Duration::from_secs(gather_args.start_time) - current_time;
-        info!(
-            "[Gatherer - {}] Has not reached it's initial start time sleeping for {:?} seconds...",
-            gather_args.name, wait_time
-        );
-        async_std::task::sleep(wait_time).await;
+/// `GatherError::Watch` if the directory could not be watched.
+async fn watch_gathering(
+    gather_args: &GatherArgs,
+    aws_config: &Option<AwsConfig>,
+    schedule: &Schedule,
+    control: &GathererControl,
+    shutdown: &CancellationToken,
+) -> Result<(), GatherError> {
+    let (sender, mut receiver) = unbounded_channel::<PathBuf>();
+    let name: String = gather_args.name.clone();
+    let mut watcher: RecommendedWatcher =
+        notify::recommended_watcher(move |event: notify::Result<Event>| match event {
+            Ok(event) => event.paths.into_iter().for_each(|path| {
+                let _ = sender.send(path);
+            }),
+            Err(e) => error!(backup = %name, "Watch error: {}", e),
+        })
+        .map_err(GatherError::Watch)?;
+    watcher
+        .watch(
+            Path::new(&gather_args.local_directory),
+            RecursiveMode::Recursive,
+        )
+        .map_err(GatherError::Watch)?;
+    let debounce: Duration = Duration::from_secs(gather_args.debounce.unwrap_or(DEFAULT_DEBOUNCE));
+
+    info!(
+        "Watching {} for changes, debounced by {:?}",
+        gather_args.local_directory, debounce
+    );
+    // Changed paths not yet backed up, and when they are due: once no change arrived for the
+    // debounce period, but no later than `MAX_DEBOUNCE_PERIODS` periods after the first change
+    let mut dirty: Vec<PathBuf> = vec![];
+    let mut quiet_at: Instant = Instant::now();
+    let mut due_by: Instant = Instant::now();
+    let mut next_scan: Option<DateTime<Utc>> = None;
+    while !shutdown.is_cancelled() {
+        tokio::select! {
+            _ = shutdown.cancelled() => break,
+            _ = control.until_resumed() => {}
+        }
+        let next_run: DateTime<Utc> = match next_scan {
+            Some(next_run) => next_run,
+            None => {
+                let last_time: u64 = last_run_time(gather_args, control);
+                let scheduled: DateTime<Utc> = schedule
+                    .next_run(last_time)
+                    .ok_or(GatherError::Unscheduled)?;
+                control.set_next_run(Some(scheduled.timestamp() as u64));
+                debug!("Next full scan at {}", schedule.display(scheduled));
+                *next_scan.insert(scheduled)
+            }
+        };
+        let wait_time: Duration = (next_run - Utc::now()).to_std().unwrap_or_default();
+        let flush_at: Instant = quiet_at.min(due_by);
+
+        // Failed runs are logged by `run_backup`
+        let _: Option<RunReport> = tokio::select! {
+            _ = shutdown.cancelled() => break,
+            _ = control.until_paused() => continue,
+            _ = control.triggered() => {
+                info!("Run triggered");
+                dirty.clear();
+                run_backup(gather_args, aws_config, None, control, shutdown).await
+            }
+            _ = tokio::time::sleep(wait_time) => {
+                dirty.clear();
+                run_backup(gather_args, aws_config, None, control, shutdown).await
+            }
+            Some(path) = receiver.recv() => {
+                if dirty.is_empty() {
+                    due_by = Instant::now() + debounce * MAX_DEBOUNCE_PERIODS;
+                }
+                dirty.push(path);
+                quiet_at = Instant::now() + debounce;
+                continue;
+            }
+            _ = tokio::time::sleep_until(flush_at.into()), if !dirty.is_empty() => {
+                let paths: Vec<PathBuf> = outermost_paths(std::mem::take(&mut dirty));
+                run_backup(gather_args, aws_config, Some(&paths), control, shutdown).await
+            }
+        };
+        next_scan = None;
     }
+    info!("Stopped");
+    Ok(())
 }
 
-/// Asynchronously waits until the configured backup interval has passed since the last backup time.
-/// If the interval has not yet passed, the function calculates the remaining wait time and sleeps for that
-/// duration before proceeding.
+/// Removes duplicates and paths lying below another of `paths`, so that no path is scanned twice.
+fn outermost_paths(mut paths: Vec<PathBuf>) -> Vec<PathBuf> {
+    paths.sort();
+    let mut outermost: Vec<PathBuf> = vec![];
+    for path in paths {
+        if !outermost.last().is_some_and(|last| path.starts_with(last)) {
+            outermost.push(path);
+        }
+    }
+    outermost
+}
+
+/// Returns the time the schedule of a backup counts from, in seconds since the Unix epoch: the
+/// last run recorded by its history, or the gatherer's last full run if that finished later.
+/// Cancelled and failed runs leave the history untouched, so counting from them keeps such a run
+/// from being retried straight away rather than at its next slot.
+fn last_run_time(gather_args: &GatherArgs, control: &GathererControl) -> u64 {
+    let recorded: u64 = match HistoryStore::open(gather_args) {
+        Ok(history) => history.timestamp(),
+        Err(e) => {
+            error!("{}", e);
+            0
+        }
+    };
+    recorded.max(control.last_attempt().unwrap_or(0))
+}
+
+/// Waits until the next run of the backup is due, logging when that is.
 ///
 /// # Arguments
 ///
-/// * `gather_args` - A reference to the `GatherArgs` struct that contains the gatherer's configuration,
-/// including the interval in seconds and the gatherer's name.
+/// * `schedule` - The `Schedule` of the backup.
+/// * `control` - The gatherer's `GathererControl`, told when the next run is due.
 /// * `last_time` - A `Duration` representing the last time a backup was made.
 ///
-/// # Panics
+/// # Returns
 ///
-/// This function will panic if the system time is earlier than the UNIX epoch, which should not normally occur.
-async fn await_backup(gather_args: &GatherArgs, last_time: Duration) {
-    let current_time: Duration = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .expect("SystemTime before UNIX EPOCH!");
-    let time_since: Duration = current_time - last_time;
-    let interval_duration: Duration = Duration::from_secs(gather_args.interval);
+/// `GatherError::Unscheduled` if the schedule never runs again.
+async fn await_next_run(
+    schedule: &Schedule,
+    control: &GathererControl,
+    last_time: Duration,
+) -> Result<(), GatherError> {
+    let next_run: DateTime<Utc> = schedule
+        .next_run(last_time.as_secs())
+        .ok_or(GatherError::Unscheduled)?;
+    let wait_time: Duration = (next_run - Utc::now()).to_std().unwrap_or_default();
+    control.set_next_run(Some(next_run.timestamp() as u64));
 
-    if time_since < Duration::from_secs(gather_args.interval) {
-        let wait_time: Duration = interval_duration - time_since;
+    if !wait_time.is_zero() {
         info!(
-            "[Gatherer - {}] Not ready for backup sleeping for {:?} seconds...",
-            gather_args.name, wait_time
+            "Next run at {}, sleeping for {:?}...",
+            schedule.display(next_run),
+            wait_time
         );
         async_std::task::sleep(wait_time).await;
     }
+    Ok(())
 }
 
 /// Deletes uploaded files, only called if the files belong to a directory that has been flagged
-/// for deletion inside the configuration toml
+/// for deletion inside the configuration toml. Only regular and sparse files are given, links and
+/// special files are never removed.
 ///
 /// # Arguments
 ///
-/// * `uploaded_files` - Array/Vector of `SandmanUploadedFile`s which contains both the local and
-/// remote paths
-async fn cleanup_deletable(uploaded_files: &[SandmanUploadedFile]) {
-    for file in uploaded_files.iter() {
-        match tokio::fs::remove_file(&file.path).await {
+/// * `removable` - Local paths of the uploaded regular and sparse files
+async fn cleanup_deletable(removable: &[PathBuf]) {
+    for path in removable.iter() {
+        match tokio::fs::remove_file(path).await {
             Ok(_) => {
-                info!("Removed local file: {}", &file.path)
+                info!(path = %path.display(), "Removed local file")
             }
             Err(e) => {
-                error!("Unable to remove file: {} {}", &file.path, e)
+                error!(path = %path.display(), "Unable to remove file: {}", e)
             }
         }
     }
 }
 
+/// Takes the lock of a backup, so that no other Sandman process gathers it at the same time.
+///
+/// # Arguments
+///
+/// * `gather_args` - `GatherArgs` of the backup, locating its history and whether to wait.
+/// * `control` - The gatherer's `GathererControl`, told while the lock is waited for.
+/// * `run` - Token cancelled to give up waiting for the lock.
+///
+/// # Returns
+///
+/// The held lock, or `None` if `run` was cancelled while waiting for it.
+async fn lock_backup(
+    gather_args: &GatherArgs,
+    control: &GathererControl,
+    run: &CancellationToken,
+) -> Result<Option<BackupLock>, GatherError> {
+    let path: PathBuf = BackupLock::path_for(&HistoryStore::location(gather_args));
+    if gather_args.wait_for_lock {
+        if let Ok(Some(lock)) = BackupLock::acquire(&path, false).await {
+            return Ok(Some(lock));
+        }
+        info!("Waiting for another instance to release {:?}", path);
+        control.set_state(GathererState::Waiting);
+    }
+    let result: io::Result<Option<BackupLock>> = tokio::select! {
+        _ = run.cancelled() => {
+            info!("Run cancelled while waiting for the lock");
+            return Ok(None);
+        }
+        result = BackupLock::acquire(&path, gather_args.wait_for_lock) => result,
+    };
+    match result {
+        Ok(Some(lock)) => Ok(Some(lock)),
+        Ok(None) => Err(GatherError::Locked(path)),
+        Err(e) => Err(GatherError::Lock(path, e)),
+    }
+}
+
 /// Processes the previously generated sha file and calculates the delta to see if enough time has
 /// elapsed to perform a new check of the designated directory. If that time hasn't been met, the
-/// task will sleep.
+/// task will sleep before running the backup.
 ///
 /// # Arguments
 ///
 /// * `gather_args` - `GatherArgs` detailing the directory to be backed up, interval to
-/// check at, whether it should be cleaned, the target bucket, and the prefix for naming.
+///   check at, whether it should be cleaned, the target bucket, and the prefix for naming.
 /// * `aws_config` - `Option<AwsConfig` optional arguments for the target Aws interface, if not
-/// provided it will be assumed this is set in `.sandman_config.toml`
-async fn gather(gather_args: &GatherArgs, aws_config: &Option<AwsConfig>) {
-    let directory: &PathBuf = &PathBuf::from(OsStr::new(&gather_args.local_directory));
-    let sha_location: &PathBuf = &directory.join(SANDMAN_HISTORY);
-    let old_file_shas: ShaFile = get_prior_shas(sha_location);
-    let last_time: Duration = Duration::from_secs(old_file_shas.timestamp);
-    let ignore: Gitignore = get_ignore(&gather_args.local_directory);
-    let mut current_file_shas: ShaFile = ShaFile::new();
-
-    check_start_time(gather_args).await;
-    await_backup(gather_args, last_time).await;
+///   provided it will be assumed this is set in `.sandman_config.toml`
+/// * `schedule` - The `Schedule` deciding when the backup is due.
+/// * `control` - The gatherer's `GathererControl`. A triggered run starts straight away, while a
+///   due run waits for a paused gatherer to be resumed.
+/// * `shutdown` - Token cancelled when Sandman is shutting down, ending the wait without a run.
+async fn gather(
+    gather_args: &GatherArgs,
+    aws_config: &Option<AwsConfig>,
+    schedule: &Schedule,
+    control: &GathererControl,
+    shutdown: &CancellationToken,
+) -> Result<(), GatherError> {
+    let last_time: Duration = Duration::from_secs(last_run_time(gather_args, control));
+    let due = async {
+        await_next_run(schedule, control, last_time).await?;
+        control.until_resumed().await;
+        Ok::<(), GatherError>(())
+    };
 
-    info!(
-        "[Gatherer - {} ] Ready for backup...of {}",
-        gather_args.name, gather_args.local_directory
-    );
+    tokio::select! {
+        _ = shutdown.cancelled() => return Ok(()),
+        _ = control.triggered() => info!("Run triggered"),
+        result = due => result?,
+    }
+    run_backup(gather_args, aws_config, None, control, shutdown).await;
+    Ok(())
+}
 
-    generate_shas(
-        gather_args.local_directory.clone(),
-        &mut current_file_shas,
-        &ignore,
-    );
+/// Scans the designated directory, or only some of its paths, and uploads whatever changed since
+/// the recorded history. The backup's lock is held until the run completes. Runs over some paths
+/// leave the time of the last run untouched, so full scans keep to their schedule. The history is
+/// only written once the uploads are over and records just the files which were uploaded, so
+/// shutting down mid-run leaves it consistent. Runs of the whole directory are surrounded by the
+/// backup's hooks, which runs over some paths skip so that a pre-hook writing into a watched
+/// directory does not trigger itself.
+///
+/// # Arguments
+///
+/// * `gather_args` - `GatherArgs` of the backup.
+/// * `aws_config` - Optional arguments for the target Aws interface.
+/// * `paths` - Paths to scan, which must not overlap, or `None` to scan the whole directory.
+/// * `control` - The gatherer's `GathererControl`, kept up to date with the run's progress and
+///   told its result.
+/// * `shutdown` - Token cancelled when Sandman is shutting down, ending the uploads early.
+///
+/// # Returns
+///
+/// The `RunReport` of the run, or `None` if a run over some paths found nothing to back up.
+async fn run_backup(
+    gather_args: &GatherArgs,
+    aws_config: &Option<AwsConfig>,
+    paths: Option<&[PathBuf]>,
+    control: &GathererControl,
+    shutdown: &CancellationToken,
+) -> Option<RunReport> {
+    let started: Instant = Instant::now();
+    let started_at: u64 = now();
+    let run_id: String = Uuid::new_v4().to_string();
+    let span: Span = info_span!("run", run_id = %run_id);
+    let run: CancellationToken = control.start_run(shutdown);
+    let result: Result<Option<RunStats>, GatherError> =
+        scan_and_upload(gather_args, aws_config, paths, control, &run, &run_id)
+            .instrument(span.clone())
+            .await;
+    control.end_run();
+    control.set_state(GathererState::Sleeping);
 
-    let sha_diff: ShaFile = get_sha_diff(&old_file_shas, current_file_shas);
-    let merged_shas: ShaFile = merge_diff_old(old_file_shas, &sha_diff);
-    write_file_shas(&merged_shas, sha_location);
+    let (outcome, stats, error): (RunOutcome, RunStats, Option<String>) = match &result {
+        Ok(None) => return None,
+        Ok(Some(stats)) if run.is_cancelled() => (RunOutcome::Cancelled, stats.clone(), None),
+        Ok(Some(stats)) if stats.uploaded < stats.changed => (
+            RunOutcome::Failed,
+            stats.clone(),
+            Some(format!(
+                "{} files could not be uploaded",
+                stats.changed - stats.uploaded
+            )),
+        ),
+        Ok(Some(stats)) => (RunOutcome::Success, stats.clone(), None),
+        Err(e) => (RunOutcome::Failed, RunStats::default(), Some(e.to_string())),
+    };
+    let duration: f64 = started.elapsed().as_secs_f64();
+    span.in_scope(|| match &error {
+        Some(error) => error!(
+            outcome = %outcome,
+            files = stats.changed,
+            uploaded = stats.uploaded,
+            bytes = stats.bytes,
+            duration,
+            "Run finished: {}",
+            error
+        ),
+        None => info!(
+            outcome = %outcome,
+            files = stats.changed,
+            uploaded = stats.uploaded,
+            bytes = stats.bytes,
+            duration,
+            "Run finished"
+        ),
+    });
+    if paths.is_none() {
+        run_post_hooks(gather_args, &run_id, outcome, &stats, error.as_deref())
+            .instrument(span.clone())
+            .await;
+    }
+    let report: RunReport = RunReport {
+        run_id,
+        started: started_at,
+        finished: now(),
+        duration,
+        outcome,
+        scanned: stats.scanned,
+        changed: stats.changed,
+        uploaded: stats.uploaded,
+        failed: stats.failures.len(),
+        bytes: stats.bytes,
+        snapshot: stats.snapshot.clone(),
+        error,
+        errors: stats
+            .failures
+            .iter()
+            .take(MAX_REPORTED_ERRORS)
+            .cloned()
+            .collect(),
+    };
+    record_run(&gather_args.name, &report);
+    if paths.is_none() {
+        control.record_attempt(report.finished);
+    }
+    control.finish_run(report.clone(), &stats, duration);
+    notify_run(gather_args, &report).instrument(span).await;
+    Some(report)
+}
 
-    let uploaded_files: Vec<SandmanUploadedFile> =
-        backup(sha_diff, gather_args, aws_config).await.unwrap();
+/// Runs the post-hook of a finished run, and its failure hook when it failed. Hooks are not
+/// interrupted by shutting down, only by their timeout, and their failures are only logged.
+async fn run_post_hooks(
+    gather_args: &GatherArgs,
+    run_id: &str,
+    outcome: RunOutcome,
+    stats: &RunStats,
+    error: Option<&str>,
+) {
+    let env: Vec<(&'static str, String)> = run_env(run_id, Some(outcome), stats, error);
+    let kinds: &[HookKind] = match outcome {
+        RunOutcome::Failed => &[HookKind::Post, HookKind::OnFailure],
+        _ => &[HookKind::Post],
+    };
+    for kind in kinds {
+        if let Err(e) = run_hook(*kind, gather_args, &env, &CancellationToken::new()).await {
+            error!(hook = %kind, "{}", e);
+        }
+    }
+}
+
+/// Does the work of `run_backup`, ending the uploads early once `run` is cancelled.
+///
+/// # Returns
+///
+/// What the run found and did, or `None` if a run over some paths found nothing to back up.
+async fn scan_and_upload(
+    gather_args: &GatherArgs,
+    aws_config: &Option<AwsConfig>,
+    paths: Option<&[PathBuf]>,
+    control: &GathererControl,
+    run: &CancellationToken,
+    run_id: &str,
+) -> Result<Option<RunStats>, GatherError> {
+    let root: &Path = Path::new(&gather_args.local_directory);
+    let Some(_lock): Option<BackupLock> = lock_backup(gather_args, control, run).await? else {
+        return Ok(Some(RunStats::default()));
+    };
+    control.set_state(GathererState::Scanning);
+    if paths.is_none() {
+        let env: Vec<(&'static str, String)> = run_env(run_id, None, &RunStats::default(), None);
+        match run_hook(HookKind::Pre, gather_args, &env, run).await {
+            Ok(()) => {}
+            Err(_) if run.is_cancelled() => return Ok(Some(RunStats::default())),
+            Err(e) if gather_args.pre_hook_optional => error!("{}, backing up anyway", e),
+            Err(e) => return Err(GatherError::PreHook(e)),
+        }
+    }
+    let mut history: HistoryStore =
+        HistoryStore::open(gather_args).map_err(GatherError::History)?;
+    let selection: Selection = Selection::new(gather_args);
+    let mut current_file_shas: ShaFile = ShaFile::with_algorithm(gather_args.hash_algorithm);
+
+    match paths {
+        Some(paths) => debug!(
+            "Scanning {} changed paths of {}",
+            paths.len(),
+            gather_args.local_directory
+        ),
+        None => info!("Ready for backup...of {}", gather_args.local_directory),
+    }
+
+    // Hashing blocks, move this worker's other tasks, such as control requests, elsewhere
+    block_in_place(|| {
+        let recorded = |keys: Vec<&String>| match history.load_keys(keys) {
+            Ok(recorded) => recorded.files,
+            Err(e) => {
+                error!("{}", e);
+                HashMap::new()
+            }
+        };
+        generate_shas(
+            root,
+            &mut current_file_shas,
+            &selection,
+            paths,
+            recorded,
+            run,
+        )
+    });
+
+    let mut old_file_shas: ShaFile = history
+        .load_keys(current_file_shas.files.keys())
+        .map_err(GatherError::History)?;
+    if old_file_shas.algorithm != gather_args.hash_algorithm {
+        info!(
+            "History was hashed with {}, re-baselining to {}",
+            old_file_shas.algorithm, gather_args.hash_algorithm
+        );
+        // Every recorded file is carried over, not only the ones scanned in this run
+        let full: ShaFile = history.load().map_err(GatherError::History)?;
+        old_file_shas = block_in_place(|| rebaseline_shas(full, root, gather_args.hash_algorithm));
+    }
+
+    let mut stats: RunStats = RunStats {
+        scanned: current_file_shas.files.len(),
+        ..Default::default()
+    };
+    let mut sha_diff: ShaFile = get_sha_diff(&old_file_shas, current_file_shas);
+    if paths.is_some() {
+        if sha_diff.files.is_empty() {
+            return Ok(None);
+        }
+        sha_diff.timestamp = old_file_shas.timestamp;
+        info!(
+            "Backing up {} changed files of {}",
+            sha_diff.files.len(),
+            gather_args.local_directory
+        );
+    }
+    stats.changed = sha_diff.files.len();
+    if run.is_cancelled() {
+        info!("Run cancelled before uploading");
+        return Ok(Some(stats));
+    }
+    control.set_state(GathererState::Uploading);
+    let snapshot: String = snapshot_name();
+    let uploads: Uploads = backup(&mut sha_diff, &snapshot, gather_args, aws_config, run)
+        .await
+        .map_err(|e| GatherError::Upload(e.to_string()))?;
+    stats.uploaded = uploads.files.len();
+    stats.bytes = uploads.bytes;
+    stats.upload_errors = uploads.errors;
+    stats.snapshot = Some(snapshot.clone());
+    stats.failures = uploads.failures;
+
+    let merged_shas: ShaFile = merge_diff_old(old_file_shas, &sha_diff);
+    let entries: usize = history
+        .save(&merged_shas, &sha_diff, &snapshot)
+        .map_err(GatherError::History)?;
+    stats.history_entries = Some(entries);
 
     if gather_args.cleanable {
-        cleanup_deletable(&uploaded_files).await;
+        cleanup_deletable(&uploads.removable).await;
+    }
+    Ok(Some(stats))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use sandman_share::config::HistoryBackend;
+    use tempfile::TempDir;
+
+    /// Returns `GatherArgs` of an hourly backup of `directory`, keeping its history there too.
+    fn gather_args(directory: &TempDir) -> GatherArgs {
+        GatherArgs {
+            history_backend: HistoryBackend::Json,
+            history_path: Some(directory.path().join("history.json")),
+            ..GatherArgs::new(
+                "Documents".to_string(),
+                directory.path().to_string_lossy().to_string(),
+                "bucket".to_string(),
+                "documents".to_string(),
+                3600,
+                0,
+                false,
+            )
+        }
+    }
+
+    fn paths(paths: &[&str]) -> Vec<PathBuf> {
+        paths.iter().map(PathBuf::from).collect()
+    }
+
+    #[test]
+    fn nested_paths_are_dropped() {
+        assert_eq!(
+            outermost_paths(paths(&["/data/a/b/c", "/data/a", "/data/a/b"])),
+            paths(&["/data/a"])
+        );
+    }
+
+    #[test]
+    fn duplicate_paths_are_kept_once() {
+        assert_eq!(
+            outermost_paths(paths(&["/data/b", "/data/a", "/data/b"])),
+            paths(&["/data/a", "/data/b"])
+        );
+    }
+
+    #[test]
+    fn sibling_prefixes_are_not_nested() {
+        assert_eq!(
+            outermost_paths(paths(&["/data/a/x", "/data/ab", "/data/a b", "/data/a"])),
+            paths(&["/data/a", "/data/a b", "/data/ab"])
+        );
+    }
+
+    #[test]
+    fn no_paths_stay_empty() {
+        assert!(outermost_paths(vec![]).is_empty());
+    }
+
+    #[test]
+    fn cancelled_runs_wait_for_their_next_slot() {
+        let directory: TempDir = TempDir::new().unwrap();
+        let gather_args: GatherArgs = gather_args(&directory);
+        let schedule: Schedule = Schedule::new(&gather_args).unwrap();
+        let control: Arc<GathererControl> = GathererControl::new(gather_args.name.clone());
+        let mut history: ShaFile = ShaFile::with_algorithm(gather_args.hash_algorithm);
+        history.timestamp = now() - 7200;
+        HistoryStore::open(&gather_args)
+            .unwrap()
+            .save(&history, &history, "2026-10-19--00-00-00")
+            .unwrap();
+        assert!(
+            schedule
+                .next_run(last_run_time(&gather_args, &control))
+                .unwrap()
+                <= Utc::now()
+        );
+
+        // A cancelled run finishes without writing the history
+        let finished: u64 = now();
+        control.record_attempt(finished);
+        assert_eq!(
+            schedule.next_run(last_run_time(&gather_args, &control)),
+            Utc.timestamp_opt(finished as i64 + 3600, 0).single()
+        );
+    }
+
+    #[test]
+    fn failed_runs_wait_for_their_next_slot() {
+        let directory: TempDir = TempDir::new().unwrap();
+        let gather_args: GatherArgs = GatherArgs {
+            schedule: Some("hourly".to_string()),
+            time_zone: Some("UTC".to_string()),
+            ..gather_args(&directory)
+        };
+        let schedule: Schedule = Schedule::new(&gather_args).unwrap();
+        let control: Arc<GathererControl> = GathererControl::new(gather_args.name.clone());
+        let mut history: ShaFile = ShaFile::with_algorithm(gather_args.hash_algorithm);
+        history.timestamp = now() - 86400;
+        HistoryStore::open(&gather_args)
+            .unwrap()
+            .save(&history, &history, "2026-10-18--00-00-00")
+            .unwrap();
+
+        // A run whose pre-hook failed finishes without writing the history
+        let finished: u64 = now();
+        control.record_attempt(finished);
+        assert_eq!(
+            schedule.next_run(last_run_time(&gather_args, &control)),
+            Utc.timestamp_opt((finished / 3600 + 1) as i64 * 3600, 0)
+                .single()
+        );
+    }
+
+    #[tokio::test]
+    async fn waiting_for_the_lock_ends_when_cancelled() {
+        let directory: TempDir = TempDir::new().unwrap();
+        let gather_args: GatherArgs = gather_args(&directory);
+        let control: Arc<GathererControl> = GathererControl::new(gather_args.name.clone());
+        let path: PathBuf = BackupLock::path_for(&HistoryStore::location(&gather_args));
+        let _held: BackupLock = BackupLock::acquire(&path, false).await.unwrap().unwrap();
+
+        let run: CancellationToken = CancellationToken::new();
+        let cancel = async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            assert_eq!(control.status().state, GathererState::Waiting);
+            run.cancel();
+        };
+        let (locked, ()) = tokio::join!(lock_backup(&gather_args, &control, &run), cancel);
+
+        assert!(locked.unwrap().is_none());
     }
 }
@@ -0,0 +1,359 @@
+use crate::clock::now;
+use crate::keys::escape_legacy_key;
+use crate::sha::ShaFile;
+use serde::Deserialize;
+use serde_json::Value;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use tracing::{error, info, warn};
+
+/// Current version of the on-disk history format.
+pub(crate) const HISTORY_VERSION: u64 = 3;
+
+/// Envelope wrapping a `ShaFile` on disk, alongside the `version` field of the format.
+#[derive(Deserialize, Debug)]
+struct HistoryEnvelope {
+    history: ShaFile,
+}
+
+/// A single upgrade step, taking a history of version `n` to version `n + 1`. Steps receive the
+/// root of the backup the history belongs to.
+type Migration = fn(Value, &Path) -> Result<Value, String>;
+
+/// Ordered upgrade steps, `MIGRATIONS[n]` upgrades a version `n` history to version `n + 1`.
+const MIGRATIONS: [Migration; HISTORY_VERSION as usize] =
+    [migrate_v0_to_v1, migrate_v1_to_v2, migrate_v2_to_v3];
+
+/// Version 0 histories were a bare serialization of `ShaFile`, wrap them in an envelope.
+fn migrate_v0_to_v1(value: Value, _root: &Path) -> Result<Value, String> {
+    if !value.is_object() {
+        return Err("Expected a JSON object".to_string());
+    }
+    Ok(serde_json::json!({ "version": 1, "history": value }))
+}
+
+/// Version 1 histories recorded the full local path of every file, make them relative to the root.
+fn migrate_v1_to_v2(mut value: Value, root: &Path) -> Result<Value, String> {
+    let files = value
+        .pointer_mut("/history/files")
+        .and_then(Value::as_object_mut)
+        .ok_or_else(|| "Expected a map of files".to_string())?;
+
+    let migrated: serde_json::Map<String, Value> = std::mem::take(files)
+        .into_iter()
+        .map(|(path, hash)| (relative_legacy_path(&path, root), hash))
+        .collect();
+    *files = migrated;
+    value["version"] = Value::from(2);
+    Ok(value)
+}
+
+/// Version 2 histories recorded paths verbatim, escape them as keys produced by `encode_path`.
+fn migrate_v2_to_v3(mut value: Value, _root: &Path) -> Result<Value, String> {
+    let files = value
+        .pointer_mut("/history/files")
+        .and_then(Value::as_object_mut)
+        .ok_or_else(|| "Expected a map of files".to_string())?;
+
+    let migrated: serde_json::Map<String, Value> = std::mem::take(files)
+        .into_iter()
+        .map(|(path, hash)| (escape_legacy_key(&path), hash))
+        .collect();
+    *files = migrated;
+    value["version"] = Value::from(3);
+    Ok(value)
+}
+
+/// Converts a path recorded by an older version, formed by joining the root of the backup and the
+/// file's path with `/` separators, into a path relative to `root`. Paths outside of `root` are
+/// returned unchanged.
+///
+/// # Arguments
+///
+/// * `path` - The path as it was recorded.
+/// * `root` - The root of the backup.
+pub(crate) fn relative_legacy_path(path: &str, root: &Path) -> String {
+    let root: String = root.to_string_lossy().replace('\\', "/");
+    let prefix: String = format!("{}/", root.trim_end_matches('/'));
+    match path.strip_prefix(&prefix) {
+        Some(relative) => relative.to_string(),
+        None => path.to_string(),
+    }
+}
+
+/// Determines the format version of a raw history document. Documents without a `version`
+/// field predate the envelope and are treated as version 0.
+fn history_version(value: &Value) -> Result<u64, String> {
+    match value.get("version") {
+        None => Ok(0),
+        Some(version) => version
+            .as_u64()
+            .ok_or_else(|| format!("Invalid history version: {}", version)),
+    }
+}
+
+/// Upgrades a raw history document to the current version and deserializes it.
+///
+/// # Arguments
+///
+/// * `json` - The contents of the history file.
+/// * `root` - The root of the backup the history belongs to.
+///
+/// # Returns
+///
+/// The upgraded `ShaFile`, or a description of why the document could not be read.
+fn parse_history(json: &str, root: &Path) -> Result<ShaFile, String> {
+    let mut value: Value = serde_json::from_str(json).map_err(|e| e.to_string())?;
+    let mut version: u64 = history_version(&value)?;
+
+    if version > HISTORY_VERSION {
+        return Err(format!(
+            "History version {} is newer than the supported version {}",
+            version, HISTORY_VERSION
+        ));
+    }
+
+    while version < HISTORY_VERSION {
+        value = MIGRATIONS[version as usize](value, root)?;
+        version = history_version(&value)?;
+        info!("Migrated history to version {}", version);
+    }
+
+    let envelope: HistoryEnvelope = serde_json::from_value(value).map_err(|e| e.to_string())?;
+    Ok(envelope.history)
+}
+
+/// Moves an unreadable history file aside so it can be inspected later, allowing a fresh history
+/// to be rebuilt in its place.
+///
+/// # Arguments
+///
+/// * `sha_location` - The path of the unreadable history file.
+pub(crate) fn quarantine(sha_location: &Path) {
+    let timestamp: u64 = now();
+    let mut quarantined = sha_location.as_os_str().to_owned();
+    quarantined.push(format!(".corrupt-{}", timestamp));
+    let quarantined: PathBuf = PathBuf::from(quarantined);
+
+    match fs::rename(sha_location, &quarantined) {
+        Ok(_) => warn!("Quarantined unreadable history to {:?}", quarantined),
+        Err(e) => error!("Unable to quarantine history {:?}: {}", sha_location, e),
+    }
+}
+
+/// Retrieves the prior SHA file information from the given location, upgrading older history
+/// formats as required. A history which cannot be read is quarantined and an empty history is
+/// returned in its place.
+///
+/// # Arguments
+///
+/// * `sha_location` - The file path to the SHA file.
+/// * `root` - The root of the backup the history belongs to.
+///
+/// # Returns
+///
+/// A `ShaFile` instance with the previously stored SHA information.
+pub(crate) fn get_prior_shas(sha_location: &Path, root: &Path) -> ShaFile {
+    match fs::read_to_string(sha_location) {
+        Ok(json) => parse_history(&json, root).unwrap_or_else(|e| {
+            error!(
+                "Unable to read history {:?}: {}. Rebuilding.",
+                sha_location, e
+            );
+            quarantine(sha_location);
+            ShaFile::new()
+        }),
+        Err(e) => {
+            error!("Unable to find ShaFile: {}. Defaulting to empty.", e);
+            ShaFile::new()
+        }
+    }
+}
+
+/// Reads the history at `sha_location`, upgrading older history formats in memory only. Unlike
+/// `get_prior_shas` a history which cannot be read is left in place.
+///
+/// # Arguments
+///
+/// * `sha_location` - The file path to the SHA file.
+/// * `root` - The root of the backup the history belongs to.
+///
+/// # Returns
+///
+/// The `ShaFile`, or a description of why the history could not be read.
+pub(crate) fn read_history(sha_location: &Path, root: &Path) -> Result<ShaFile, String> {
+    fs::read_to_string(sha_location)
+        .map_err(|e| e.to_string())
+        .and_then(|json| parse_history(&json, root))
+        .map_err(|e| format!("Unable to read history {:?}: {}", sha_location, e))
+}
+
+/// Atomically replaces the file at `path` with `contents`. The contents are written to a temporary
+/// file beside it, flushed to disk and renamed over the original, so a crash leaves either the old
+/// or the new file in place but never a truncated one.
+///
+/// # Arguments
+///
+/// * `path` - The file to replace.
+/// * `contents` - The new contents of the file.
+pub(crate) fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(format!(".tmp-{}", std::process::id()));
+    let temporary: PathBuf = PathBuf::from(temporary);
+
+    let written: io::Result<()> = (|| {
+        let mut file: File = File::create(&temporary)?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+        fs::rename(&temporary, path)
+    })();
+    if written.is_err() {
+        let _ = fs::remove_file(&temporary);
+        return written;
+    }
+
+    // Persist the rename itself, directories cannot be opened for syncing on Windows
+    #[cfg(unix)]
+    if let Some(parent) = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        File::open(parent)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Writes the SHA file information to the specified output path in the current history format.
+///
+/// # Arguments
+///
+/// * `shas` - The `ShaFile` containing SHA information.
+/// * `output_path` - The file path to write the SHA file to.
+pub(crate) fn write_file_shas(shas: &ShaFile, output_path: &Path) -> io::Result<()> {
+    let envelope = serde_json::json!({ "version": HISTORY_VERSION, "history": shas });
+    let shas_json = serde_json::to_string_pretty(&envelope).expect("Failed to serialize ShaFile");
+    write_atomic(output_path, shas_json.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sandman_share::config::HashAlgorithm;
+    use std::collections::HashMap;
+    use tempfile::TempDir;
+
+    fn files(entries: &[(&str, &str)]) -> HashMap<String, String> {
+        entries
+            .iter()
+            .map(|(key, hash)| (key.to_string(), hash.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn bare_histories_are_upgraded() {
+        let json: &str =
+            r#"{"files": {"/data/docs/a.txt": "aa", "/data/100%.txt": "bb"}, "timestamp": 7}"#;
+
+        let history: ShaFile = parse_history(json, Path::new("/data")).unwrap();
+
+        assert_eq!(history.timestamp, 7);
+        assert_eq!(history.algorithm, HashAlgorithm::Sha256);
+        assert_eq!(
+            history.files,
+            files(&[("docs/a.txt", "aa"), ("100%25.txt", "bb")])
+        );
+    }
+
+    #[test]
+    fn version_1_histories_are_made_relative_and_escaped() {
+        let json: &str = r#"{"version": 1, "history": {
+            "files": {"/data/a%b.txt": "aa", "/elsewhere/c.txt": "cc"},
+            "timestamp": 7,
+            "algorithm": "blake3"
+        }}"#;
+
+        let history: ShaFile = parse_history(json, Path::new("/data/")).unwrap();
+
+        assert_eq!(history.algorithm, HashAlgorithm::Blake3);
+        assert_eq!(
+            history.files,
+            files(&[("a%25b.txt", "aa"), ("/elsewhere/c.txt", "cc")])
+        );
+    }
+
+    #[test]
+    fn version_2_histories_are_escaped() {
+        let json: &str = r#"{"version": 2, "history": {"files": {"50%/x": "aa"}, "timestamp": 7}}"#;
+
+        let history: ShaFile = parse_history(json, Path::new("/data")).unwrap();
+
+        assert_eq!(history.files, files(&[("50%25/x", "aa")]));
+    }
+
+    #[test]
+    fn current_histories_are_read_unchanged() {
+        let json: &str =
+            r#"{"version": 3, "history": {"files": {"50%25/x": "aa"}, "timestamp": 7}}"#;
+
+        let history: ShaFile = parse_history(json, Path::new("/data")).unwrap();
+
+        assert_eq!(history.files, files(&[("50%25/x", "aa")]));
+    }
+
+    #[test]
+    fn newer_and_invalid_histories_are_rejected() {
+        let newer: &str = r#"{"version": 4, "history": {"files": {}, "timestamp": 7}}"#;
+
+        assert!(parse_history(newer, Path::new("/data")).is_err());
+        assert!(parse_history(r#"{"version": "3"}"#, Path::new("/data")).is_err());
+        assert!(parse_history("[]", Path::new("/data")).is_err());
+        assert!(parse_history("{", Path::new("/data")).is_err());
+    }
+
+    #[test]
+    fn written_histories_are_read_back() {
+        let directory: TempDir = TempDir::new().unwrap();
+        let path: PathBuf = directory.path().join(".sandman_history");
+        let mut shas: ShaFile = ShaFile::with_algorithm(HashAlgorithm::Xxh3);
+        shas.files = files(&[("a.txt", "aa"), ("sub/b.txt", "bb")]);
+
+        write_file_shas(&shas, &path).unwrap();
+
+        assert_eq!(get_prior_shas(&path, directory.path()), shas);
+        assert_eq!(read_history(&path, directory.path()).unwrap(), shas);
+        assert_eq!(fs::read_dir(directory.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn unreadable_histories_are_quarantined() {
+        let directory: TempDir = TempDir::new().unwrap();
+        let path: PathBuf = directory.path().join(".sandman_history");
+        fs::write(&path, "not a history").unwrap();
+
+        let history: ShaFile = get_prior_shas(&path, directory.path());
+
+        assert!(history.files.is_empty());
+        assert!(!path.exists());
+        let quarantined: Vec<String> = fs::read_dir(directory.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(quarantined.len(), 1);
+        assert!(quarantined[0].starts_with(".sandman_history.corrupt-"));
+        assert_eq!(
+            fs::read_to_string(directory.path().join(&quarantined[0])).unwrap(),
+            "not a history"
+        );
+    }
+
+    #[test]
+    fn reading_leaves_unreadable_histories_in_place() {
+        let directory: TempDir = TempDir::new().unwrap();
+        let path: PathBuf = directory.path().join(".sandman_history");
+        fs::write(&path, "not a history").unwrap();
+
+        assert!(read_history(&path, directory.path()).is_err());
+        assert!(path.exists());
+    }
+}
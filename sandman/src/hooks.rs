@@ -0,0 +1,161 @@
+use crate::args::GatherArgs;
+use crate::control::{RunOutcome, RunStats};
+use std::fmt;
+use std::process::{Output, Stdio};
+use std::time::Duration;
+use tokio::process::{Child, Command};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info};
+
+/// Seconds a hook may run before it is killed, unless configured.
+const DEFAULT_HOOK_TIMEOUT: u64 = 300;
+
+/// The points of a run at which a configured command is run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum HookKind {
+    /// Before the directory is scanned, aborting the run when it fails unless optional.
+    Pre,
+
+    /// After the run, whatever its outcome.
+    Post,
+
+    /// After the run, only when it failed.
+    OnFailure,
+}
+
+impl fmt::Display for HookKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HookKind::Pre => write!(f, "pre_hook"),
+            HookKind::Post => write!(f, "post_hook"),
+            HookKind::OnFailure => write!(f, "on_failure"),
+        }
+    }
+}
+
+impl HookKind {
+    /// Returns the command configured for this hook of a backup, if any.
+    fn command(self, gather_args: &GatherArgs) -> Option<&String> {
+        match self {
+            HookKind::Pre => gather_args.pre_hook.as_ref(),
+            HookKind::Post => gather_args.post_hook.as_ref(),
+            HookKind::OnFailure => gather_args.on_failure.as_ref(),
+        }
+    }
+}
+
+/// Describes a run to its hooks as environment variables.
+///
+/// # Arguments
+///
+/// * `run_id` - Identifies the run in the log.
+/// * `outcome` - How the run ended, `None` before it started.
+/// * `stats` - What the run found and did.
+/// * `error` - Why the run failed, if it did.
+pub(crate) fn run_env(
+    run_id: &str,
+    outcome: Option<RunOutcome>,
+    stats: &RunStats,
+    error: Option<&str>,
+) -> Vec<(&'static str, String)> {
+    let mut env: Vec<(&'static str, String)> = vec![("SANDMAN_RUN_ID", run_id.to_string())];
+    if let Some(outcome) = outcome {
+        env.extend([
+            ("SANDMAN_STATUS", outcome.to_string()),
+            (
+                "SANDMAN_SNAPSHOT",
+                stats.snapshot.clone().unwrap_or_default(),
+            ),
+            ("SANDMAN_FILES_SCANNED", stats.scanned.to_string()),
+            ("SANDMAN_FILES_CHANGED", stats.changed.to_string()),
+            ("SANDMAN_FILES_UPLOADED", stats.uploaded.to_string()),
+            ("SANDMAN_BYTES_UPLOADED", stats.bytes.to_string()),
+            ("SANDMAN_ERROR", error.unwrap_or_default().to_string()),
+        ]);
+    }
+    env
+}
+
+/// Runs a hook of a backup through the shell, if the backup configures one. The hook inherits
+/// Sandman's environment along with `SANDMAN_HOOK`, `SANDMAN_BACKUP`, `SANDMAN_DIRECTORY` and
+/// `env`, and is killed once it outlives the backup's hook timeout or `stop` is cancelled. Its
+/// output is logged at debug level.
+///
+/// # Arguments
+///
+/// * `kind` - Which of the backup's hooks to run.
+/// * `gather_args` - `GatherArgs` of the backup.
+/// * `env` - Variables describing the run, from `run_env`.
+/// * `stop` - Token cancelled when the hook should be killed early.
+///
+/// # Returns
+///
+/// A description of why the hook failed, if it did.
+pub(crate) async fn run_hook(
+    kind: HookKind,
+    gather_args: &GatherArgs,
+    env: &[(&'static str, String)],
+    stop: &CancellationToken,
+) -> Result<(), String> {
+    let Some(command) = kind.command(gather_args) else {
+        return Ok(());
+    };
+    let timeout: Duration =
+        Duration::from_secs(gather_args.hook_timeout.unwrap_or(DEFAULT_HOOK_TIMEOUT));
+    info!(hook = %kind, "Running {}", command);
+    let child: Child = shell(command)
+        .env("SANDMAN_HOOK", kind.to_string())
+        .env("SANDMAN_BACKUP", &gather_args.name)
+        .env("SANDMAN_DIRECTORY", &gather_args.local_directory)
+        .envs(env.iter().map(|(key, value)| (*key, value)))
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| format!("Unable to run {} {:?}: {}", kind, command, e))?;
+
+    // Dropping the child on timeout or cancellation kills it
+    let output: Output = tokio::select! {
+        _ = stop.cancelled() => return Err(format!("{} was interrupted", kind)),
+        result = tokio::time::timeout(timeout, child.wait_with_output()) => match result {
+            Ok(Ok(output)) => output,
+            Ok(Err(e)) => return Err(format!("Error waiting for {}: {}", kind, e)),
+            Err(_) => return Err(format!("{} timed out after {:?}", kind, timeout)),
+        },
+    };
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    stdout
+        .lines()
+        .chain(stderr.lines())
+        .for_each(|line| debug!(hook = %kind, "{}", line));
+
+    if output.status.success() {
+        debug!(hook = %kind, "Hook finished");
+        return Ok(());
+    }
+    let status: String = match output.status.code() {
+        Some(code) => format!("exited with code {}", code),
+        None => "was killed by a signal".to_string(),
+    };
+    match stderr.lines().rev().find(|line| !line.trim().is_empty()) {
+        Some(line) => Err(format!("{} {}: {}", kind, status, line.trim())),
+        None => Err(format!("{} {}", kind, status)),
+    }
+}
+
+/// Builds the command running `command` through the platform's shell.
+#[cfg(unix)]
+fn shell(command: &str) -> Command {
+    let mut shell: Command = Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+#[cfg(not(unix))]
+fn shell(command: &str) -> Command {
+    let mut shell: Command = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
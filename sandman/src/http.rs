@@ -0,0 +1,201 @@
+use crate::clock::now;
+use crate::control::{BackupMetrics, GathererControl};
+use crate::ctl::Registry;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use sandman_share::config::HttpConfig;
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info};
+
+/// Seconds a run may be overdue before `/healthz` fails, unless configured.
+const DEFAULT_OVERDUE_GRACE: u64 = 300;
+
+/// Content type of the Prometheus text exposition format.
+const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Serves `/metrics` and `/healthz` for the gatherers of `registry` until `shutdown` is cancelled.
+///
+/// # Arguments
+///
+/// * `config` - `HttpConfig` holding the address to listen on.
+/// * `registry` - Controls of the running gatherers.
+/// * `shutdown` - Token cancelled when Sandman is shutting down.
+pub(crate) async fn serve_http(
+    config: HttpConfig,
+    registry: Registry,
+    shutdown: CancellationToken,
+) {
+    let address: SocketAddr = match config.listen.parse() {
+        Ok(address) => address,
+        Err(e) => {
+            error!("Invalid HTTP listen address {:?}: {}", config.listen, e);
+            return;
+        }
+    };
+    let grace: u64 = config.overdue_grace.unwrap_or(DEFAULT_OVERDUE_GRACE);
+    let make_service = make_service_fn(move |_| {
+        let registry: Registry = registry.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let controls: Vec<Arc<GathererControl>> = registry.lock().unwrap().clone();
+                async move { Ok::<_, Infallible>(respond(request, &controls, grace)) }
+            }))
+        }
+    });
+
+    let server = match Server::try_bind(&address) {
+        Ok(builder) => builder.serve(make_service),
+        Err(e) => {
+            error!("Unable to listen for HTTP on {}: {}", address, e);
+            return;
+        }
+    };
+    info!("Serving /metrics and /healthz on http://{}", address);
+    if let Err(e) = server
+        .with_graceful_shutdown(async move { shutdown.cancelled().await })
+        .await
+    {
+        error!("HTTP listener failed: {}", e);
+    }
+}
+
+/// Answers a request to the HTTP listener.
+fn respond(
+    request: Request<Body>,
+    controls: &[Arc<GathererControl>],
+    grace: u64,
+) -> Response<Body> {
+    if request.method() != Method::GET {
+        return plain(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "Method not allowed\n".to_string(),
+        );
+    }
+    match request.uri().path() {
+        "/metrics" => Response::builder()
+            .header("Content-Type", METRICS_CONTENT_TYPE)
+            .body(Body::from(render_metrics(controls)))
+            .expect("Unable to build metrics response"),
+        "/healthz" => {
+            let now: u64 = now();
+            let problems: Vec<String> = controls
+                .iter()
+                .filter_map(|control| control.health_problem(now, grace))
+                .collect();
+            match problems.is_empty() {
+                true => plain(StatusCode::OK, "ok\n".to_string()),
+                false => plain(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    format!("{}\n", problems.join("\n")),
+                ),
+            }
+        }
+        _ => plain(StatusCode::NOT_FOUND, "Not found\n".to_string()),
+    }
+}
+
+/// Creates a plain text response.
+fn plain(status: StatusCode, body: String) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "text/plain; charset=utf-8")
+        .body(Body::from(body))
+        .expect("Unable to build response")
+}
+
+/// A metric exported for every backup, as its name, type, help text and value.
+type MetricFamily = (
+    &'static str,
+    &'static str,
+    &'static str,
+    fn(&BackupMetrics) -> Option<f64>,
+);
+
+/// Renders the metrics of every gatherer in the Prometheus text exposition format, labelled by
+/// backup name.
+fn render_metrics(controls: &[Arc<GathererControl>]) -> String {
+    let metrics: Vec<(String, BackupMetrics)> = controls
+        .iter()
+        .map(|control| (escape_label(control.name()), control.metrics()))
+        .collect();
+    let families: [MetricFamily; 9] = [
+        (
+            "sandman_runs_total",
+            "counter",
+            "Backup runs finished.",
+            |m| Some(m.runs as f64),
+        ),
+        (
+            "sandman_failed_runs_total",
+            "counter",
+            "Backup runs which failed.",
+            |m| Some(m.failed_runs as f64),
+        ),
+        (
+            "sandman_files_scanned_total",
+            "counter",
+            "Files hashed by backup runs.",
+            |m| Some(m.files_scanned as f64),
+        ),
+        (
+            "sandman_files_changed_total",
+            "counter",
+            "Files found changed by backup runs.",
+            |m| Some(m.files_changed as f64),
+        ),
+        (
+            "sandman_bytes_uploaded_total",
+            "counter",
+            "Bytes uploaded by backup runs.",
+            |m| Some(m.bytes_uploaded as f64),
+        ),
+        (
+            "sandman_upload_errors_total",
+            "counter",
+            "Uploads which failed.",
+            |m| Some(m.upload_errors as f64),
+        ),
+        (
+            "sandman_run_duration_seconds",
+            "gauge",
+            "Duration of the last backup run.",
+            |m| m.last_duration,
+        ),
+        (
+            "sandman_last_success_timestamp_seconds",
+            "gauge",
+            "Time the last successful backup run finished.",
+            |m| m.last_success.map(|time| time as f64),
+        ),
+        (
+            "sandman_history_entries",
+            "gauge",
+            "Entries recorded by the backup's history.",
+            |m| m.history_entries.map(|entries| entries as f64),
+        ),
+    ];
+
+    let mut body: String = String::new();
+    for (name, kind, help, value) in families {
+        let _ = writeln!(body, "# HELP {} {}", name, help);
+        let _ = writeln!(body, "# TYPE {} {}", name, kind);
+        for (backup, metrics) in &metrics {
+            if let Some(value) = value(metrics) {
+                let _ = writeln!(body, "{}{{backup=\"{}\"}} {}", name, backup, value);
+            }
+        }
+    }
+    body
+}
+
+/// Escapes a label value of the Prometheus text exposition format.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -0,0 +1,219 @@
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::path::{Component, Path, PathBuf};
+
+/// Encodes a path relative to the backup root into the key recorded in histories and used for
+/// remote object names. Components are separated with `/`, and the path's raw bytes are kept
+/// losslessly: `%` is written as `%25` and bytes which are not valid UTF-8 are written as `%XX`.
+///
+/// # Arguments
+///
+/// * `relative` - A path relative to the backup root.
+///
+/// # Returns
+///
+/// The encoded key, which `decode_key` turns back into the original path.
+pub(crate) fn encode_path(relative: &Path) -> String {
+    let mut key: String = String::new();
+    for component in relative.components() {
+        if let Component::Normal(name) = component {
+            if !key.is_empty() {
+                key.push('/');
+            }
+            escape_into(name.as_encoded_bytes(), &mut key);
+        }
+    }
+    key
+}
+
+/// Appends the escaped form of a single path component to `key`.
+fn escape_into(bytes: &[u8], key: &mut String) {
+    for chunk in bytes.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '%' => key.push_str("%25"),
+                _ => key.push(c),
+            }
+        }
+        for byte in chunk.invalid() {
+            key.push_str(&format!("%{:02X}", byte));
+        }
+    }
+}
+
+/// Escapes `%` in a key recorded before keys were encoded, where every other character was
+/// already stored verbatim.
+pub(crate) fn escape_legacy_key(key: &str) -> String {
+    key.replace('%', "%25")
+}
+
+/// Key of the object metadata marking objects whose key was produced by `encode_path`. Objects
+/// uploaded before keys were encoded lack it, their keys holding file names verbatim.
+pub(crate) const ENCODED_METADATA: &str = "sandman-key";
+
+/// Value of `ENCODED_METADATA` on objects with encoded keys.
+const ENCODED: &str = "encoded";
+
+/// Adds the marker of encoded keys to the metadata of an object about to be uploaded.
+pub(crate) fn mark_encoded(metadata: Option<HashMap<String, String>>) -> HashMap<String, String> {
+    let mut metadata: HashMap<String, String> = metadata.unwrap_or_default();
+    metadata.insert(ENCODED_METADATA.to_string(), ENCODED.to_string());
+    metadata
+}
+
+/// Whether the key of an uploaded object reads differently depending on it being encoded, which
+/// is only the case for keys holding `%`.
+pub(crate) fn is_ambiguous(key: &str) -> bool {
+    key.contains('%')
+}
+
+/// Returns the key of an uploaded object as `encode_path` would have produced it.
+///
+/// # Arguments
+///
+/// * `key` - The key of the object, relative to its snapshot.
+/// * `metadata` - The metadata of the object.
+///
+/// # Returns
+///
+/// `key` when the object is marked as encoded, or the escaped key of an object uploaded before
+/// keys were encoded.
+pub(crate) fn stored_key(key: &str, metadata: Option<&HashMap<String, String>>) -> String {
+    match metadata.and_then(|metadata| metadata.get(ENCODED_METADATA)) {
+        Some(marker) if marker == ENCODED => key.to_string(),
+        _ => escape_legacy_key(key),
+    }
+}
+
+/// Decodes a key produced by `encode_path` back into the relative path it was created from.
+///
+/// # Arguments
+///
+/// * `key` - The encoded key.
+///
+/// # Returns
+///
+/// The relative path, byte for byte identical to the encoded one on Unix.
+pub(crate) fn decode_key(key: &str) -> PathBuf {
+    let raw: &[u8] = key.as_bytes();
+    let mut bytes: Vec<u8> = Vec::with_capacity(raw.len());
+    let mut index: usize = 0;
+
+    while index < raw.len() {
+        let escaped: Option<u8> = match raw[index] {
+            b'%' => raw
+                .get(index + 1..index + 3)
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+            _ => None,
+        };
+        match escaped {
+            Some(byte) => {
+                bytes.push(byte);
+                index += 3;
+            }
+            None => {
+                bytes.push(raw[index]);
+                index += 1;
+            }
+        }
+    }
+
+    path_from_bytes(bytes)
+}
+
+#[cfg(unix)]
+fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+    use std::os::unix::ffi::OsStrExt;
+    PathBuf::from(OsStr::from_bytes(&bytes))
+}
+
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+    PathBuf::from(OsStr::new(String::from_utf8_lossy(&bytes).as_ref()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_join_components_with_slashes() {
+        assert_eq!(
+            encode_path(Path::new("docs/2024/report.pdf")),
+            "docs/2024/report.pdf"
+        );
+        assert_eq!(
+            encode_path(Path::new("./docs//notes.txt")),
+            "docs/notes.txt"
+        );
+    }
+
+    #[test]
+    fn percent_signs_are_escaped() {
+        let path: &Path = Path::new("100% done/a%20b.txt");
+        let key: String = encode_path(path);
+
+        assert_eq!(key, "100%25 done/a%2520b.txt");
+        assert_eq!(decode_key(&key), path);
+    }
+
+    #[test]
+    fn unicode_is_kept_verbatim() {
+        let path: &Path = Path::new("Fotos/Größe 日本.jpg");
+
+        assert_eq!(encode_path(path), "Fotos/Größe 日本.jpg");
+        assert_eq!(decode_key(&encode_path(path)), path);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_names_round_trip() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let path: PathBuf = Path::new("dir").join(OsStr::from_bytes(b"caf\xe9 %\xff.txt"));
+        let key: String = encode_path(&path);
+
+        assert_eq!(key, "dir/caf%E9 %25%FF.txt");
+        assert_eq!(
+            decode_key(&key).as_os_str().as_bytes(),
+            path.as_os_str().as_bytes()
+        );
+    }
+
+    #[test]
+    fn incomplete_escapes_are_kept_literally() {
+        assert_eq!(decode_key("50%"), Path::new("50%"));
+        assert_eq!(decode_key("a%zzb"), Path::new("a%zzb"));
+    }
+
+    #[test]
+    fn legacy_keys_only_escape_percent_signs() {
+        let legacy: &str = "100% done/a%20b.txt";
+
+        assert_eq!(escape_legacy_key(legacy), "100%25 done/a%2520b.txt");
+        assert_eq!(decode_key(&escape_legacy_key(legacy)), Path::new(legacy));
+    }
+
+    #[test]
+    fn keys_of_objects_uploaded_before_encoding_are_read_verbatim() {
+        let legacy: &str = "50%41 off/a%20b.txt";
+        let key: String = stored_key(legacy, None);
+
+        assert_eq!(key, "50%2541 off/a%2520b.txt");
+        assert_eq!(decode_key(&key), Path::new(legacy));
+    }
+
+    #[test]
+    fn keys_of_marked_objects_are_decoded() {
+        let metadata: HashMap<String, String> = mark_encoded(None);
+
+        assert_eq!(stored_key("100%25 done", Some(&metadata)), "100%25 done");
+        assert_eq!(
+            decode_key(&stored_key("100%25 done", Some(&metadata))),
+            Path::new("100% done")
+        );
+        assert!(is_ambiguous("100%25 done"));
+        assert!(!is_ambiguous("report.pdf"));
+    }
+}
@@ -0,0 +1,50 @@
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// How often a held lock is retried while waiting for it, polling keeps the wait cancellable.
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Advisory lock ensuring only one Sandman process gathers a backup at a time. The lock is held
+/// until the value is dropped.
+pub(crate) struct BackupLock {
+    _file: File,
+}
+
+impl BackupLock {
+    /// Returns the path of the lock file guarding the history at `history_path`.
+    pub(crate) fn path_for(history_path: &Path) -> PathBuf {
+        let mut path = history_path.as_os_str().to_owned();
+        path.push(".lock");
+        PathBuf::from(path)
+    }
+
+    /// Acquires the lock at `path`.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Location of the lock file, created if it does not exist.
+    /// * `wait` - Whether to wait for another process to release the lock.
+    ///
+    /// # Returns
+    ///
+    /// The held lock, `None` if `wait` is false and another process holds it, or an error if the
+    /// lock file could not be opened.
+    pub(crate) async fn acquire(path: &Path, wait: bool) -> io::Result<Option<Self>> {
+        let file: File = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)?;
+
+        loop {
+            match file.try_lock() {
+                Ok(_) => return Ok(Some(BackupLock { _file: file })),
+                Err(TryLockError::WouldBlock) if !wait => return Ok(None),
+                Err(TryLockError::WouldBlock) => tokio::time::sleep(LOCK_POLL_INTERVAL).await,
+                Err(TryLockError::Error(e)) => return Err(e),
+            }
+        }
+    }
+}
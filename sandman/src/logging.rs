@@ -0,0 +1,243 @@
+use sandman_share::consts::SANDMAN_LOG;
+use sandman_share::paths::backup_state_dir;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, IsTerminal, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id};
+use tracing::Subscriber;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::field::RecordFields;
+use tracing_subscriber::fmt::format::{DefaultFields, Writer};
+use tracing_subscriber::fmt::{FormatFields, MakeWriter};
+use tracing_subscriber::layer::{Context, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer, Registry};
+
+/// Number of daily log files kept per backup.
+const LOG_FILE_COUNT: usize = 5;
+
+/// Field carrying the name of the backup an event belongs to, routing it to that backup's log file.
+const BACKUP_FIELD: &str = "backup";
+
+/// Format of the lines written to the log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum LogFormat {
+    /// Human readable lines, with fields appended as `key=value`.
+    #[default]
+    Text,
+
+    /// One JSON object per line.
+    Json,
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogFormat::Text => write!(f, "text"),
+            LogFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("Unknown log format: {}", s)),
+        }
+    }
+}
+
+/// Installs the `tracing` subscriber, which also receives the records of the `log` crate used by
+/// dependencies. Events are written to stderr as text or JSON, carrying the fields of the spans
+/// they happen in, such as the backup's name and the run's ID. The level defaults to `info`,
+/// `debug` with `verbosity`, and can be overridden per module through `RUST_LOG`.
+///
+/// # Arguments
+///
+/// * `verbosity` - Whether debug events are written.
+/// * `format` - The `LogFormat` of the written lines.
+/// * `log_files` - Whether events of a backup are also written to a daily log file in its state
+///   directory.
+pub(crate) fn init_logging(verbosity: bool, format: LogFormat, log_files: bool) {
+    let default_level: &str = if verbosity { "debug" } else { "info" };
+    let filter: EnvFilter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(default_level));
+    let files: Option<BackupFiles> = log_files.then(BackupFiles::default);
+    let subscriber = tracing_subscriber::registry()
+        .with(filter)
+        .with(BackupNames);
+
+    match format {
+        LogFormat::Text => subscriber
+            .with(
+                tracing_subscriber::fmt::layer()
+                    .with_ansi(io::stderr().is_terminal())
+                    .with_writer(io::stderr),
+            )
+            .with(files.map(|files| {
+                tracing_subscriber::fmt::layer()
+                    .with_ansi(false)
+                    .fmt_fields(FileFields::default())
+                    .with_writer(files)
+            }))
+            .init(),
+        LogFormat::Json => subscriber
+            .with(
+                tracing_subscriber::fmt::layer()
+                    .json()
+                    .flatten_event(true)
+                    .with_current_span(false)
+                    .with_writer(io::stderr),
+            )
+            .with(files.map(|files| {
+                tracing_subscriber::fmt::layer()
+                    .json()
+                    .flatten_event(true)
+                    .with_current_span(false)
+                    .with_writer(files)
+            }))
+            .init(),
+    }
+}
+
+/// Formats fields as `DefaultFields` does. Span fields are formatted once per field formatter type,
+/// so log files use their own to keep out the colours of a terminal.
+#[derive(Default)]
+struct FileFields(DefaultFields);
+
+impl<'writer> FormatFields<'writer> for FileFields {
+    fn format_fields<R: RecordFields>(&self, writer: Writer<'writer>, fields: R) -> fmt::Result {
+        self.0.format_fields(writer, fields)
+    }
+}
+
+/// Name of the backup a span belongs to, kept in the span's extensions.
+struct BackupName(String);
+
+/// Remembers the backup every span belongs to, given by its own `backup` field or inherited from
+/// its parent, so that events can be routed to that backup's log file.
+struct BackupNames;
+
+impl<S> Layer<S> for BackupNames
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attributes: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut visitor: BackupVisitor = BackupVisitor(None);
+        attributes.record(&mut visitor);
+        let backup: Option<String> = visitor.0.or_else(|| {
+            span.parent()
+                .and_then(|parent| parent.extensions().get::<BackupName>().map(|n| n.0.clone()))
+        });
+        if let Some(backup) = backup {
+            span.extensions_mut().insert(BackupName(backup));
+        }
+    }
+}
+
+/// Finds the `backup` field among the fields of a span.
+struct BackupVisitor(Option<String>);
+
+impl Visit for BackupVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == BACKUP_FIELD {
+            self.0 = Some(value.to_string());
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == BACKUP_FIELD {
+            self.0 = Some(format!("{:?}", value));
+        }
+    }
+}
+
+/// Returns the name of the backup the current span belongs to.
+fn current_backup() -> Option<String> {
+    tracing::dispatcher::get_default(|dispatch| {
+        let registry: &Registry = dispatch.downcast_ref::<Registry>()?;
+        let id: Id = dispatch.current_span().id()?.clone();
+        let span = registry.span(&id)?;
+        let backup: Option<String> = span.extensions().get::<BackupName>().map(|n| n.0.clone());
+        backup
+    })
+}
+
+/// The log file of a backup, shared by the writers of its events.
+type LogFile = Arc<Mutex<RollingFileAppender>>;
+
+/// Opens the log files of backups as they are first written to, rotated daily in each backup's
+/// state directory and keeping the last `LOG_FILE_COUNT` days.
+#[derive(Clone, Default)]
+struct BackupFiles {
+    /// Log files by backup name, `None` for backups whose log file could not be opened.
+    files: Arc<Mutex<HashMap<String, Option<LogFile>>>>,
+}
+
+impl BackupFiles {
+    fn open(backup: &str) -> Option<LogFile> {
+        let name: &Path = Path::new(SANDMAN_LOG);
+        let mut builder = RollingFileAppender::builder()
+            .rotation(Rotation::DAILY)
+            .max_log_files(LOG_FILE_COUNT);
+        if let Some(prefix) = name.file_stem() {
+            builder = builder.filename_prefix(prefix.to_string_lossy());
+        }
+        if let Some(suffix) = name.extension() {
+            builder = builder.filename_suffix(suffix.to_string_lossy());
+        }
+        match builder.build(backup_state_dir(backup)) {
+            Ok(appender) => Some(Arc::new(Mutex::new(appender))),
+            Err(e) => {
+                let _ = writeln!(io::stderr(), "Unable to open log file of {}: {}", backup, e);
+                None
+            }
+        }
+    }
+}
+
+impl<'a> MakeWriter<'a> for BackupFiles {
+    type Writer = BackupWriter;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        let Some(backup) = current_backup() else {
+            return BackupWriter(None);
+        };
+        let mut files = self.files.lock().unwrap();
+        let file = files
+            .entry(backup)
+            .or_insert_with_key(|backup| BackupFiles::open(backup));
+        BackupWriter(file.clone())
+    }
+}
+
+/// Writes an event to a backup's log file, or discards events outside of any backup.
+struct BackupWriter(Option<LogFile>);
+
+impl Write for BackupWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &self.0 {
+            Some(file) => file.lock().unwrap().write(buf),
+            None => Ok(buf.len()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &self.0 {
+            Some(file) => file.lock().unwrap().flush(),
+            None => Ok(()),
+        }
+    }
+}
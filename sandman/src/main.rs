@@ -1,10 +1,32 @@
 mod args;
 mod backup;
+mod clock;
+mod configuration;
+mod control;
+mod ctl;
+mod daemon;
+mod entry;
 mod gatherer;
+mod history;
+mod hooks;
+mod http;
+mod keys;
+mod lock;
+mod logging;
+mod notifications;
+mod preview;
+mod remote;
+mod restore;
+mod runs;
 mod sandman;
+mod schedule;
+mod selection;
 mod sha;
+mod store;
+
+use std::process::ExitCode;
 
 #[tokio::main]
-async fn main() {
-    let _ = sandman::run_sandman().await;
+async fn main() -> ExitCode {
+    sandman::run_sandman().await
 }
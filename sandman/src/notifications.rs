@@ -0,0 +1,472 @@
+use crate::args::GatherArgs;
+use crate::clock::now;
+use crate::control::{GathererControl, RunOutcome, RunReport};
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request, Response, Uri};
+use hyper_tls::HttpsConnector;
+use sandman_share::config::{WebhookConfig, WebhookFormat};
+use serde_json::{json, Value};
+use std::fmt;
+use std::time::Duration;
+use tracing::{debug, error};
+
+/// Time a webhook has to answer before the notification counts as failed.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What a notification reports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum NotificationEvent {
+    /// A run failed.
+    Failure,
+
+    /// A run succeeded.
+    Success,
+
+    /// The backup has gone too long without a successful run.
+    Stale,
+}
+
+impl fmt::Display for NotificationEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NotificationEvent::Failure => write!(f, "failure"),
+            NotificationEvent::Success => write!(f, "success"),
+            NotificationEvent::Stale => write!(f, "stale"),
+        }
+    }
+}
+
+/// A notification about a backup, and the run it is about.
+struct Notification<'a> {
+    event: NotificationEvent,
+    backup: &'a str,
+    /// The finished run, or the last run of a stale backup if it ran at all.
+    report: Option<&'a RunReport>,
+    /// Seconds since the last successful run, for stale notifications.
+    stale_for: Option<u64>,
+}
+
+impl Notification<'_> {
+    /// Describes the notification in a sentence, as posted to chats.
+    fn message(&self) -> String {
+        match self.event {
+            NotificationEvent::Failure => format!(
+                "Backup {} failed: {}",
+                self.backup,
+                self.report
+                    .and_then(|report| report.error.as_deref())
+                    .unwrap_or("unknown error")
+            ),
+            NotificationEvent::Success => {
+                let (uploaded, bytes, duration) = self
+                    .report
+                    .map(|report| (report.uploaded, report.bytes, report.duration))
+                    .unwrap_or_default();
+                format!(
+                    "Backup {} succeeded, uploaded {} files ({} bytes) in {:.1}s",
+                    self.backup, uploaded, bytes, duration
+                )
+            }
+            NotificationEvent::Stale => format!(
+                "Backup {} has not succeeded for {}, {}",
+                self.backup,
+                format_age(self.stale_for.unwrap_or_default()),
+                match self.report {
+                    Some(RunReport {
+                        error: Some(error), ..
+                    }) => format!("its last run failed: {}", error),
+                    Some(report) => format!("its last run was {}", report.outcome),
+                    None => "it has not run yet".to_string(),
+                }
+            ),
+        }
+    }
+
+    /// Builds the body posted to a webhook expecting `format`.
+    fn payload(&self, format: WebhookFormat) -> Value {
+        match format {
+            WebhookFormat::Json => json!({
+                "event": self.event.to_string(),
+                "backup": self.backup,
+                "message": self.message(),
+                "run_id": self.report.map(|report| &report.run_id),
+                "outcome": self.report.map(|report| report.outcome),
+                "finished": self.report.map(|report| report.finished),
+                "snapshot": self.report.and_then(|report| report.snapshot.as_ref()),
+                "files_scanned": self.report.map(|report| report.scanned),
+                "files_changed": self.report.map(|report| report.changed),
+                "files_uploaded": self.report.map(|report| report.uploaded),
+                "bytes_uploaded": self.report.map(|report| report.bytes),
+                "duration": self.report.map(|report| report.duration),
+                "error": self.report.and_then(|report| report.error.as_ref()),
+                "stale_for": self.stale_for,
+            }),
+            WebhookFormat::Slack => json!({ "text": self.message() }),
+            WebhookFormat::Discord => json!({ "content": self.message() }),
+        }
+    }
+}
+
+/// Tells the webhooks of a backup about a finished run, as their rules ask: failed and
+/// successful runs when `on_failure` and `on_success` are set. Webhooks which cannot be reached
+/// are logged and otherwise ignored.
+///
+/// # Arguments
+///
+/// * `gather_args` - `GatherArgs` of the backup, holding its webhooks.
+/// * `report` - The `RunReport` of the run.
+pub(crate) async fn notify_run(gather_args: &GatherArgs, report: &RunReport) {
+    if gather_args.webhooks.is_empty() {
+        return;
+    }
+    let client: Client<HttpsConnector<HttpConnector>> =
+        Client::builder().build(HttpsConnector::new());
+    for webhook in &gather_args.webhooks {
+        let event: NotificationEvent = match report.outcome {
+            RunOutcome::Failed if webhook.on_failure => NotificationEvent::Failure,
+            RunOutcome::Success if webhook.on_success => NotificationEvent::Success,
+            _ => continue,
+        };
+        let notification: Notification = Notification {
+            event,
+            backup: &gather_args.name,
+            report: Some(report),
+            stale_for: None,
+        };
+        send(&client, webhook, &notification).await;
+    }
+}
+
+/// Tells the webhooks of a backup with `stale_after` set that it has gone that many seconds
+/// without a successful run, once until it succeeds again. Called periodically by the daemon, so
+/// that a backup which stopped running at all is reported too.
+///
+/// # Arguments
+///
+/// * `gather_args` - `GatherArgs` of the backup, holding its webhooks.
+/// * `control` - The gatherer's `GathererControl`, tracking when the backup last succeeded.
+pub(crate) async fn notify_stale(gather_args: &GatherArgs, control: &GathererControl) {
+    let mut client: Option<Client<HttpsConnector<HttpConnector>>> = None;
+    for (index, webhook) in gather_args.webhooks.iter().enumerate() {
+        let Some(stale_for) = webhook
+            .stale_after
+            .and_then(|stale_after| control.newly_stale(now(), stale_after, index))
+        else {
+            continue;
+        };
+        let last_run: Option<RunReport> = control.status().last_run;
+        let notification: Notification = Notification {
+            event: NotificationEvent::Stale,
+            backup: &gather_args.name,
+            report: last_run.as_ref(),
+            stale_for: Some(stale_for),
+        };
+        let client = client.get_or_insert_with(|| Client::builder().build(HttpsConnector::new()));
+        send(client, webhook, &notification).await;
+    }
+}
+
+/// Posts a notification to a webhook, logging whether it was delivered.
+async fn send(
+    client: &Client<HttpsConnector<HttpConnector>>,
+    webhook: &WebhookConfig,
+    notification: &Notification<'_>,
+) {
+    let host: String = webhook_host(&webhook.url);
+    let event: NotificationEvent = notification.event;
+    match post(client, webhook, &notification.payload(webhook.format)).await {
+        Ok(()) => debug!(webhook = %host, event = %event, "Notified webhook"),
+        Err(e) => error!(webhook = %host, event = %event, "{}", e),
+    }
+}
+
+/// Posts `payload` to a webhook as JSON.
+///
+/// # Returns
+///
+/// A description of why the webhook could not be notified, if it could not.
+async fn post(
+    client: &Client<HttpsConnector<HttpConnector>>,
+    webhook: &WebhookConfig,
+    payload: &Value,
+) -> Result<(), String> {
+    let request: Request<Body> = Request::builder()
+        .method(Method::POST)
+        .uri(&webhook.url)
+        .header("Content-Type", "application/json")
+        .header("User-Agent", concat!("sandman/", env!("CARGO_PKG_VERSION")))
+        .body(Body::from(payload.to_string()))
+        .map_err(|e| format!("Invalid webhook URL: {}", e))?;
+    let response: Response<Body> = tokio::time::timeout(WEBHOOK_TIMEOUT, client.request(request))
+        .await
+        .map_err(|_| format!("Webhook did not answer within {:?}", WEBHOOK_TIMEOUT))?
+        .map_err(|e| format!("Unable to notify webhook: {}", e))?;
+    match response.status().is_success() {
+        true => Ok(()),
+        false => Err(format!("Webhook answered {}", response.status())),
+    }
+}
+
+/// Returns the host of a webhook URL for the log, leaving out the path which often holds the
+/// webhook's secret token.
+fn webhook_host(url: &str) -> String {
+    url.parse::<Uri>()
+        .ok()
+        .and_then(|uri| uri.host().map(str::to_string))
+        .unwrap_or_default()
+}
+
+/// Formats a number of seconds in days, hours and minutes, such as `1d 2h`.
+fn format_age(seconds: u64) -> String {
+    let (days, hours, minutes) = (seconds / 86400, seconds % 86400 / 3600, seconds % 3600 / 60);
+    match (days, hours) {
+        (0, 0) => format!("{}m", minutes),
+        (0, _) => format!("{}h {}m", hours, minutes),
+        _ => format!("{}d {}h", days, hours),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::body::to_bytes;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::Server;
+    use sandman_share::config::{NotificationsConfig, SandmanDirectory};
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+    /// Starts an HTTP listener on a free local port, passing on the path and JSON body of every
+    /// request it answers.
+    fn listen() -> (SocketAddr, UnboundedReceiver<(String, Value)>) {
+        let (sender, receiver): (UnboundedSender<(String, Value)>, _) = unbounded_channel();
+        let make_service = make_service_fn(move |_| {
+            let sender: UnboundedSender<(String, Value)> = sender.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let sender: UnboundedSender<(String, Value)> = sender.clone();
+                    async move {
+                        let path: String = request.uri().path().to_string();
+                        let body = to_bytes(request.into_body()).await.unwrap();
+                        let _ = sender.send((path, serde_json::from_slice(&body).unwrap()));
+                        Ok::<_, Infallible>(Response::new(Body::empty()))
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let address: SocketAddr = server.local_addr();
+        tokio::spawn(server);
+        (address, receiver)
+    }
+
+    /// Returns `GatherArgs` of a backup named Documents with the given webhooks.
+    fn gather_args(webhooks: &str) -> GatherArgs {
+        let directory: SandmanDirectory = toml::from_str(
+            r#"
+            name = "Documents"
+            directory = "/tmp/documents"
+            interval = 3600
+            start_time = 0
+            prefix = "documents"
+            bucket = "bucket"
+            cleanable = false
+            "#,
+        )
+        .unwrap();
+        let notifications: NotificationsConfig = toml::from_str(webhooks).unwrap();
+        GatherArgs::configured(directory, &notifications)
+    }
+
+    fn report(outcome: RunOutcome, finished: u64, error: Option<&str>) -> RunReport {
+        RunReport {
+            run_id: "0b4f5809-a877-4ee5-babb-40887a2cdfd5".to_string(),
+            started: finished - 1,
+            finished,
+            duration: 0.5,
+            outcome,
+            scanned: 120,
+            changed: 2,
+            uploaded: 1,
+            failed: 1,
+            bytes: 2048,
+            snapshot: Some("2026-10-19--00-07-32".to_string()),
+            error: error.map(str::to_string),
+            errors: vec![],
+        }
+    }
+
+    /// Takes the requests received so far, sorted by path.
+    fn received(receiver: &mut UnboundedReceiver<(String, Value)>) -> Vec<(String, Value)> {
+        let mut requests: Vec<(String, Value)> = vec![];
+        while let Ok(request) = receiver.try_recv() {
+            requests.push(request);
+        }
+        requests.sort_by(|a, b| a.0.cmp(&b.0));
+        requests
+    }
+
+    #[tokio::test]
+    async fn failures_are_posted_in_each_format() {
+        let (address, mut receiver) = listen();
+        let gather_args: GatherArgs = gather_args(&format!(
+            r#"
+            [[webhooks]]
+            url = "http://{address}/json"
+            on_failure = true
+
+            [[webhooks]]
+            url = "http://{address}/slack"
+            format = "slack"
+            on_failure = true
+
+            [[webhooks]]
+            url = "http://{address}/discord"
+            format = "discord"
+            on_failure = true
+
+            [[webhooks]]
+            url = "http://{address}/success"
+            on_success = true
+            "#
+        ));
+        let report: RunReport = report(
+            RunOutcome::Failed,
+            1792368452,
+            Some("1 file could not be uploaded"),
+        );
+
+        notify_run(&gather_args, &report).await;
+
+        let message: &str = "Backup Documents failed: 1 file could not be uploaded";
+        assert_eq!(
+            received(&mut receiver),
+            vec![
+                ("/discord".to_string(), json!({ "content": message })),
+                (
+                    "/json".to_string(),
+                    json!({
+                        "event": "failure",
+                        "backup": "Documents",
+                        "message": message,
+                        "run_id": "0b4f5809-a877-4ee5-babb-40887a2cdfd5",
+                        "outcome": "failed",
+                        "finished": 1792368452,
+                        "snapshot": "2026-10-19--00-07-32",
+                        "files_scanned": 120,
+                        "files_changed": 2,
+                        "files_uploaded": 1,
+                        "bytes_uploaded": 2048,
+                        "duration": 0.5,
+                        "error": "1 file could not be uploaded",
+                        "stale_for": null,
+                    })
+                ),
+                ("/slack".to_string(), json!({ "text": message })),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn successes_are_posted_to_webhooks_asking_for_them() {
+        let (address, mut receiver) = listen();
+        let gather_args: GatherArgs = gather_args(&format!(
+            r#"
+            [[webhooks]]
+            url = "http://{address}/failure"
+            on_failure = true
+
+            [[webhooks]]
+            url = "http://{address}/success"
+            format = "slack"
+            on_success = true
+            "#
+        ));
+
+        notify_run(&gather_args, &report(RunOutcome::Success, 1792368452, None)).await;
+        notify_run(
+            &gather_args,
+            &report(RunOutcome::Cancelled, 1792368452, None),
+        )
+        .await;
+
+        assert_eq!(
+            received(&mut receiver),
+            vec![(
+                "/success".to_string(),
+                json!({ "text": "Backup Documents succeeded, uploaded 1 files (2048 bytes) in 0.5s" })
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn stale_backups_are_posted_once() {
+        let (address, mut receiver) = listen();
+        let gather_args: GatherArgs = gather_args(&format!(
+            r#"
+            [[webhooks]]
+            url = "http://{address}/json"
+            stale_after = 3600
+
+            [[webhooks]]
+            url = "http://{address}/discord"
+            format = "discord"
+            stale_after = 86400
+            "#
+        ));
+        let control = GathererControl::new(gather_args.name.clone());
+        let succeeded: u64 = now() - 7200;
+        control.restore(&[
+            report(RunOutcome::Success, succeeded, None),
+            report(
+                RunOutcome::Failed,
+                succeeded + 3600,
+                Some("Bucket not found"),
+            ),
+        ]);
+
+        notify_stale(&gather_args, &control).await;
+        notify_stale(&gather_args, &control).await;
+
+        let requests: Vec<(String, Value)> = received(&mut receiver);
+        assert_eq!(requests.len(), 1);
+        let (path, payload) = &requests[0];
+        assert_eq!(path, "/json");
+        assert_eq!(payload["event"], "stale");
+        assert_eq!(payload["outcome"], "failed");
+        assert_eq!(payload["error"], "Bucket not found");
+        let stale_for: u64 = payload["stale_for"].as_u64().unwrap();
+        assert!((7200..7260).contains(&stale_for));
+        assert_eq!(
+            payload["message"],
+            format!(
+                "Backup Documents has not succeeded for {}, its last run failed: Bucket not found",
+                format_age(stale_for)
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn backups_which_never_ran_are_posted_as_stale() {
+        let (address, mut receiver) = listen();
+        let gather_args: GatherArgs = gather_args(&format!(
+            r#"
+            [[webhooks]]
+            url = "http://{address}/slack"
+            format = "slack"
+            stale_after = 0
+            "#
+        ));
+        let control = GathererControl::new(gather_args.name.clone());
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+
+        notify_stale(&gather_args, &control).await;
+
+        let requests: Vec<(String, Value)> = received(&mut receiver);
+        assert_eq!(requests.len(), 1);
+        let text: &str = requests[0].1["text"].as_str().unwrap();
+        assert!(text.starts_with("Backup Documents has not succeeded for "));
+        assert!(text.ends_with(", it has not run yet"));
+    }
+}
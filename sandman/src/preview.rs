@@ -0,0 +1,159 @@
+use crate::args::GatherArgs;
+use crate::entry::{EntryKind, SparseReader};
+use crate::keys::decode_key;
+use crate::selection::{Explanation, Selection};
+use crate::sha::{generate_shas, get_sha_diff, rebaseline_shas, relative_key, ShaFile};
+use crate::store::HistoryStore;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tokio_util::sync::CancellationToken;
+
+/// Prints every file a backup would include with its size, followed by the totals. The files are
+/// found by the same walk a backup performs.
+///
+/// # Arguments
+///
+/// * `gather_args` - `GatherArgs` of the backup to preview.
+pub(crate) fn ls_files(gather_args: &GatherArgs) {
+    let root: &Path = Path::new(&gather_args.local_directory);
+    let selection: Selection = Selection::new(gather_args);
+    let mut files: Vec<(String, u64)> = vec![];
+
+    selection.walk(root, &mut |path, metadata| {
+        if let Some(key) = relative_key(root, path) {
+            files.push((key, metadata.len()));
+        }
+    });
+    files.sort();
+
+    for (key, size) in &files {
+        println!("{:>14}  {}", size, key);
+    }
+    let total: u64 = files.iter().map(|(_, size)| size).sum();
+    println!("{} files, {} bytes", files.len(), total);
+}
+
+/// Scans a backup as a run would and prints every file the run would upload with its size,
+/// followed by the totals and, for cleanable backups, the files which would be removed locally.
+/// Nothing is uploaded or removed, and the history is only read, never created or written.
+///
+/// # Arguments
+///
+/// * `gather_args` - `GatherArgs` of the backup to dry run.
+pub(crate) fn dry_run(gather_args: &GatherArgs) {
+    let root: &Path = Path::new(&gather_args.local_directory);
+    let mut old_file_shas: ShaFile = HistoryStore::peek(gather_args)
+        .unwrap_or_else(|e| {
+            eprintln!("{}, comparing against an empty history", e);
+            None
+        })
+        .unwrap_or_else(|| ShaFile::with_algorithm(gather_args.hash_algorithm));
+    if old_file_shas.algorithm != gather_args.hash_algorithm {
+        old_file_shas = rebaseline_shas(old_file_shas, root, gather_args.hash_algorithm);
+    }
+    let mut current_file_shas: ShaFile = ShaFile::with_algorithm(gather_args.hash_algorithm);
+    let selection: Selection = Selection::new(gather_args);
+    generate_shas(
+        root,
+        &mut current_file_shas,
+        &selection,
+        None,
+        |keys| {
+            keys.into_iter()
+                .filter_map(|key| Some((key.clone(), old_file_shas.files.get(key)?.clone())))
+                .collect()
+        },
+        &CancellationToken::new(),
+    );
+    let scanned: usize = current_file_shas.files.len();
+    let sha_diff: ShaFile = get_sha_diff(&old_file_shas, current_file_shas);
+
+    let mut files: Vec<(String, u64)> = sha_diff
+        .files
+        .iter()
+        .map(|(key, record)| (key.clone(), upload_size(root, key, record)))
+        .collect();
+    files.sort();
+
+    println!(
+        "Dry run of {} ({}), {} files scanned",
+        gather_args.name, gather_args.local_directory, scanned
+    );
+    for (key, size) in &files {
+        println!("{:>14}  {}", size, key);
+    }
+    let total: u64 = files.iter().map(|(_, size)| size).sum();
+    println!(
+        "{} files, {} bytes would be uploaded to s3://{}/{}/",
+        files.len(),
+        total,
+        gather_args.bucket,
+        gather_args.bucket_prefix
+    );
+    if gather_args.cleanable {
+        let removable: usize = sha_diff
+            .files
+            .values()
+            .filter(|record| EntryKind::of_record(record).is_removable())
+            .count();
+        println!("{} files would be removed locally once uploaded", removable);
+    }
+}
+
+/// Returns the size of the object a changed entry would be uploaded as, the size of a file, its
+/// header and data extents for sparse files, or the length of what is stored for links and
+/// special files. Entries which cannot be read count as empty.
+fn upload_size(root: &Path, key: &str, record: &str) -> u64 {
+    let path: PathBuf = root.join(decode_key(key));
+    match EntryKind::of_record(record) {
+        EntryKind::File => fs::metadata(&path).map(|m| m.len()).unwrap_or(0),
+        EntryKind::Sparse => SparseReader::object(&path)
+            .map(|reader| reader.size())
+            .unwrap_or(0),
+        kind => kind
+            .read(&path)
+            .map(|bytes| bytes.len() as u64)
+            .unwrap_or(0),
+    }
+}
+
+/// Prints the ignore rule or filter deciding each path, in the style of `git check-ignore -v`:
+/// `<source>:<line>:<pattern>\t<path>`. Paths no rule applies to are not printed.
+///
+/// # Arguments
+///
+/// * `gather_args` - `GatherArgs` of the backup the paths belong to.
+/// * `paths` - The paths to check.
+///
+/// # Returns
+///
+/// Whether any of the paths is excluded from the backup, `false` if any path is not inside its root.
+pub(crate) fn check_ignore(gather_args: &GatherArgs, paths: &[PathBuf]) -> bool {
+    let selection: Selection = Selection::new(gather_args);
+    let mut any_excluded: bool = false;
+
+    let mut all_inside: bool = true;
+
+    for path in paths {
+        let explanation: Option<Explanation> = match selection.explain(path) {
+            Ok(explanation) => explanation,
+            Err(e) => {
+                eprintln!("{}", e);
+                all_inside = false;
+                continue;
+            }
+        };
+        if let Some(explanation) = explanation {
+            let Explanation {
+                source,
+                line,
+                pattern,
+                excluded,
+            } = explanation;
+            let line: String = line.map(|line| line.to_string()).unwrap_or_default();
+            println!("{}:{}:{}\t{}", source, line, pattern, path.display());
+            any_excluded |= excluded;
+        }
+    }
+    any_excluded && all_inside
+}
@@ -0,0 +1,511 @@
+use crate::args::GatherArgs;
+use crate::keys::{is_ambiguous, stored_key};
+use crate::sha::ShaFile;
+use crate::store::HistoryStore;
+use rusoto_core::{HttpClient, Region};
+use rusoto_s3::{
+    Delete, DeleteObjectsRequest, HeadObjectRequest, ListObjectsV2Request, ObjectIdentifier,
+    S3Client, S3,
+};
+use sandman_share::config::AwsConfig;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::str::FromStr;
+
+/// Number of objects S3 deletes in one request.
+const DELETE_BATCH: usize = 1000;
+
+/// Number of missing files `sandman verify` lists per backup, the rest are only counted.
+const MAX_LISTED_MISSING: usize = 20;
+
+/// An object uploaded by a backup, its key being `<prefix>/<snapshot>/<key>`.
+pub(crate) struct RemoteObject {
+    /// Key of the object in the bucket.
+    pub(crate) object_key: String,
+    /// The snapshot the object was uploaded in.
+    pub(crate) snapshot: String,
+    /// Key of the backed up path, relative to the backup root.
+    pub(crate) key: String,
+    /// Size of the object in bytes.
+    pub(crate) size: u64,
+}
+
+/// The files and bytes uploaded in one snapshot, as printed by `sandman snapshots`.
+#[derive(Serialize)]
+struct SnapshotSummary<'a> {
+    snapshot: &'a str,
+    files: usize,
+    bytes: u64,
+}
+
+/// Creates the S3 client backups are uploaded and restored with, using the configured credentials
+/// and region. Without credentials the client falls back to the environment's credentials in
+/// `us-east-1`.
+///
+/// # Arguments
+///
+/// * `credentials` - Optional AWS credentials configuration.
+pub(crate) fn s3_client(credentials: &Option<AwsConfig>) -> S3Client {
+    let Some(credentials) = credentials else {
+        return S3Client::new(Region::UsEast1);
+    };
+    let region: Region =
+        Region::from_str(&credentials.aws_default_region).expect("Invalid region string");
+    S3Client::new_with(HttpClient::new().unwrap(), credentials.clone(), region)
+}
+
+/// Lists every object uploaded by a backup, following the listing across pages.
+///
+/// # Arguments
+///
+/// * `client` - The `S3Client` of the backup's bucket.
+/// * `gather_args` - `GatherArgs` of the backup, giving its bucket and prefix.
+///
+/// # Returns
+///
+/// The objects, ordered by snapshot and key, or a description of why they could not be listed.
+pub(crate) async fn list_objects(
+    client: &S3Client,
+    gather_args: &GatherArgs,
+) -> Result<Vec<RemoteObject>, String> {
+    let prefix: String = format!("{}/", gather_args.bucket_prefix);
+    let mut objects: Vec<RemoteObject> = vec![];
+    let mut continuation_token: Option<String> = None;
+    loop {
+        let page = client
+            .list_objects_v2(ListObjectsV2Request {
+                bucket: gather_args.bucket.clone(),
+                prefix: Some(prefix.clone()),
+                continuation_token: continuation_token.take(),
+                ..Default::default()
+            })
+            .await
+            .map_err(|e| {
+                format!(
+                    "Unable to list s3://{}/{}: {}",
+                    gather_args.bucket, prefix, e
+                )
+            })?;
+        for object in page.contents.unwrap_or_default() {
+            let Some(object_key) = object.key else {
+                continue;
+            };
+            let Some((snapshot, key)) = object_key
+                .strip_prefix(&prefix)
+                .and_then(|rest| rest.split_once('/'))
+            else {
+                continue;
+            };
+            objects.push(RemoteObject {
+                snapshot: snapshot.to_string(),
+                key: key.to_string(),
+                size: object.size.unwrap_or(0).max(0) as u64,
+                object_key,
+            });
+        }
+        match page.next_continuation_token {
+            Some(token) if page.is_truncated == Some(true) => continuation_token = Some(token),
+            _ => break,
+        }
+    }
+    // Keys uploaded before keys were encoded hold `%` verbatim, their metadata tells them apart
+    for object in objects
+        .iter_mut()
+        .filter(|object| is_ambiguous(&object.key))
+    {
+        let head = client
+            .head_object(HeadObjectRequest {
+                bucket: gather_args.bucket.clone(),
+                key: object.object_key.clone(),
+                ..Default::default()
+            })
+            .await
+            .map_err(|e| {
+                format!(
+                    "Unable to read s3://{}/{}: {}",
+                    gather_args.bucket, object.object_key, e
+                )
+            })?;
+        object.key = stored_key(&object.key, head.metadata.as_ref());
+    }
+    objects.sort_by(|a, b| (&a.snapshot, &a.key).cmp(&(&b.snapshot, &b.key)));
+    Ok(objects)
+}
+
+/// Returns the snapshots found among a backup's objects, oldest first. Snapshot names are times,
+/// so their order is also the order they were taken in.
+pub(crate) fn snapshots(objects: &[RemoteObject]) -> Vec<&str> {
+    let mut snapshots: Vec<&str> = objects
+        .iter()
+        .map(|object| object.snapshot.as_str())
+        .collect();
+    snapshots.dedup();
+    snapshots
+}
+
+/// Picks the object holding each path as of a snapshot. Runs only upload changed files, so a path
+/// is held by the object of the latest snapshot up to and including `snapshot` it was uploaded in.
+///
+/// # Arguments
+///
+/// * `objects` - The backup's objects, as returned by `list_objects`.
+/// * `snapshot` - Name of the snapshot.
+///
+/// # Returns
+///
+/// The object of every path, by the key of the path.
+pub(crate) fn objects_at<'a>(
+    objects: &'a [RemoteObject],
+    snapshot: &str,
+) -> HashMap<&'a str, &'a RemoteObject> {
+    let mut at: HashMap<&str, &RemoteObject> = HashMap::new();
+    // Objects are ordered by snapshot, so later ones replace earlier ones
+    for object in objects
+        .iter()
+        .filter(|object| object.snapshot.as_str() <= snapshot)
+    {
+        at.insert(object.key.as_str(), object);
+    }
+    at
+}
+
+/// Prints the snapshots of a backup with the number of files and bytes uploaded in each, most
+/// recent first.
+///
+/// # Arguments
+///
+/// * `gather_args` - `GatherArgs` of the backup.
+/// * `aws` - Optional AWS credentials configuration.
+/// * `json` - Whether to print JSON rather than a table.
+///
+/// # Returns
+///
+/// A description of why the snapshots could not be listed, if they could not.
+pub(crate) async fn print_snapshots(
+    gather_args: &GatherArgs,
+    aws: &Option<AwsConfig>,
+    json: bool,
+) -> Result<(), String> {
+    let objects: Vec<RemoteObject> = list_objects(&s3_client(aws), gather_args).await?;
+    let mut summaries: BTreeMap<&str, SnapshotSummary> = BTreeMap::new();
+    for object in &objects {
+        let summary: &mut SnapshotSummary =
+            summaries
+                .entry(&object.snapshot)
+                .or_insert(SnapshotSummary {
+                    snapshot: &object.snapshot,
+                    files: 0,
+                    bytes: 0,
+                });
+        summary.files += 1;
+        summary.bytes += object.size;
+    }
+    let summaries: Vec<SnapshotSummary> = summaries.into_values().rev().collect();
+
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&summaries).expect("Unable to serialize snapshots")
+        );
+        return Ok(());
+    }
+    let width: usize = summaries
+        .iter()
+        .map(|summary| summary.snapshot.len())
+        .max()
+        .unwrap_or(0)
+        .max("SNAPSHOT".len());
+    println!("{:<width$}  {:>8}  {:>14}", "SNAPSHOT", "FILES", "BYTES");
+    for summary in &summaries {
+        println!(
+            "{:<width$}  {:>8}  {:>14}",
+            summary.snapshot, summary.files, summary.bytes
+        );
+    }
+    Ok(())
+}
+
+/// Checks that every file the history of a backup records has an object in its bucket, printing
+/// what was found and the files which are missing.
+///
+/// # Arguments
+///
+/// * `gather_args` - `GatherArgs` of the backup.
+/// * `aws` - Optional AWS credentials configuration.
+///
+/// # Returns
+///
+/// Whether every recorded file was found.
+pub(crate) async fn verify(gather_args: &GatherArgs, aws: &Option<AwsConfig>) -> bool {
+    if !HistoryStore::location(gather_args).exists() {
+        println!(
+            "{}: no history, the backup has not run yet",
+            gather_args.name
+        );
+        return false;
+    }
+    let history: ShaFile = match HistoryStore::open(gather_args).and_then(|history| history.load())
+    {
+        Ok(history) => history,
+        Err(e) => {
+            println!("{}: {}", gather_args.name, e);
+            return false;
+        }
+    };
+    let objects: Vec<RemoteObject> = match list_objects(&s3_client(aws), gather_args).await {
+        Ok(objects) => objects,
+        Err(e) => {
+            println!("{}: {}", gather_args.name, e);
+            return false;
+        }
+    };
+
+    let uploaded: HashSet<&str> = objects.iter().map(|object| object.key.as_str()).collect();
+    let mut missing: Vec<&String> = history
+        .files
+        .keys()
+        .filter(|key| !uploaded.contains(key.as_str()))
+        .collect();
+    missing.sort();
+    println!(
+        "{}: {} files recorded, {} objects in {} snapshots, {} missing",
+        gather_args.name,
+        history.files.len(),
+        objects.len(),
+        snapshots(&objects).len(),
+        missing.len()
+    );
+    for key in missing.iter().take(MAX_LISTED_MISSING) {
+        println!("  missing: {}", key);
+    }
+    if missing.len() > MAX_LISTED_MISSING {
+        println!("  ... and {} more", missing.len() - MAX_LISTED_MISSING);
+    }
+    missing.is_empty()
+}
+
+/// Which objects of a backup are needed to keep its most recent snapshots restorable.
+struct Retention<'a> {
+    /// The snapshots of the backup, oldest first.
+    snapshots: Vec<&'a str>,
+    /// Number of most recent snapshots kept restorable.
+    keep: usize,
+    /// Object keys held by a path as of one of the kept snapshots.
+    needed: HashSet<&'a str>,
+}
+
+impl<'a> Retention<'a> {
+    /// Works out which objects the `keep` most recent snapshots of a backup need.
+    ///
+    /// # Arguments
+    ///
+    /// * `objects` - The backup's objects, as returned by `list_objects`.
+    /// * `keep` - Number of most recent snapshots to keep restorable.
+    fn new(objects: &'a [RemoteObject], keep: usize) -> Self {
+        let snapshots: Vec<&str> = snapshots(objects);
+        let keep: usize = keep.min(snapshots.len());
+        let needed: HashSet<&str> = snapshots[snapshots.len() - keep..]
+            .iter()
+            .flat_map(|snapshot| objects_at(objects, snapshot).into_values())
+            .map(|object| object.object_key.as_str())
+            .collect();
+        Retention {
+            snapshots,
+            keep,
+            needed,
+        }
+    }
+
+    /// The snapshots kept restorable, oldest first.
+    fn kept(&self) -> &[&'a str] {
+        &self.snapshots[self.snapshots.len() - self.keep..]
+    }
+
+    /// The older snapshots which are no longer kept restorable, oldest first.
+    fn dropped(&self) -> &[&'a str] {
+        &self.snapshots[..self.snapshots.len() - self.keep]
+    }
+
+    /// The objects none of the kept snapshots need.
+    fn unneeded<'o>(&self, objects: &'o [RemoteObject]) -> Vec<&'o RemoteObject> {
+        objects
+            .iter()
+            .filter(|object| !self.needed.contains(object.object_key.as_str()))
+            .collect()
+    }
+}
+
+/// Deletes the objects of a backup which none of its `keep` most recent snapshots need, so that
+/// those snapshots stay fully restorable while older ones are dropped. Files unchanged since an
+/// old snapshot keep the object they were uploaded in.
+///
+/// # Arguments
+///
+/// * `gather_args` - `GatherArgs` of the backup.
+/// * `aws` - Optional AWS credentials configuration.
+/// * `keep` - Number of most recent snapshots to keep restorable.
+/// * `dry_run` - Whether to only print what would be deleted.
+///
+/// # Returns
+///
+/// A description of why pruning failed, if it did.
+pub(crate) async fn prune(
+    gather_args: &GatherArgs,
+    aws: &Option<AwsConfig>,
+    keep: usize,
+    dry_run: bool,
+) -> Result<(), String> {
+    let client: S3Client = s3_client(aws);
+    let objects: Vec<RemoteObject> = list_objects(&client, gather_args).await?;
+    let retention: Retention = Retention::new(&objects, keep);
+    let unneeded: Vec<&RemoteObject> = retention.unneeded(&objects);
+
+    let verb: &str = if dry_run { "Would delete" } else { "Deleting" };
+    println!(
+        "{} {} of {} objects ({} bytes) of {}, keeping {} of {} snapshots restorable",
+        verb,
+        unneeded.len(),
+        objects.len(),
+        unneeded.iter().map(|object| object.size).sum::<u64>(),
+        gather_args.name,
+        retention.kept().len(),
+        retention.snapshots.len()
+    );
+    for snapshot in retention.dropped() {
+        let remaining: usize = objects
+            .iter()
+            .filter(|object| object.snapshot == *snapshot)
+            .filter(|object| retention.needed.contains(object.object_key.as_str()))
+            .count();
+        match remaining {
+            0 => println!("  {}: removed", snapshot),
+            _ => println!("  {}: {} objects still needed", snapshot, remaining),
+        }
+    }
+    if dry_run {
+        return Ok(());
+    }
+
+    let mut failed: usize = 0;
+    for batch in unneeded.chunks(DELETE_BATCH) {
+        let output = client
+            .delete_objects(DeleteObjectsRequest {
+                bucket: gather_args.bucket.clone(),
+                delete: Delete {
+                    objects: batch
+                        .iter()
+                        .map(|object| ObjectIdentifier {
+                            key: object.object_key.clone(),
+                            version_id: None,
+                        })
+                        .collect(),
+                    quiet: Some(true),
+                },
+                ..Default::default()
+            })
+            .await
+            .map_err(|e| format!("Unable to delete objects: {}", e))?;
+        for error in output.errors.unwrap_or_default() {
+            eprintln!(
+                "Unable to delete {}: {}",
+                error.key.unwrap_or_default(),
+                error.message.unwrap_or_default()
+            );
+            failed += 1;
+        }
+    }
+    match failed {
+        0 => Ok(()),
+        _ => Err(format!("{} objects could not be deleted", failed)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the objects of a backup from `(snapshot, key)` pairs, ordered as `list_objects`
+    /// orders them.
+    fn objects(uploads: &[(&str, &str)]) -> Vec<RemoteObject> {
+        let mut objects: Vec<RemoteObject> = uploads
+            .iter()
+            .map(|(snapshot, key)| RemoteObject {
+                object_key: format!("documents/{}/{}", snapshot, key),
+                snapshot: snapshot.to_string(),
+                key: key.to_string(),
+                size: 1,
+            })
+            .collect();
+        objects.sort_by(|a, b| (&a.snapshot, &a.key).cmp(&(&b.snapshot, &b.key)));
+        objects
+    }
+
+    fn object_keys(objects: Vec<&RemoteObject>) -> Vec<&str> {
+        objects
+            .iter()
+            .map(|object| object.object_key.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn objects_held_over_from_old_snapshots_are_kept() {
+        let objects: Vec<RemoteObject> = objects(&[
+            ("2026-01-01", "a.txt"),
+            ("2026-01-01", "b.txt"),
+            ("2026-02-01", "a.txt"),
+            ("2026-03-01", "c.txt"),
+        ]);
+        let retention: Retention = Retention::new(&objects, 1);
+        assert_eq!(retention.kept(), ["2026-03-01"]);
+        assert_eq!(retention.dropped(), ["2026-01-01", "2026-02-01"]);
+        assert_eq!(
+            object_keys(retention.unneeded(&objects)),
+            ["documents/2026-01-01/a.txt"]
+        );
+    }
+
+    #[test]
+    fn every_kept_snapshot_stays_restorable() {
+        let objects: Vec<RemoteObject> = objects(&[
+            ("2026-01-01", "a.txt"),
+            ("2026-02-01", "a.txt"),
+            ("2026-03-01", "a.txt"),
+        ]);
+        let retention: Retention = Retention::new(&objects, 2);
+        assert_eq!(retention.kept(), ["2026-02-01", "2026-03-01"]);
+        assert_eq!(
+            object_keys(retention.unneeded(&objects)),
+            ["documents/2026-01-01/a.txt"]
+        );
+    }
+
+    #[test]
+    fn keeping_more_snapshots_than_exist_deletes_nothing() {
+        let objects: Vec<RemoteObject> =
+            objects(&[("2026-01-01", "a.txt"), ("2026-02-01", "a.txt")]);
+        let retention: Retention = Retention::new(&objects, 5);
+        assert_eq!(retention.kept(), ["2026-01-01", "2026-02-01"]);
+        assert!(retention.dropped().is_empty());
+        assert!(retention.unneeded(&objects).is_empty());
+    }
+
+    #[test]
+    fn keeping_no_snapshots_deletes_everything() {
+        let objects: Vec<RemoteObject> =
+            objects(&[("2026-01-01", "a.txt"), ("2026-02-01", "b.txt")]);
+        let retention: Retention = Retention::new(&objects, 0);
+        assert!(retention.kept().is_empty());
+        assert_eq!(retention.unneeded(&objects).len(), 2);
+    }
+
+    #[test]
+    fn objects_at_picks_the_latest_upload_up_to_the_snapshot() {
+        let objects: Vec<RemoteObject> = objects(&[
+            ("2026-01-01", "a.txt"),
+            ("2026-02-01", "a.txt"),
+            ("2026-03-01", "a.txt"),
+        ]);
+        let at: HashMap<&str, &RemoteObject> = objects_at(&objects, "2026-02-01");
+        assert_eq!(at["a.txt"].snapshot, "2026-02-01");
+    }
+}
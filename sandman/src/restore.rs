@@ -0,0 +1,326 @@
+use crate::args::GatherArgs;
+use crate::entry::EntryKind;
+use crate::keys::decode_key;
+use crate::remote::{list_objects, objects_at, s3_client, snapshots, RemoteObject};
+use rusoto_s3::{GetObjectOutput, GetObjectRequest, S3Client, S3};
+use sandman_share::config::AwsConfig;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use tokio::io::AsyncRead;
+use tokio::task::spawn_blocking;
+use tokio_util::io::SyncIoBridge;
+use tracing::{error, warn};
+
+/// What `sandman restore` should restore and where.
+pub(crate) struct RestoreRequest<'a> {
+    /// Snapshot to restore, the most recent one when `None`.
+    pub(crate) snapshot: Option<&'a str>,
+    /// Directory the files are restored into.
+    pub(crate) to: &'a Path,
+    /// Files or directories to restore, relative to the backup root, everything when empty.
+    pub(crate) paths: &'a [PathBuf],
+    /// Whether files already in `to` are replaced.
+    pub(crate) overwrite: bool,
+}
+
+/// What a restore did.
+#[derive(Default)]
+struct Restored {
+    files: usize,
+    bytes: u64,
+    skipped: usize,
+    failed: usize,
+}
+
+/// Restores the files of a backup as they were at a snapshot into a directory, recreating
+/// symbolic links, hard links and sparse files. Files which already exist are skipped unless
+/// `overwrite` is set, and special files are skipped as they cannot be recreated from their
+/// objects. Prints a summary once done.
+///
+/// # Arguments
+///
+/// * `gather_args` - `GatherArgs` of the backup.
+/// * `aws` - Optional AWS credentials configuration.
+/// * `request` - The `RestoreRequest` describing what to restore.
+///
+/// # Returns
+///
+/// Whether every file was restored or deliberately skipped, or a description of why the restore
+/// could not start.
+pub(crate) async fn restore(
+    gather_args: &GatherArgs,
+    aws: &Option<AwsConfig>,
+    request: &RestoreRequest<'_>,
+) -> Result<bool, String> {
+    let client: S3Client = s3_client(aws);
+    let objects: Vec<RemoteObject> = list_objects(&client, gather_args).await?;
+    let snapshot: &str = match request.snapshot {
+        Some(snapshot) if snapshots(&objects).contains(&snapshot) => snapshot,
+        Some(snapshot) => {
+            return Err(format!(
+                "No snapshot {} of {}, list them with `sandman snapshots {}`",
+                snapshot, gather_args.name, gather_args.name
+            ))
+        }
+        None => snapshots(&objects)
+            .last()
+            .copied()
+            .ok_or_else(|| format!("No snapshots of {} found", gather_args.name))?,
+    };
+
+    let at: HashMap<&str, &RemoteObject> = objects_at(&objects, snapshot);
+    let mut selected: Vec<&RemoteObject> = at
+        .values()
+        .copied()
+        .filter(|object| {
+            let path: PathBuf = decode_key(&object.key);
+            request.paths.is_empty() || request.paths.iter().any(|wanted| path.starts_with(wanted))
+        })
+        .collect();
+    if selected.is_empty() {
+        return Err(format!(
+            "Nothing to restore from snapshot {} of {}",
+            snapshot, gather_args.name
+        ));
+    }
+    selected.sort_by(|a, b| a.key.cmp(&b.key));
+    let selected_keys: HashSet<&str> = selected.iter().map(|object| object.key.as_str()).collect();
+    fs::create_dir_all(request.to)
+        .map_err(|e| format!("Unable to create {:?}: {}", request.to, e))?;
+
+    let mut restored: Restored = Restored::default();
+    // Hard links are created once every file they may link to is in place
+    let mut links: Vec<(PathBuf, String)> = vec![];
+    for object in selected {
+        let relative: PathBuf = decode_key(&object.key);
+        if !is_contained(&relative) {
+            error!(path = %object.key, "Refusing to restore outside {}", request.to.display());
+            restored.failed += 1;
+            continue;
+        }
+        let path: PathBuf = request.to.join(relative);
+        if fs::symlink_metadata(&path).is_ok() && !request.overwrite {
+            warn!(path = %path.display(), "Already exists, skipping");
+            restored.skipped += 1;
+            continue;
+        }
+        match restore_object(&client, gather_args, object, &path, request.overwrite).await {
+            Ok(Some(EntryKind::Hardlink(target))) => links.push((path, target)),
+            Ok(Some(EntryKind::Special(description))) => {
+                warn!(path = %path.display(), "Skipping special file ({})", description);
+                restored.skipped += 1;
+            }
+            Ok(_) => {
+                restored.files += 1;
+                restored.bytes += object.size;
+            }
+            Err(e) => {
+                error!(path = %path.display(), "Unable to restore: {}", e);
+                restored.failed += 1;
+            }
+        }
+    }
+    for (path, target) in links {
+        let target_path: PathBuf = decode_key(&target);
+        if !is_contained(&target_path) {
+            error!(path = %path.display(), "Refusing to link outside {}", request.to.display());
+            restored.failed += 1;
+            continue;
+        }
+        let result: io::Result<u64> = match link_source(&target, &selected_keys, &at) {
+            Some(LinkSource::Restored) => {
+                fs::hard_link(request.to.join(target_path), &path).map(|_| 0)
+            }
+            Some(LinkSource::Excluded(object)) => {
+                restore_object(&client, gather_args, object, &path, request.overwrite)
+                    .await
+                    .map(|_| object.size)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "file not found in the snapshot",
+            )),
+        };
+        match result {
+            Ok(bytes) => {
+                restored.files += 1;
+                restored.bytes += bytes;
+            }
+            Err(e) => {
+                error!(path = %path.display(), "Unable to link to {}: {}", target, e);
+                restored.failed += 1;
+            }
+        }
+    }
+
+    println!(
+        "Restored {} files ({} bytes) of {} from snapshot {} to {}, {} skipped, {} failed",
+        restored.files,
+        restored.bytes,
+        gather_args.name,
+        snapshot,
+        request.to.display(),
+        restored.skipped,
+        restored.failed
+    );
+    Ok(restored.failed == 0)
+}
+
+/// Where the contents of a hard link being restored come from.
+enum LinkSource<'a> {
+    /// The file linked to is being restored too, so the link is recreated.
+    Restored,
+
+    /// The file linked to is not being restored, so its object is restored to the link's path.
+    Excluded(&'a RemoteObject),
+}
+
+/// Decides how a hard link to the file stored under `target` is restored.
+///
+/// # Arguments
+///
+/// * `target` - Key of the file the link points to.
+/// * `selected` - Keys of the files being restored.
+/// * `at` - The object of every path as of the snapshot being restored.
+///
+/// # Returns
+///
+/// The `LinkSource` of the link, `None` if the snapshot has no such file.
+fn link_source<'a>(
+    target: &str,
+    selected: &HashSet<&str>,
+    at: &HashMap<&str, &'a RemoteObject>,
+) -> Option<LinkSource<'a>> {
+    match at.get(target) {
+        Some(_) if selected.contains(target) => Some(LinkSource::Restored),
+        Some(object) => Some(LinkSource::Excluded(object)),
+        None => None,
+    }
+}
+
+/// Downloads an object and recreates its entry at `path`.
+///
+/// # Returns
+///
+/// The kind of entry, left for the caller to recreate when it is a hard link or special file.
+async fn restore_object(
+    client: &S3Client,
+    gather_args: &GatherArgs,
+    object: &RemoteObject,
+    path: &Path,
+    overwrite: bool,
+) -> io::Result<Option<EntryKind>> {
+    let output: GetObjectOutput = client
+        .get_object(GetObjectRequest {
+            bucket: gather_args.bucket.clone(),
+            key: object.object_key.clone(),
+            ..Default::default()
+        })
+        .await
+        .map_err(|e| io::Error::other(e.to_string()))?;
+    let body: Box<dyn AsyncRead + Send + Unpin> = match output.body {
+        Some(stream) => Box::new(stream.into_async_read()),
+        None => Box::new(tokio::io::empty()),
+    };
+    let metadata: Option<HashMap<String, String>> = output.metadata;
+    let path: PathBuf = path.to_path_buf();
+
+    // The body is written to the file as it downloads, which blocks
+    spawn_blocking(move || {
+        let mut body: SyncIoBridge<_> = SyncIoBridge::new(body);
+        let kind: EntryKind = EntryKind::of_object(metadata.as_ref(), &mut body)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if overwrite && !kind.is_special() && fs::symlink_metadata(&path).is_ok() {
+            fs::remove_file(&path)?;
+        }
+        match kind {
+            EntryKind::Hardlink(_) | EntryKind::Special(_) => Ok(Some(kind)),
+            kind => kind.restore(body, &path).map(|_| None),
+        }
+    })
+    .await
+    .map_err(io::Error::other)?
+}
+
+/// Whether a path decoded from a key stays within the directory it is joined to. Keys come from
+/// the bucket, which must not be able to place files elsewhere.
+fn is_contained(relative: &Path) -> bool {
+    relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relative_paths_are_contained() {
+        assert!(is_contained(Path::new("notes.txt")));
+        assert!(is_contained(Path::new("projects/sandman/Cargo.toml")));
+        assert!(is_contained(Path::new("..hidden/file..")));
+    }
+
+    #[test]
+    fn parent_components_escape() {
+        assert!(!is_contained(Path::new("../notes.txt")));
+        assert!(!is_contained(Path::new("projects/../../notes.txt")));
+    }
+
+    #[test]
+    fn absolute_paths_escape() {
+        assert!(!is_contained(Path::new("/etc/passwd")));
+    }
+
+    #[test]
+    fn current_directory_components_are_rejected() {
+        assert!(!is_contained(Path::new("./notes.txt")));
+    }
+
+    fn object(snapshot: &str, key: &str) -> RemoteObject {
+        RemoteObject {
+            object_key: format!("documents/{}/{}", snapshot, key),
+            snapshot: snapshot.to_string(),
+            key: key.to_string(),
+            size: 4,
+        }
+    }
+
+    #[test]
+    fn links_to_restored_files_are_recreated() {
+        let target: RemoteObject = object("2026-10-19", "docs/a.txt");
+        let at: HashMap<&str, &RemoteObject> = HashMap::from([("docs/a.txt", &target)]);
+        let selected: HashSet<&str> = HashSet::from(["docs/a.txt", "docs/b.txt"]);
+
+        assert!(matches!(
+            link_source("docs/a.txt", &selected, &at),
+            Some(LinkSource::Restored)
+        ));
+    }
+
+    #[test]
+    fn links_to_excluded_files_restore_their_contents() {
+        let target: RemoteObject = object("2026-10-18", "docs/a.txt");
+        let at: HashMap<&str, &RemoteObject> = HashMap::from([("docs/a.txt", &target)]);
+        let selected: HashSet<&str> = HashSet::from(["other/b.txt"]);
+
+        match link_source("docs/a.txt", &selected, &at) {
+            Some(LinkSource::Excluded(object)) => {
+                assert_eq!(object.object_key, "documents/2026-10-18/docs/a.txt")
+            }
+            _ => panic!("the excluded target's object should be restored"),
+        }
+    }
+
+    #[test]
+    fn links_to_missing_files_fail() {
+        let at: HashMap<&str, &RemoteObject> = HashMap::new();
+        let selected: HashSet<&str> = HashSet::from(["other/b.txt"]);
+
+        assert!(link_source("docs/a.txt", &selected, &at).is_none());
+    }
+}
@@ -0,0 +1,305 @@
+use crate::args::GatherArgs;
+use crate::control::{RunOutcome, RunReport};
+use crate::ctl::local_time;
+use crate::keys::encode_path;
+use crate::store::{FileRecord, HistoryStore};
+use sandman_share::consts::SANDMAN_RUNS;
+use sandman_share::paths::backup_state_dir;
+use serde::Serialize;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tracing::error;
+
+/// Number of reports a run log keeps, older ones are dropped.
+const MAX_RUN_REPORTS: usize = 1000;
+
+/// Number of failed files a report lists, the rest are only counted.
+pub(crate) const MAX_REPORTED_ERRORS: usize = 20;
+
+/// A run report alongside the backup it belongs to, as printed by `sandman history --json`.
+#[derive(Serialize)]
+struct BackupRun<'a> {
+    backup: &'a str,
+    #[serde(flatten)]
+    report: &'a RunReport,
+}
+
+/// Returns the location of a backup's run log, a JSON report per line in its state directory.
+fn runs_path(name: &str) -> PathBuf {
+    backup_state_dir(name).join(SANDMAN_RUNS)
+}
+
+/// Appends a report to a backup's run log. Once the log holds more than `MAX_RUN_REPORTS`
+/// reports it is rewritten with the most recent ones, replacing the file in one step so that a
+/// crash leaves either log whole. Errors are logged, a run is never failed by its report.
+///
+/// # Arguments
+///
+/// * `name` - Name of the backup.
+/// * `report` - The `RunReport` of the finished run.
+pub(crate) fn record_run(name: &str, report: &RunReport) {
+    let path: PathBuf = runs_path(name);
+    let mut line: String = serde_json::to_string(report).expect("Unable to serialize run report");
+    line.push('\n');
+    let appended = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .and_then(|mut file| file.write_all(line.as_bytes()));
+    if let Err(e) = appended {
+        error!("Unable to record run in {:?}: {}", path, e);
+        return;
+    }
+
+    let runs: Vec<RunReport> = load_runs(name);
+    if runs.len() > MAX_RUN_REPORTS {
+        let kept: String = runs[runs.len() - MAX_RUN_REPORTS..]
+            .iter()
+            .map(|report| serde_json::to_string(report).expect("Unable to serialize run report"))
+            .collect::<Vec<String>>()
+            .join("\n");
+        let temporary: PathBuf = path.with_extension("jsonl.tmp");
+        if let Err(e) =
+            fs::write(&temporary, kept + "\n").and_then(|_| fs::rename(&temporary, &path))
+        {
+            error!("Unable to trim run log {:?}: {}", path, e);
+        }
+    }
+}
+
+/// Loads the reports of a backup's past runs, oldest first. Lines which cannot be parsed, such as
+/// one cut short by a crash, are skipped.
+pub(crate) fn load_runs(name: &str) -> Vec<RunReport> {
+    fs::read_to_string(runs_path(name))
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect()
+}
+
+/// Prints the past runs of some backups, or every detail of one run, for `sandman history`.
+///
+/// # Arguments
+///
+/// * `names` - Names of the backups whose runs are listed.
+/// * `run` - ID, or start of the ID, of a run to show in detail instead of listing runs.
+/// * `limit` - Number of most recent runs listed per backup.
+/// * `json` - Whether to print JSON rather than a table.
+///
+/// # Returns
+///
+/// Whether the run asked for was found, always `true` when listing runs.
+pub(crate) fn print_history(names: &[String], run: Option<&str>, limit: usize, json: bool) -> bool {
+    let runs: Vec<(&str, RunReport)> = names
+        .iter()
+        .flat_map(|name| {
+            let reports: Vec<RunReport> = load_runs(name);
+            let skipped: usize = match run {
+                Some(_) => 0,
+                None => reports.len().saturating_sub(limit),
+            };
+            reports
+                .into_iter()
+                .skip(skipped)
+                .map(move |report| (name.as_str(), report))
+        })
+        .collect();
+
+    if let Some(run) = run {
+        let matching: Vec<&(&str, RunReport)> = runs
+            .iter()
+            .filter(|(_, report)| report.run_id.starts_with(run))
+            .collect();
+        return match matching.as_slice() {
+            [(backup, report)] => {
+                match json {
+                    true => println!(
+                        "{}",
+                        serde_json::to_string_pretty(&BackupRun { backup, report })
+                            .expect("Unable to serialize run report")
+                    ),
+                    false => print_run(backup, report),
+                }
+                true
+            }
+            [] => {
+                eprintln!("No run with ID {}", run);
+                false
+            }
+            _ => {
+                eprintln!("{} runs have IDs starting with {}", matching.len(), run);
+                false
+            }
+        };
+    }
+
+    if json {
+        let runs: Vec<BackupRun> = runs
+            .iter()
+            .map(|(backup, report)| BackupRun { backup, report })
+            .collect();
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&runs).expect("Unable to serialize run reports")
+        );
+    } else {
+        print_runs(&runs);
+    }
+    true
+}
+
+/// Prints the summary of `sandman run`: its runs as a table, why those which did not succeed
+/// failed, and how many succeeded.
+///
+/// # Arguments
+///
+/// * `runs` - Name of every backup run and the report of its run, or why it did not finish.
+///
+/// # Returns
+///
+/// Whether every run succeeded.
+pub(crate) fn print_summary(runs: &[(String, Result<RunReport, String>)]) -> bool {
+    let finished: Vec<(&str, RunReport)> = runs
+        .iter()
+        .filter_map(|(backup, report)| report.clone().ok().map(|report| (backup.as_str(), report)))
+        .collect();
+    if !finished.is_empty() {
+        print_runs(&finished);
+    }
+    let mut succeeded: usize = 0;
+    for (backup, report) in runs {
+        match report {
+            Ok(report) if report.outcome == RunOutcome::Success => succeeded += 1,
+            Ok(report) => match &report.error {
+                Some(error) => println!("{}: {}, {}", backup, report.outcome, error),
+                None => println!("{}: {}", backup, report.outcome),
+            },
+            Err(reason) => println!("{}: {}", backup, reason),
+        }
+    }
+    println!("{} of {} backups succeeded", succeeded, runs.len());
+    succeeded == runs.len()
+}
+
+/// Prints when files were last uploaded, as recorded in a backup's SQLite history.
+///
+/// # Arguments
+///
+/// * `gather_args` - `GatherArgs` of the backup the files belong to.
+/// * `paths` - Paths of the files, absolute or relative to the backup root.
+///
+/// # Returns
+///
+/// Whether every file is in the history.
+pub(crate) fn print_uploads(gather_args: &GatherArgs, paths: &[PathBuf]) -> bool {
+    let root: &Path = Path::new(&gather_args.local_directory);
+    let history: HistoryStore = match HistoryStore::open(gather_args) {
+        Ok(history) => history,
+        Err(e) => {
+            eprintln!("{}", e);
+            return false;
+        }
+    };
+    let mut found: bool = true;
+    for path in paths {
+        let Ok(relative) = root.join(path).strip_prefix(root).map(Path::to_path_buf) else {
+            eprintln!("{}: not inside {}", path.display(), root.display());
+            found = false;
+            continue;
+        };
+        match history.file_record(&encode_path(&relative)) {
+            Ok(Some(FileRecord {
+                snapshot: Some(snapshot),
+                uploaded_at: Some(uploaded_at),
+                ..
+            })) => println!(
+                "{}: uploaded {} in snapshot {}",
+                path.display(),
+                local_time(uploaded_at),
+                snapshot
+            ),
+            Ok(Some(_)) => println!(
+                "{}: backed up, carried over from an earlier history",
+                path.display()
+            ),
+            Ok(None) => {
+                println!("{}: not backed up", path.display());
+                found = false;
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                return false;
+            }
+        }
+    }
+    found
+}
+
+/// Prints runs as a table, most recent first.
+fn print_runs(runs: &[(&str, RunReport)]) {
+    let mut runs: Vec<&(&str, RunReport)> = runs.iter().collect();
+    runs.sort_by_key(|(_, report)| std::cmp::Reverse(report.started));
+    let headers: [&str; 10] = [
+        "BACKUP", "RUN", "STARTED", "DURATION", "OUTCOME", "SCANNED", "CHANGED", "UPLOADED",
+        "FAILED", "BYTES",
+    ];
+    let rows: Vec<[String; 10]> = [headers.map(String::from)]
+        .into_iter()
+        .chain(runs.iter().map(|(backup, report)| {
+            [
+                backup.to_string(),
+                report.run_id.chars().take(8).collect(),
+                local_time(report.started),
+                format!("{:.1}s", report.duration),
+                report.outcome.to_string(),
+                report.scanned.to_string(),
+                report.changed.to_string(),
+                report.uploaded.to_string(),
+                report.failed.to_string(),
+                report.bytes.to_string(),
+            ]
+        }))
+        .collect();
+    let widths: Vec<usize> = (0..headers.len())
+        .map(|column| rows.iter().map(|row| row[column].len()).max().unwrap_or(0))
+        .collect();
+    for row in &rows {
+        let cells: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect();
+        println!("{}", cells.join("  ").trim_end());
+    }
+}
+
+/// Prints every detail of one run.
+fn print_run(backup: &str, report: &RunReport) {
+    println!("Run:       {}", report.run_id);
+    println!("Backup:    {}", backup);
+    println!("Started:   {}", local_time(report.started));
+    println!("Finished:  {}", local_time(report.finished));
+    println!("Duration:  {:.1}s", report.duration);
+    println!("Outcome:   {}", report.outcome);
+    println!("Snapshot:  {}", report.snapshot.as_deref().unwrap_or("-"));
+    println!("Scanned:   {}", report.scanned);
+    println!("Changed:   {}", report.changed);
+    println!("Uploaded:  {}", report.uploaded);
+    println!("Failed:    {}", report.failed);
+    println!("Bytes:     {}", report.bytes);
+    if let Some(error) = &report.error {
+        println!("Error:     {}", error);
+    }
+    if !report.errors.is_empty() {
+        println!("Failed files:");
+        report
+            .errors
+            .iter()
+            .for_each(|error| println!("  {}", error));
+        if report.failed > report.errors.len() {
+            println!("  ... and {} more", report.failed - report.errors.len());
+        }
+    }
+}
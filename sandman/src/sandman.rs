@@ -1,131 +1,511 @@
-use crate::args::{Args, GatherArgs};
-use crate::gatherer::Gatherer;
-use clap::Parser;
-use ignore::gitignore::{Gitignore, GitignoreBuilder};
-use log::{error, info};
-use sandman_share::config::{AwsConfig, Config};
-use sandman_share::consts::{SANDMAN_CONFIG, SANDMAN_IGNORE};
-use sandman_share::paths::{file_in_config, verify_config_existence};
-use std::ffi::OsStr;
+use crate::args::{Args, Command, ConfigCommand, CtlCommand, GatherArgs, SelectionArgs};
+use crate::configuration::{check_config, print_config, validate, ConfigProblems};
+use crate::control::RunReport;
+use crate::ctl::ctl;
+use crate::daemon::Daemon;
+use crate::gatherer::{join_gatherers, Gatherer};
+use crate::logging::init_logging;
+use crate::preview::{check_ignore, dry_run, ls_files};
+use crate::remote::{print_snapshots, prune, verify};
+use crate::restore::{restore, RestoreRequest};
+use crate::runs::{print_history, print_summary, print_uploads};
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser};
+use sandman_share::config::{AwsConfig, Config, SandmanDirectory};
+use sandman_share::consts::{SANDMAN_CONFIG, SANDMAN_HISTORY};
+use sandman_share::paths::{
+    backup_state_dir, backup_state_path, control_socket, file_in_config, verify_config_existence,
+};
 use std::fs;
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, Mutex};
+use std::process::ExitCode;
+use std::time::Duration;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info};
 
-/// Creates a `Gitignore` file matcher from the provided path. If an error occurs it will
-/// return a default match accepting any and all files
+/// Exit code of a command which ran but did not succeed, such as a backup run which failed or a
+/// verification which found missing files.
+const EXIT_FAILURE: u8 = 1;
+
+/// Exit code of a configuration file which cannot be read or is invalid, or which lacks a backup
+/// asked for. Invalid arguments exit with `2`, as reported by clap.
+const EXIT_CONFIG: u8 = 3;
+
+/// Why a command did not succeed, deciding Sandman's exit code.
+enum Failure {
+    /// The command ran but did not succeed, having reported why.
+    Failed,
+
+    /// The configuration file cannot be used, for the reason held.
+    Config(String),
+}
+
+/// Resolves the location of the `.sandman_config.toml` file, `path` if it is a file and otherwise
+/// the system default.
+fn config_location(path: String) -> PathBuf {
+    let path: PathBuf = PathBuf::from(path);
+    if path.is_file() {
+        return path;
+    }
+    let default_file: PathBuf = file_in_config(SANDMAN_CONFIG);
+    info!(
+        "Unable to find `{}` checking system default `{:?}",
+        SANDMAN_CONFIG, default_file
+    );
+    default_file
+}
+
+/// Reads and parses a `.sandman_config.toml` file into a `Config` struct.
+///
+/// # Returns
+///
+/// The `Config`, or a description of why it could not be read or parsed.
+pub(crate) fn read_config(path: &Path) -> Result<Config, String> {
+    let config_string: String = fs::read_to_string(path)
+        .map_err(|e| format!("Error while processing {:?}: {}", path, e))?;
+    toml::from_str(&config_string)
+        .map_err(|e| format!("Error while processing {}: {}", SANDMAN_CONFIG, e))
+}
+
+/// Resolves the location of the control socket, `--control-socket` if given and otherwise the
+/// default in the state directory.
+fn socket_path(args: &Args) -> PathBuf {
+    args.control_socket.clone().unwrap_or_else(control_socket)
+}
+
+/// Creates the token cancelled once Sandman is asked to shut down by SIGINT or, on Unix, SIGTERM.
+/// A second signal exits immediately.
+fn shutdown_on_signal() -> CancellationToken {
+    let shutdown: CancellationToken = CancellationToken::new();
+    let token: CancellationToken = shutdown.clone();
+    tokio::spawn(async move {
+        let signal: &str = wait_for_signal().await;
+        info!("Received {}, finishing in-flight backups", signal);
+        token.cancel();
+        let signal: &str = wait_for_signal().await;
+        error!("Received {} again, exiting immediately", signal);
+        std::process::exit(130);
+    });
+    shutdown
+}
+
+/// Waits for the next shutdown signal, returning its name.
+#[cfg(unix)]
+async fn wait_for_signal() -> &'static str {
+    let mut terminate: Signal =
+        signal(SignalKind::terminate()).expect("Unable to listen for SIGTERM");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => "SIGINT",
+        _ = terminate.recv() => "SIGTERM",
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() -> &'static str {
+    let _ = tokio::signal::ctrl_c().await;
+    "Ctrl-C"
+}
+
+/// Builds the `GatherArgs` of a backup of a directory given on the command line rather than in
+/// the configuration file. It is named after a hash of the directory's canonical path, so every
+/// directory keeps its own history, run log and lock however its path is spelled.
 ///
 /// # Arguments
 ///
-/// * `path` - String of the path to the `.sandmanignore` file
+/// * `local_directory` - The directory to back up.
+/// * `selection` - Flags narrowing which files are included.
+fn local_gather_args(local_directory: &str, selection: &SelectionArgs) -> GatherArgs {
+    let name: String = local_backup_name(local_directory);
+    let history_path: PathBuf = backup_state_path(&name).join(SANDMAN_HISTORY);
+    let mut gather_args: GatherArgs = GatherArgs::new(
+        name,
+        local_directory.to_string(),
+        String::new(),
+        String::new(),
+        0,
+        0,
+        false,
+    );
+    gather_args.history_path = Some(history_path);
+    gather_args.wait_for_lock = false;
+    selection.apply(&mut gather_args);
+    gather_args
+}
+
+/// Returns the name of the backup of a directory given on the command line, `local-` followed by
+/// the start of the BLAKE3 hash of its canonical path.
+fn local_backup_name(local_directory: &str) -> String {
+    let path: PathBuf =
+        fs::canonicalize(local_directory).unwrap_or_else(|_| PathBuf::from(local_directory));
+    let hash = blake3::hash(path.as_os_str().as_encoded_bytes());
+    format!("local-{}", &hash.to_hex()[..16])
+}
+
+/// Moves the history of a directory given on the command line from where earlier versions kept
+/// it, a state directory named after the path as it was typed, to the state directory of its
+/// backup.
 ///
-/// # Returns
+/// # Arguments
 ///
-/// `Gitignore` - glob matcher
-pub(crate) fn get_ignore(dir: &String) -> Gitignore {
-    let path: &Path = Path::new(OsStr::new(&dir));
-    let file: PathBuf = path.join(SANDMAN_IGNORE);
-    let global_file: PathBuf = file_in_config(SANDMAN_IGNORE);
-
-    let mut builder: GitignoreBuilder = GitignoreBuilder::new(path);
-    builder.add(file);
-    builder.add(global_file);
-    builder.build().unwrap_or_else(|e| {
-        error!("Error processing ignore file: {}", e);
-        Gitignore::empty()
-    })
-}
-
-/// Use the `verbosity` parameter of the `args` struct to determine the level of our logger.
-fn set_loggers(verbosity: bool) {
-    if verbosity {
-        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("debug")).init();
-    } else {
-        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+/// * `local_directory` - The directory as given on the command line.
+/// * `gather_args` - `GatherArgs` of its backup.
+fn adopt_local_history(local_directory: &str, gather_args: &GatherArgs) {
+    let legacy: PathBuf = backup_state_path(local_directory).join(SANDMAN_HISTORY);
+    let location: PathBuf = backup_state_dir(&gather_args.name).join(SANDMAN_HISTORY);
+    if location.exists() || !legacy.is_file() {
+        return;
+    }
+    match fs::rename(&legacy, &location) {
+        Ok(()) => info!("Moved history {:?} to {:?}", legacy, location),
+        Err(e) => error!(
+            "Unable to move history {:?} to {:?}: {}",
+            legacy, location, e
+        ),
     }
 }
 
-/// Opens and processes the `sandman_config.toml` file into a `Config` struct
-fn get_config(path: String) -> Config {
-    let path: PathBuf = PathBuf::from(path);
-    let config_string: String = match path.is_file() {
-        true => fs::read_to_string(path)
-            .unwrap_or_else(|e| panic!("Error while processing .sandman_config.toml {}", e)),
-        false => {
-            let default_file: &PathBuf = &file_in_config(SANDMAN_CONFIG);
-            info!(
-                "Unable to find `{}` checking system default `{:?}",
-                SANDMAN_CONFIG, default_file
-            );
-            fs::read_to_string(default_file)
-                .unwrap_or_else(|e| panic!("Error while processing {:?}: {}", default_file, e))
-        }
-    };
-    toml::from_str(&config_string)
-        .unwrap_or_else(|e| panic!("Error while processing {}: {}", SANDMAN_CONFIG, e))
+/// Reads the configuration file and checks it is usable.
+///
+/// # Returns
+///
+/// The location of the file and the `Config` read from it.
+fn load_config(args: &Args) -> Result<(PathBuf, Config), Failure> {
+    let path: PathBuf = config_location(args.config_path.clone());
+    let config: Config = read_config(&path)
+        .and_then(validate)
+        .map_err(Failure::Config)?;
+    Ok((path, config))
 }
 
-/// Runs Sandman with an external `.sandman_config.toml` file passed with `Args`. If the path does
-/// not exist or was left blank it will check in the default system location. In the case of the
-/// directory or file not existing it will be created and the application will exit.
+/// Finds the `GatherArgs` of configured backups by name.
 ///
 /// # Arguments
 ///
-/// * `args` - `Args` used to get the configuration path
-async fn with_external_config(args: &Args) {
-    verify_config_existence();
-    let exit: Option<Arc<Mutex<bool>>> = Some(Arc::new(Mutex::new(false)));
-    let config: Config = get_config(args.config_path.clone());
-    let mut gatherers: Vec<Gatherer> = vec![];
-
-    for directory in config.directories.backups {
-        let aws_config: AwsConfig = config.aws.clone();
-        let gather_args: GatherArgs = GatherArgs::new(
-            directory.name,
-            directory.directory,
-            directory.bucket,
-            directory.prefix,
-            directory.interval,
-            directory.start_time,
-            directory.cleanable,
-        );
-
-        gatherers.push(Gatherer::new(gather_args, Some(aws_config)));
-        let len: usize = gatherers.len() - 1;
-        let gatherer: &mut Gatherer = &mut gatherers[len];
-        let _ = &gatherer.gather(false, exit.clone());
+/// * `config` - The `Config` holding the backups.
+/// * `names` - Names of the backups, every configured backup when empty.
+///
+/// # Returns
+///
+/// The `GatherArgs` in the order of `names`, or a `Failure::Config` naming a backup which is not
+/// configured.
+fn configured_backups(config: &Config, names: &[String]) -> Result<Vec<GatherArgs>, Failure> {
+    let backups: &[SandmanDirectory] = &config.directories.backups;
+    if names.is_empty() {
+        return Ok(backups
+            .iter()
+            .map(|directory| GatherArgs::configured(directory.clone(), &config.notifications))
+            .collect());
     }
-    for gatherer in gatherers {
-        gatherer.handle.unwrap().await.expect("TODO: panic message");
+    names
+        .iter()
+        .map(|name| {
+            backups
+                .iter()
+                .find(|directory| directory.name == *name)
+                .map(|directory| GatherArgs::configured(directory.clone(), &config.notifications))
+                .ok_or_else(|| {
+                    Failure::Config(format!("No backup named {} in {}", name, SANDMAN_CONFIG))
+                })
+        })
+        .collect()
+}
+
+/// Finds the `GatherArgs` of one configured backup by name.
+fn configured_backup(args: &Args, name: &str) -> Result<(Config, GatherArgs), Failure> {
+    let (_, config): (PathBuf, Config) = load_config(args)?;
+    let mut backups: Vec<GatherArgs> = configured_backups(&config, &[name.to_string()])?;
+    let gather_args: GatherArgs = backups.remove(0);
+    Ok((config, gather_args))
+}
+
+/// Resolves the backup a preview command applies to, a named backup from the configuration file or
+/// otherwise the directory given on the command line.
+fn preview_gather_args(
+    args: &Args,
+    name: &Option<String>,
+    local_directory: &Option<String>,
+    selection: &SelectionArgs,
+) -> Result<GatherArgs, Failure> {
+    match (name, local_directory) {
+        (Some(name), _) => configured_backup(args, name).map(|(_, gather_args)| gather_args),
+        (None, Some(local_directory)) => Ok(local_gather_args(local_directory, selection)),
+        // Clap requires one of them
+        (None, None) => unreachable!("--name or --local-directory is required"),
     }
 }
 
-/// Runs Sandman with provided CLI arguments in the form of an `Args` struct
+/// Runs configured backups, or a directory given on the command line, once. Runs can be
+/// interrupted like the daemon, giving in-flight uploads `shutdown_timeout` seconds to finish.
 ///
 /// # Arguments
 ///
-/// * `args` - `Args` built from the CLI parameters
-async fn with_cli_args(args: &Args) {
-    let gather_args: GatherArgs = GatherArgs::new(
-        "OneShotter".to_string(),
-        args.local_directory.clone(),
-        args.s3_bucket.clone(),
-        args.bucket_prefix.clone(),
-        0,
-        0,
-        false,
-    );
-    let mut gatherer: Gatherer = Gatherer::new(gather_args, None);
-    gatherer.gather(true, None);
-    let _ = &gatherer.handle.unwrap().await;
+/// * `args` - `Args` built from the CLI parameters, holding a `Command::Run`.
+async fn run_backups(args: &Args) -> Result<(), Failure> {
+    let Command::Run {
+        names,
+        local_directory,
+        s3_bucket,
+        bucket_prefix,
+        sha_file,
+        hash_algorithm,
+        selection,
+        wait_for_lock,
+        dry_run: dry,
+        shutdown_timeout,
+    } = &args.command
+    else {
+        unreachable!("run_backups is only called for `sandman run`");
+    };
+
+    let (backups, aws): (Vec<GatherArgs>, Option<AwsConfig>) = match local_directory {
+        Some(local_directory) => {
+            if s3_bucket.is_none() && !dry {
+                Args::command()
+                    .error(
+                        ErrorKind::MissingRequiredArgument,
+                        "--s3-bucket is required to back up a --local-directory",
+                    )
+                    .exit();
+            }
+            let mut gather_args: GatherArgs = local_gather_args(local_directory, selection);
+            gather_args.bucket = s3_bucket.clone().unwrap_or_default();
+            gather_args.bucket_prefix = bucket_prefix.clone();
+            gather_args.hash_algorithm = hash_algorithm.unwrap_or_default();
+            match sha_file {
+                Some(sha_file) => gather_args.history_path = Some(sha_file.clone()),
+                None if !dry => adopt_local_history(local_directory, &gather_args),
+                None => {}
+            }
+            (vec![gather_args], None)
+        }
+        None => {
+            let (_, config): (PathBuf, Config) = load_config(args)?;
+            (configured_backups(&config, names)?, Some(config.aws))
+        }
+    };
+
+    if *dry {
+        backups.iter().for_each(dry_run);
+        return Ok(());
+    }
+    let shutdown: CancellationToken = shutdown_on_signal();
+    let gatherers: Vec<Gatherer> = backups
+        .into_iter()
+        .map(|mut gather_args| {
+            gather_args.wait_for_lock = *wait_for_lock;
+            let mut gatherer: Gatherer = Gatherer::new(gather_args, aws.clone());
+            gatherer.gather(true, shutdown.clone());
+            gatherer
+        })
+        .collect();
+    let timeout: Duration = Duration::from_secs(*shutdown_timeout);
+    let runs: Vec<(String, Result<RunReport, String>)> =
+        join_gatherers(gatherers, &shutdown, timeout)
+            .await
+            .into_iter()
+            .map(|(name, result)| {
+                let report: Result<RunReport, String> = match result {
+                    Ok(Some(report)) => Ok(report),
+                    Ok(None) => Err("did not finish".to_string()),
+                    Err(e) => Err(format!("did not finish, {}", e)),
+                };
+                (name, report)
+            })
+            .collect();
+    succeeded(print_summary(&runs))
+}
+
+/// Runs `sandman config`.
+fn config_command(args: &Args, command: &ConfigCommand) -> Result<(), Failure> {
+    let path: PathBuf = config_location(args.config_path.clone());
+    match command {
+        ConfigCommand::Path => {
+            println!("{}", path.display());
+            Ok(())
+        }
+        ConfigCommand::Show => {
+            let config: Config = read_config(&path).map_err(Failure::Config)?;
+            print_config(&config, &path);
+            Ok(())
+        }
+        ConfigCommand::Check => {
+            let config: Config = read_config(&path).map_err(Failure::Config)?;
+            let problems: ConfigProblems = check_config(&config);
+            for error in &problems.errors {
+                println!("error: {}", error);
+            }
+            for warning in &problems.warnings {
+                println!("warning: {}", warning);
+            }
+            match problems.errors.len() {
+                0 => {
+                    println!(
+                        "{} is valid, {} backups, {} warnings",
+                        path.display(),
+                        config.directories.backups.len(),
+                        problems.warnings.len()
+                    );
+                    Ok(())
+                }
+                errors => Err(Failure::Config(format!(
+                    "{} has {} errors",
+                    path.display(),
+                    errors
+                ))),
+            }
+        }
+    }
+}
+
+/// Runs the command given on the command line.
+async fn run_command(args: &Args) -> Result<(), Failure> {
+    match &args.command {
+        Command::Daemon { shutdown_timeout } => {
+            // A missing default configuration is created for the user to fill in
+            if !Path::new(&args.config_path).is_file() {
+                verify_config_existence();
+            }
+            let (config_path, config): (PathBuf, Config) = load_config(args)?;
+            let timeout: Duration = Duration::from_secs(*shutdown_timeout);
+            Daemon::new(
+                config_path,
+                config,
+                socket_path(args),
+                shutdown_on_signal(),
+                timeout,
+            )
+            .run()
+            .await;
+            Ok(())
+        }
+        Command::Run { .. } => run_backups(args).await,
+        Command::Restore {
+            name,
+            snapshot,
+            to,
+            path,
+            overwrite,
+        } => {
+            let (config, gather_args): (Config, GatherArgs) = configured_backup(args, name)?;
+            let request: RestoreRequest = RestoreRequest {
+                snapshot: snapshot.as_deref(),
+                to,
+                paths: path,
+                overwrite: *overwrite,
+            };
+            match restore(&gather_args, &Some(config.aws), &request).await {
+                Ok(restored) => succeeded(restored),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    Err(Failure::Failed)
+                }
+            }
+        }
+        Command::Snapshots { name, json } => {
+            let (config, gather_args): (Config, GatherArgs) = configured_backup(args, name)?;
+            print_snapshots(&gather_args, &Some(config.aws), *json)
+                .await
+                .map_err(|e| {
+                    eprintln!("{}", e);
+                    Failure::Failed
+                })
+        }
+        Command::Verify { names } => {
+            let (_, config): (PathBuf, Config) = load_config(args)?;
+            let aws: Option<AwsConfig> = Some(config.aws.clone());
+            let mut verified: bool = true;
+            for gather_args in configured_backups(&config, names)? {
+                verified &= verify(&gather_args, &aws).await;
+            }
+            succeeded(verified)
+        }
+        Command::Prune {
+            name,
+            keep,
+            dry_run,
+        } => {
+            let (config, gather_args): (Config, GatherArgs) = configured_backup(args, name)?;
+            prune(&gather_args, &Some(config.aws), *keep as usize, *dry_run)
+                .await
+                .map_err(|e| {
+                    eprintln!("{}", e);
+                    Failure::Failed
+                })
+        }
+        Command::Status { json } => {
+            succeeded(ctl(&socket_path(args), &CtlCommand::Status { json: *json }))
+        }
+        Command::Config { command } => config_command(args, command),
+        Command::LsFiles {
+            name,
+            local_directory,
+            selection,
+        } => {
+            ls_files(&preview_gather_args(
+                args,
+                name,
+                local_directory,
+                selection,
+            )?);
+            Ok(())
+        }
+        Command::CheckIgnore {
+            name,
+            local_directory,
+            selection,
+            paths,
+        } => {
+            let gather_args: GatherArgs =
+                preview_gather_args(args, name, local_directory, selection)?;
+            succeeded(check_ignore(&gather_args, paths))
+        }
+        Command::History {
+            name,
+            run,
+            limit,
+            json,
+            file,
+        } => {
+            let (_, config): (PathBuf, Config) = load_config(args)?;
+            if !file.is_empty() {
+                let backups: Vec<GatherArgs> = configured_backups(&config, name.as_slice())?;
+                return succeeded(backups.iter().all(|backup| print_uploads(backup, file)));
+            }
+            let names: Vec<String> = configured_backups(&config, name.as_slice())?
+                .into_iter()
+                .map(|gather_args| gather_args.name)
+                .collect();
+            succeeded(print_history(&names, run.as_deref(), *limit, *json))
+        }
+        Command::Ctl { command } => succeeded(ctl(&socket_path(args), command)),
+    }
+}
+
+/// Turns whether a command succeeded into its result.
+fn succeeded(success: bool) -> Result<(), Failure> {
+    match success {
+        true => Ok(()),
+        false => Err(Failure::Failed),
+    }
 }
 
 /// Main entry point for running the Sandman application.
-pub(crate) async fn run_sandman() {
+///
+/// # Returns
+///
+/// The exit code: `0` on success, `1` when the command did not succeed, such as a failed backup
+/// run, `2` for invalid arguments and `3` when the configuration file cannot be used.
+pub(crate) async fn run_sandman() -> ExitCode {
     let args = Args::parse();
-    set_loggers(args.verbosity);
-    if args.with_config {
-        with_external_config(&args).await;
-    } else {
-        with_cli_args(&args).await;
+    init_logging(args.verbosity, args.log_format, args.log_files);
+    match run_command(&args).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(Failure::Failed) => ExitCode::from(EXIT_FAILURE),
+        Err(Failure::Config(e)) => {
+            eprintln!("{}", e);
+            ExitCode::from(EXIT_CONFIG)
+        }
     }
 }
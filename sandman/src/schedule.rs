@@ -0,0 +1,281 @@
+use crate::args::GatherArgs;
+use chrono::{DateTime, Local, TimeZone, Utc};
+use chrono_tz::Tz;
+use croner::Cron;
+
+/// Calendar shorthands accepted in place of a cron expression.
+const CALENDAR_SHORTHANDS: [&str; 6] =
+    ["hourly", "daily", "weekly", "monthly", "yearly", "annually"];
+
+/// When a gatherer runs its backups.
+pub(crate) enum Schedule {
+    /// Every `interval` seconds after the previous run, the shorthand of a backup without a
+    /// `schedule`.
+    Interval { interval: u64, start_time: u64 },
+
+    /// At the times matching a cron expression, evaluated in `time_zone` or the local time zone.
+    Cron {
+        cron: Box<Cron>,
+        time_zone: Option<Tz>,
+        start_time: u64,
+    },
+}
+
+impl Schedule {
+    /// Creates the schedule of a backup, its `schedule` expression if one was given and its
+    /// `interval` otherwise. In both cases no run happens before `start_time`.
+    ///
+    /// # Arguments
+    ///
+    /// * `gather_args` - `GatherArgs` of the backup.
+    ///
+    /// # Returns
+    ///
+    /// The `Schedule`, or a description of why the expression or time zone is invalid.
+    pub(crate) fn new(gather_args: &GatherArgs) -> Result<Self, String> {
+        let expression: &String = match &gather_args.schedule {
+            Some(expression) => expression,
+            None => {
+                return Ok(Schedule::Interval {
+                    interval: gather_args.interval,
+                    start_time: gather_args.start_time,
+                })
+            }
+        };
+        let time_zone: Option<Tz> = match &gather_args.time_zone {
+            Some(name) => Some(
+                name.parse()
+                    .map_err(|_| format!("Unknown time zone: {}", name))?,
+            ),
+            None => None,
+        };
+        let cron: Cron = Cron::new(&cron_pattern(expression)?)
+            .with_seconds_optional()
+            .parse()
+            .map_err(|e| format!("Invalid schedule {:?}: {}", expression, e))?;
+        Ok(Schedule::Cron {
+            cron: Box::new(cron),
+            time_zone,
+            start_time: gather_args.start_time,
+        })
+    }
+
+    /// Finds the time of the next run. Runs missed while Sandman was not running are caught up on
+    /// straight away.
+    ///
+    /// # Arguments
+    ///
+    /// * `last_time` - Time of the previous run in seconds since the Unix epoch, `0` if there was none.
+    ///
+    /// # Returns
+    ///
+    /// The time of the next run, `None` if the expression never matches again.
+    pub(crate) fn next_run(&self, last_time: u64) -> Option<DateTime<Utc>> {
+        let (next, start_time): (DateTime<Utc>, u64) = match self {
+            Schedule::Interval {
+                interval,
+                start_time,
+            } => (
+                from_timestamp(last_time.saturating_add(*interval)),
+                *start_time,
+            ),
+            Schedule::Cron {
+                cron,
+                time_zone,
+                start_time,
+            } => {
+                let after: DateTime<Utc> = from_timestamp(last_time);
+                let next: DateTime<Utc> = match time_zone {
+                    Some(time_zone) => cron
+                        .find_next_occurrence(&after.with_timezone(time_zone), false)
+                        .ok()?
+                        .with_timezone(&Utc),
+                    None => cron
+                        .find_next_occurrence(&after.with_timezone(&Local), false)
+                        .ok()?
+                        .with_timezone(&Utc),
+                };
+                (next, *start_time)
+            }
+        };
+        Some(next.max(from_timestamp(start_time)))
+    }
+
+    /// Formats `time` in the time zone of the schedule, for logging.
+    pub(crate) fn display(&self, time: DateTime<Utc>) -> String {
+        match self {
+            Schedule::Cron {
+                time_zone: Some(time_zone),
+                ..
+            } => time
+                .with_timezone(time_zone)
+                .format("%Y-%m-%d %H:%M:%S %Z")
+                .to_string(),
+            _ => time
+                .with_timezone(&Local)
+                .format("%Y-%m-%d %H:%M:%S %:z")
+                .to_string(),
+        }
+    }
+}
+
+/// Converts seconds since the Unix epoch into a `DateTime`, clamping values out of range.
+fn from_timestamp(seconds: u64) -> DateTime<Utc> {
+    Utc.timestamp_opt(seconds.min(i64::MAX as u64) as i64, 0)
+        .single()
+        .unwrap_or(DateTime::<Utc>::MAX_UTC)
+}
+
+/// Translates a schedule expression into a cron pattern. Besides cron expressions, with optional
+/// seconds and `@daily` style nicknames, two calendar forms are accepted: the shorthands `hourly`,
+/// `daily`, `weekly`, `monthly` and `yearly`, and a time of day optionally preceded by weekdays,
+/// such as `02:30`, `Mon..Fri 18:00` or `Sat,Sun 09:15:30`.
+///
+/// # Returns
+///
+/// The cron pattern, or a description of why the time of day is invalid.
+fn cron_pattern(expression: &str) -> Result<String, String> {
+    let expression: &str = expression.trim();
+    if CALENDAR_SHORTHANDS.contains(&expression.to_lowercase().as_str()) {
+        return Ok(format!("@{}", expression));
+    }
+
+    let (days, time): (&str, &str) = match expression.rsplit_once(char::is_whitespace) {
+        Some((days, time)) if time.contains(':') => (days.trim(), time),
+        None if expression.contains(':') => ("*", expression),
+        _ => return Ok(expression.to_string()),
+    };
+    let fields: Vec<&str> = time.split(':').collect();
+    let (hour, minute, second): (&str, &str, &str) = match fields.as_slice() {
+        [hour, minute] => (hour, minute, "0"),
+        [hour, minute, second] => (hour, minute, second),
+        _ => return Err(format!("Invalid time of day: {}", time)),
+    };
+    let days: String = days.replace("..", "-").replace(' ', "");
+    Ok(format!("{} {} {} * * {}", second, minute, hour, days))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sandman_share::config::SandmanDirectory;
+
+    /// Returns `GatherArgs` of a backup running every hour, or on `schedule` in `time_zone`.
+    fn gather_args(schedule: Option<&str>, time_zone: Option<&str>, start_time: u64) -> GatherArgs {
+        let directory: SandmanDirectory = toml::from_str(&format!(
+            r#"
+            name = "Documents"
+            directory = "/tmp/documents"
+            interval = 3600
+            start_time = {}
+            prefix = "documents"
+            bucket = "bucket"
+            cleanable = false
+            "#,
+            start_time
+        ))
+        .unwrap();
+        GatherArgs {
+            schedule: schedule.map(str::to_string),
+            time_zone: time_zone.map(str::to_string),
+            ..GatherArgs::from(directory)
+        }
+    }
+
+    fn timestamp(text: &str) -> u64 {
+        DateTime::parse_from_rfc3339(text).unwrap().timestamp() as u64
+    }
+
+    fn time(text: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(text)
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    #[test]
+    fn calendar_shorthands_become_nicknames() {
+        assert_eq!(cron_pattern("daily").unwrap(), "@daily");
+        assert_eq!(cron_pattern(" weekly ").unwrap(), "@weekly");
+        assert_eq!(cron_pattern("Hourly").unwrap(), "@Hourly");
+    }
+
+    #[test]
+    fn times_of_day_become_cron_patterns() {
+        assert_eq!(cron_pattern("02:30").unwrap(), "0 30 02 * * *");
+        assert_eq!(
+            cron_pattern("Mon..Fri 18:00").unwrap(),
+            "0 00 18 * * Mon-Fri"
+        );
+        assert_eq!(
+            cron_pattern("Sat,Sun 09:15:30").unwrap(),
+            "30 15 09 * * Sat,Sun"
+        );
+    }
+
+    #[test]
+    fn cron_expressions_pass_through() {
+        assert_eq!(cron_pattern("*/15 * * * *").unwrap(), "*/15 * * * *");
+        assert_eq!(cron_pattern("@monthly").unwrap(), "@monthly");
+    }
+
+    #[test]
+    fn malformed_times_of_day_are_rejected() {
+        assert!(cron_pattern("1:2:3:4").is_err());
+        assert!(cron_pattern("Mon 12:00:00:00").is_err());
+    }
+
+    #[test]
+    fn invalid_expressions_and_time_zones_are_rejected() {
+        assert!(Schedule::new(&gather_args(Some("61 * * * *"), None, 0)).is_err());
+        assert!(Schedule::new(&gather_args(Some("25:00"), None, 0)).is_err());
+        assert!(Schedule::new(&gather_args(Some("daily"), Some("Mars/Olympus"), 0)).is_err());
+    }
+
+    #[test]
+    fn interval_runs_after_the_previous_run() {
+        let schedule: Schedule = Schedule::new(&gather_args(None, None, 0)).unwrap();
+        let last: u64 = timestamp("2026-10-19T08:00:00Z");
+        assert_eq!(schedule.next_run(last), Some(time("2026-10-19T09:00:00Z")));
+    }
+
+    #[test]
+    fn no_run_happens_before_the_start_time() {
+        let start: u64 = timestamp("2026-11-01T00:00:00Z");
+        let interval: Schedule = Schedule::new(&gather_args(None, None, start)).unwrap();
+        assert_eq!(interval.next_run(0), Some(time("2026-11-01T00:00:00Z")));
+
+        let cron: Schedule =
+            Schedule::new(&gather_args(Some("daily"), Some("UTC"), start)).unwrap();
+        let last: u64 = timestamp("2026-10-19T08:00:00Z");
+        assert_eq!(cron.next_run(last), Some(time("2026-11-01T00:00:00Z")));
+    }
+
+    #[test]
+    fn times_of_day_are_evaluated_in_the_time_zone() {
+        let schedule: Schedule =
+            Schedule::new(&gather_args(Some("02:30"), Some("Europe/Berlin"), 0)).unwrap();
+        let last: u64 = timestamp("2026-10-19T00:00:00Z");
+        assert_eq!(schedule.next_run(last), Some(time("2026-10-19T00:30:00Z")));
+        assert_eq!(
+            schedule.display(time("2026-10-19T00:30:00Z")),
+            "2026-10-19 02:30:00 CEST"
+        );
+    }
+
+    #[test]
+    fn weekdays_restrict_the_days_of_runs() {
+        let schedule: Schedule =
+            Schedule::new(&gather_args(Some("Sat,Sun 09:00"), Some("UTC"), 0)).unwrap();
+        // 2026-10-19 is a Monday.
+        let last: u64 = timestamp("2026-10-19T12:00:00Z");
+        assert_eq!(schedule.next_run(last), Some(time("2026-10-24T09:00:00Z")));
+    }
+
+    #[test]
+    fn missed_runs_are_caught_up_on() {
+        let schedule: Schedule =
+            Schedule::new(&gather_args(Some("hourly"), Some("UTC"), 0)).unwrap();
+        let last: u64 = timestamp("2020-01-01T00:30:00Z");
+        assert_eq!(schedule.next_run(last), Some(time("2020-01-01T01:00:00Z")));
+    }
+}
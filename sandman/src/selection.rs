@@ -0,0 +1,827 @@
+use crate::args::GatherArgs;
+use crate::entry::{device_of, EntryKind};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use ignore::gitignore::{Gitignore, GitignoreBuilder, Glob};
+use ignore::Match;
+use sandman_share::config::{SpecialFilePolicy, SymlinkPolicy};
+use sandman_share::consts::SANDMAN_IGNORE;
+use sandman_share::paths::file_in_config;
+use std::fs::{self, File, Metadata};
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tracing::{debug, error, info};
+
+/// Name of the marker file identifying cache directories, see <https://bford.info/cachedir/>.
+const CACHEDIR_TAG: &str = "CACHEDIR.TAG";
+
+/// Header every valid `CACHEDIR.TAG` file starts with.
+const CACHEDIR_SIGNATURE: &[u8] = b"Signature: 8a477f597d28d172789f06886806bc55";
+
+/// Name of the git ignore files optionally honoured alongside `.sandmanignore`.
+const GIT_IGNORE: &str = ".gitignore";
+
+/// Ignore rules loaded from the ignore files of a single directory of the walk, linked to the
+/// scope of its parent directory.
+pub(crate) struct IgnoreScope<'a> {
+    parent: Option<&'a IgnoreScope<'a>>,
+    /// Matchers of this directory, highest precedence first.
+    matchers: Vec<Gitignore>,
+}
+
+/// Describes the rule or filter deciding whether a path is part of a backup.
+pub(crate) struct Explanation {
+    /// The ignore file the rule was read from, or a description of the filter.
+    pub(crate) source: String,
+    /// Line of the rule in `source`, if it came from an ignore file.
+    pub(crate) line: Option<usize>,
+    /// The rule itself.
+    pub(crate) pattern: String,
+    /// Whether the path is left out of the backup.
+    pub(crate) excluded: bool,
+}
+
+impl Explanation {
+    fn from_glob(glob: &Glob, excluded: bool) -> Self {
+        let source: Option<&Path> = glob.from();
+        Explanation {
+            source: source
+                .map(|source| source.display().to_string())
+                .unwrap_or_default(),
+            line: source.and_then(|source| find_line(source, glob.original())),
+            pattern: glob.original().to_string(),
+            excluded,
+        }
+    }
+
+    fn filter(source: &str, pattern: String) -> Self {
+        Explanation {
+            source: source.to_string(),
+            line: None,
+            pattern,
+            excluded: true,
+        }
+    }
+}
+
+/// Decides which files of a backup are selected while walking its directory.
+pub(crate) struct Selection {
+    root: PathBuf,
+    /// The global `.sandmanignore` from the configuration directory, rooted at the backup root.
+    global: Gitignore,
+    honor_gitignore: bool,
+    honor_cachedir_tag: bool,
+    /// Include globs without a `/`, matched against file names.
+    include_names: Option<GlobSet>,
+    /// Include globs containing a `/`, matched against paths relative to the root.
+    include_paths: Option<GlobSet>,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    min_age: Option<Duration>,
+    max_age: Option<Duration>,
+    symlinks: SymlinkPolicy,
+    special_files: SpecialFilePolicy,
+    one_file_system: bool,
+    /// Device of the root, entries on other devices are skipped when `one_file_system` is set.
+    root_device: Option<u64>,
+}
+
+impl Selection {
+    /// Creates the selection of a backup.
+    ///
+    /// # Arguments
+    ///
+    /// * `gather_args` - `GatherArgs` of the backup, locating its root and the honoured markers.
+    pub(crate) fn new(gather_args: &GatherArgs) -> Self {
+        let root: &Path = Path::new(&gather_args.local_directory);
+        let (paths, names): (Vec<&String>, Vec<&String>) = gather_args
+            .include
+            .iter()
+            .partition(|pattern| pattern.trim_start_matches('/').contains('/'));
+        Selection {
+            root: root.to_path_buf(),
+            global: build_matcher(root, &file_in_config(SANDMAN_IGNORE))
+                .unwrap_or_else(Gitignore::empty),
+            honor_gitignore: gather_args.honor_gitignore,
+            honor_cachedir_tag: gather_args.honor_cachedir_tag,
+            include_names: build_globs(&names),
+            include_paths: build_globs(&paths),
+            min_size: gather_args.min_size,
+            max_size: gather_args.max_size,
+            min_age: gather_args.min_age.map(Duration::from_secs),
+            max_age: gather_args.max_age.map(Duration::from_secs),
+            symlinks: gather_args.symlinks,
+            special_files: gather_args.special_files,
+            one_file_system: gather_args.one_file_system,
+            root_device: fs::metadata(root)
+                .ok()
+                .and_then(|metadata| device_of(&metadata)),
+        }
+    }
+
+    /// Walks `directory` recursively, calling `visit` with the path and metadata of every selected
+    /// entry. Ignored directories are not descended into. Symbolic links are stored, followed or
+    /// skipped according to the symlink policy, stored links being visited with the metadata of
+    /// the link itself.
+    ///
+    /// # Arguments
+    ///
+    /// * `directory` - The directory to walk.
+    /// * `visit` - Called for every selected entry.
+    pub(crate) fn walk(&self, directory: &Path, visit: &mut dyn FnMut(&Path, &Metadata)) {
+        let mut ancestors: Vec<PathBuf> = fs::canonicalize(directory).into_iter().collect();
+        self.walk_below(directory, None, &mut ancestors, visit);
+    }
+
+    /// Walks a single directory of the walk started by `walk`.
+    ///
+    /// # Arguments
+    ///
+    /// * `directory` - The directory to walk.
+    /// * `parent_scope` - Ignore rules of the parent directories, `None` when walking the root.
+    /// * `ancestors` - Canonical paths of the directories being walked, used to detect loops
+    ///   when following symbolic links.
+    /// * `visit` - Called for every selected entry.
+    fn walk_below(
+        &self,
+        directory: &Path,
+        parent_scope: Option<&IgnoreScope>,
+        ancestors: &mut Vec<PathBuf>,
+        visit: &mut dyn FnMut(&Path, &Metadata),
+    ) {
+        let paths = match fs::read_dir(directory) {
+            Ok(paths) => paths,
+            Err(e) => {
+                error!("Unable to open directory: {}", e);
+                return;
+            }
+        };
+        let scope: IgnoreScope = self.scope(directory, parent_scope);
+
+        for entry in paths {
+            let path: PathBuf = match entry {
+                Ok(entry) => entry.path(),
+                Err(e) => {
+                    error!("Error reading directory entry: {}", e);
+                    continue;
+                }
+            };
+            debug!(path = %path.display(), "Visiting");
+
+            let metadata: Metadata = match self.admit(&scope, &path) {
+                Some(metadata) => metadata,
+                None => continue,
+            };
+            if metadata.is_dir() {
+                self.enter(&path, &scope, ancestors, visit);
+                continue;
+            }
+            self.visit_file(&path, &metadata, visit);
+        }
+    }
+
+    /// Walks only `path` and what lies below it, applying the ignore rules of every directory
+    /// between the root and `path`. Paths which no longer exist are skipped.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - A path inside the root.
+    /// * `visit` - Called for every selected entry.
+    pub(crate) fn walk_path(&self, path: &Path, visit: &mut dyn FnMut(&Path, &Metadata)) {
+        if fs::symlink_metadata(path).is_err() {
+            debug!(path = %path.display(), "Skipping removed path");
+            return;
+        }
+        let relative: &Path = match path.strip_prefix(&self.root) {
+            Ok(relative) => relative,
+            Err(_) => return,
+        };
+        let components: Vec<&std::ffi::OsStr> = relative.iter().collect();
+        if components.is_empty() {
+            return self.walk(&self.root, visit);
+        }
+        let mut ancestors: Vec<PathBuf> = fs::canonicalize(&self.root).into_iter().collect();
+        self.walk_path_below(&self.root, &components, None, &mut ancestors, visit);
+    }
+
+    /// Descends towards the last of `components`, one directory of the walk started by `walk_path`
+    /// at a time.
+    fn walk_path_below(
+        &self,
+        directory: &Path,
+        components: &[&std::ffi::OsStr],
+        parent_scope: Option<&IgnoreScope>,
+        ancestors: &mut Vec<PathBuf>,
+        visit: &mut dyn FnMut(&Path, &Metadata),
+    ) {
+        let scope: IgnoreScope = self.scope(directory, parent_scope);
+        let path: PathBuf = directory.join(components[0]);
+        let metadata: Metadata = match self.admit(&scope, &path) {
+            Some(metadata) => metadata,
+            None => return,
+        };
+
+        match (components.len(), metadata.is_dir()) {
+            (1, true) => self.enter(&path, &scope, ancestors, visit),
+            (1, false) => self.visit_file(&path, &metadata, visit),
+            (_, true) => {
+                self.walk_path_below(&path, &components[1..], Some(&scope), ancestors, visit)
+            }
+            (_, false) => {}
+        }
+    }
+
+    /// Reads the metadata of an entry of a directory being walked, unless the entry is ignored,
+    /// skipped by the symlink policy or on another filesystem.
+    fn admit(&self, scope: &IgnoreScope, path: &Path) -> Option<Metadata> {
+        let metadata: Metadata = self.entry_metadata(path)?;
+        if self.is_ignored(scope, path, metadata.is_dir()) {
+            return None;
+        }
+        if self.one_file_system && device_of(&metadata) != self.root_device {
+            debug!(path = %path.display(), "Skipping path on another filesystem");
+            return None;
+        }
+        Some(metadata)
+    }
+
+    /// Visits an entry which is not a directory, unless it is a skipped special file or filtered out.
+    fn visit_file(
+        &self,
+        path: &Path,
+        metadata: &Metadata,
+        visit: &mut dyn FnMut(&Path, &Metadata),
+    ) {
+        if EntryKind::of(metadata).is_special() && self.special_files == SpecialFilePolicy::Skip {
+            debug!(path = %path.display(), "Skipping special file");
+            return;
+        }
+        if self.is_selected(path, metadata) {
+            visit(path, metadata);
+        }
+    }
+
+    /// Reads the metadata an entry is walked with, following symbolic links only when the symlink
+    /// policy says so.
+    ///
+    /// # Returns
+    ///
+    /// The `Metadata`, or `None` if the entry is skipped or its metadata could not be read.
+    fn entry_metadata(&self, path: &Path) -> Option<Metadata> {
+        let metadata: Metadata = match fs::symlink_metadata(path) {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                error!(path = %path.display(), "Error while reading metadata: {}", e);
+                return None;
+            }
+        };
+        if !metadata.file_type().is_symlink() {
+            return Some(metadata);
+        }
+        match self.symlinks {
+            SymlinkPolicy::Store => Some(metadata),
+            SymlinkPolicy::Skip => {
+                debug!(path = %path.display(), "Skipping symlink");
+                None
+            }
+            SymlinkPolicy::Follow => match fs::metadata(path) {
+                Ok(target) => Some(target),
+                Err(e) => {
+                    error!(path = %path.display(), "Unable to follow symlink: {}", e);
+                    None
+                }
+            },
+        }
+    }
+
+    /// Descends into `directory`, unless following symbolic links led back into a directory which
+    /// is already being walked.
+    fn enter(
+        &self,
+        directory: &Path,
+        scope: &IgnoreScope,
+        ancestors: &mut Vec<PathBuf>,
+        visit: &mut dyn FnMut(&Path, &Metadata),
+    ) {
+        if self.symlinks != SymlinkPolicy::Follow {
+            self.walk_below(directory, Some(scope), ancestors, visit);
+            return;
+        }
+        let canonical: PathBuf = match fs::canonicalize(directory) {
+            Ok(canonical) => canonical,
+            Err(e) => {
+                error!(path = %directory.display(), "Unable to resolve directory: {}", e);
+                return;
+            }
+        };
+        if ancestors.contains(&canonical) {
+            info!(path = %directory.display(), "Skipping symlink loop");
+            return;
+        }
+        ancestors.push(canonical);
+        self.walk_below(directory, Some(scope), ancestors, visit);
+        ancestors.pop();
+    }
+
+    /// Explains whether `path` is part of the backup, finding the ignore rule or filter which
+    /// decides it. Paths inside an ignored directory are explained by the directory's rule.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - A path inside the backup, relative paths are resolved against the root.
+    ///
+    /// # Returns
+    ///
+    /// The deciding `Explanation`, `None` if no rule or filter applies and the path is included, or
+    /// an error if the path is not inside the backup root.
+    pub(crate) fn explain(&self, path: &Path) -> Result<Option<Explanation>, String> {
+        let joined: PathBuf = self.root.join(path);
+        let relative: &Path = joined
+            .strip_prefix(&self.root)
+            .ok()
+            .filter(|relative| {
+                !relative
+                    .components()
+                    .any(|component| component == Component::ParentDir)
+            })
+            .ok_or_else(|| {
+                format!(
+                    "{} is not inside the backup root {}",
+                    path.display(),
+                    self.root.display()
+                )
+            })?;
+        let components: Vec<&std::ffi::OsStr> = relative.iter().collect();
+        if components.is_empty() {
+            return Ok(None);
+        }
+        Ok(self.explain_below(&self.root, &components, None))
+    }
+
+    fn explain_below(
+        &self,
+        directory: &Path,
+        components: &[&std::ffi::OsStr],
+        parent_scope: Option<&IgnoreScope>,
+    ) -> Option<Explanation> {
+        let scope: IgnoreScope = self.scope(directory, parent_scope);
+        let path: PathBuf = directory.join(components[0]);
+        let is_last: bool = components.len() == 1;
+        let metadata: Option<Metadata> = fs::symlink_metadata(&path).ok();
+        let is_symlink: bool = metadata
+            .as_ref()
+            .is_some_and(|metadata| metadata.file_type().is_symlink());
+        if is_symlink && self.symlinks == SymlinkPolicy::Skip {
+            return Some(Explanation::filter("symlinks", "skip".to_string()));
+        }
+        let metadata: Option<Metadata> = if is_symlink && self.symlinks == SymlinkPolicy::Follow {
+            fs::metadata(&path).ok()
+        } else {
+            metadata
+        };
+        let is_dir: bool = !is_last || metadata.as_ref().is_some_and(Metadata::is_dir);
+
+        match self.matched(&scope, &path, is_dir) {
+            Match::Ignore(glob) => return Some(Explanation::from_glob(glob, true)),
+            Match::Whitelist(glob) if is_last => return Some(Explanation::from_glob(glob, false)),
+            _ => {}
+        }
+        if is_dir && self.honor_cachedir_tag && is_cache_dir(&path) {
+            return Some(Explanation::filter(
+                &path.join(CACHEDIR_TAG).display().to_string(),
+                "CACHEDIR.TAG".to_string(),
+            ));
+        }
+
+        if !is_last {
+            return self.explain_below(&path, &components[1..], Some(&scope));
+        }
+        match metadata {
+            Some(metadata)
+                if !is_dir
+                    && EntryKind::of(&metadata).is_special()
+                    && self.special_files == SpecialFilePolicy::Skip =>
+            {
+                Some(Explanation::filter("special_files", "skip".to_string()))
+            }
+            Some(metadata) if !is_dir && !self.is_selected(&path, &metadata) => Some(
+                Explanation::filter("filters", "include, size or age filter".to_string()),
+            ),
+            _ => None,
+        }
+    }
+
+    /// Whether a file which is not ignored passes the include globs and the size and age filters.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path of the file.
+    /// * `metadata` - `Metadata` of the file.
+    pub(crate) fn is_selected(&self, path: &Path, metadata: &Metadata) -> bool {
+        let included: bool = match (&self.include_names, &self.include_paths) {
+            (None, None) => true,
+            (names, paths) => {
+                let relative: &Path = path.strip_prefix(&self.root).unwrap_or(path);
+                names.as_ref().is_some_and(|names| {
+                    path.file_name()
+                        .is_some_and(|file_name| names.is_match(file_name))
+                }) || paths.as_ref().is_some_and(|paths| paths.is_match(relative))
+            }
+        };
+        if !included {
+            return false;
+        }
+
+        let size: u64 = metadata.len();
+        if self.min_size.is_some_and(|min| size < min)
+            || self.max_size.is_some_and(|max| size > max)
+        {
+            return false;
+        }
+
+        if self.min_age.is_some() || self.max_age.is_some() {
+            let age: Duration = metadata
+                .modified()
+                .ok()
+                .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+                .unwrap_or_default();
+            if self.min_age.is_some_and(|min| age < min)
+                || self.max_age.is_some_and(|max| age > max)
+            {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Loads the ignore files found in `directory`, creating the scope its entries are matched in.
+    /// `.sandmanignore` rules take precedence over `.gitignore` rules of the same directory.
+    ///
+    /// # Arguments
+    ///
+    /// * `directory` - The directory being entered.
+    /// * `parent` - The scope of the directory's parent, `None` for the backup root.
+    pub(crate) fn scope<'a>(
+        &self,
+        directory: &Path,
+        parent: Option<&'a IgnoreScope<'a>>,
+    ) -> IgnoreScope<'a> {
+        let mut names: Vec<&str> = vec![SANDMAN_IGNORE];
+        if self.honor_gitignore {
+            names.push(GIT_IGNORE);
+        }
+        let matchers: Vec<Gitignore> = names
+            .into_iter()
+            .filter_map(|name| build_matcher(directory, &directory.join(name)))
+            .collect();
+        IgnoreScope { parent, matchers }
+    }
+
+    /// Finds the rule deciding whether `path` is ignored. Rules from deeper directories take
+    /// precedence over those of their parents, and the global ignore file has the lowest precedence.
+    ///
+    /// # Arguments
+    ///
+    /// * `scope` - The scope of the directory containing `path`.
+    /// * `path` - The path to match.
+    /// * `is_dir` - Whether `path` is a directory.
+    ///
+    /// # Returns
+    ///
+    /// The deciding `Match`, `Match::None` if no rule matched.
+    pub(crate) fn matched<'a>(
+        &'a self,
+        scope: &'a IgnoreScope<'a>,
+        path: &Path,
+        is_dir: bool,
+    ) -> Match<&'a Glob> {
+        let mut current: Option<&IgnoreScope> = Some(scope);
+        while let Some(level) = current {
+            for matcher in &level.matchers {
+                let matched: Match<&Glob> = matcher.matched(path, is_dir);
+                if !matched.is_none() {
+                    return matched;
+                }
+            }
+            current = level.parent;
+        }
+        self.global.matched(path, is_dir)
+    }
+
+    /// Whether `path` should be left out of the backup.
+    pub(crate) fn is_ignored(&self, scope: &IgnoreScope, path: &Path, is_dir: bool) -> bool {
+        if self.matched(scope, path, is_dir).is_ignore() {
+            return true;
+        }
+        if is_dir && self.honor_cachedir_tag && is_cache_dir(path) {
+            debug!(path = %path.display(), "Skipping cache directory");
+            return true;
+        }
+        false
+    }
+}
+
+/// Builds a matcher rooted at `root` from an ignore file.
+///
+/// # Returns
+///
+/// The matcher, or `None` if the file does not exist or could not be parsed.
+fn build_matcher(root: &Path, file: &Path) -> Option<Gitignore> {
+    if !file.is_file() {
+        return None;
+    }
+    let mut builder: GitignoreBuilder = GitignoreBuilder::new(root);
+    if let Some(e) = builder.add(file) {
+        error!("Error processing ignore file: {}", e);
+        return None;
+    }
+    builder
+        .build()
+        .map_err(|e| error!("Error processing ignore file: {}", e))
+        .ok()
+}
+
+/// Compiles include globs into a set, leading `/` anchoring them to the root is dropped as all paths
+/// are matched relative to it. Invalid globs are reported and skipped.
+///
+/// # Returns
+///
+/// The compiled `GlobSet`, or `None` if there were no valid globs.
+fn build_globs(patterns: &[&String]) -> Option<GlobSet> {
+    let mut builder: GlobSetBuilder = GlobSetBuilder::new();
+    let mut added: bool = false;
+    for pattern in patterns {
+        match GlobBuilder::new(pattern.trim_start_matches('/'))
+            .literal_separator(true)
+            .build()
+        {
+            Ok(glob) => {
+                builder.add(glob);
+                added = true;
+            }
+            Err(e) => error!("Invalid include glob {}: {}", pattern, e),
+        }
+    }
+    if !added {
+        return None;
+    }
+    builder
+        .build()
+        .map_err(|e| error!("Invalid include globs: {}", e))
+        .ok()
+}
+
+/// Finds the line number of the last line in `file` holding `pattern`, later lines taking
+/// precedence in ignore files.
+fn find_line(file: &Path, pattern: &str) -> Option<usize> {
+    let contents: String = fs::read_to_string(file).ok()?;
+    contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| line.trim_end() == pattern.trim_end())
+        .last()
+        .map(|(index, _)| index + 1)
+}
+
+/// Whether `directory` is marked as a cache directory by a valid `CACHEDIR.TAG` file.
+fn is_cache_dir(directory: &Path) -> bool {
+    let mut header: Vec<u8> = vec![0; CACHEDIR_SIGNATURE.len()];
+    match File::open(directory.join(CACHEDIR_TAG)) {
+        Ok(mut file) => file.read_exact(&mut header).is_ok() && header == CACHEDIR_SIGNATURE,
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    /// Creates the files of a test tree, with their parent directories.
+    fn create(root: &Path, files: &[(&str, &str)]) {
+        for (path, contents) in files {
+            let path: PathBuf = root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+    }
+
+    /// Returns the selection of a backup of `root`, without the global ignore file of whoever
+    /// runs the tests.
+    fn selection(root: &TempDir, configure: impl FnOnce(&mut GatherArgs)) -> Selection {
+        let mut gather_args: GatherArgs = GatherArgs::new(
+            "test".to_string(),
+            root.path().display().to_string(),
+            String::new(),
+            String::new(),
+            0,
+            0,
+            false,
+        );
+        configure(&mut gather_args);
+        Selection {
+            global: Gitignore::empty(),
+            ..Selection::new(&gather_args)
+        }
+    }
+
+    /// Returns the selected files below the root, relative to it and sorted, leaving out the
+    /// ignore files themselves.
+    fn walked(selection: &Selection, root: &TempDir) -> Vec<String> {
+        let mut files: Vec<String> = vec![];
+        selection.walk(root.path(), &mut |path, _| {
+            let relative: &Path = path.strip_prefix(root.path()).unwrap();
+            if relative.file_name().is_some_and(|name| {
+                name != SANDMAN_IGNORE && name != GIT_IGNORE && name != CACHEDIR_TAG
+            }) {
+                files.push(relative.display().to_string());
+            }
+        });
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn nested_ignore_files_take_precedence_over_their_parents() {
+        let root: TempDir = TempDir::new().unwrap();
+        create(
+            root.path(),
+            &[
+                (".sandmanignore", "*.log\nbuild/\n"),
+                ("a.log", ""),
+                ("a.txt", ""),
+                ("build/out.o", ""),
+                ("logs/.sandmanignore", "!keep.log\n"),
+                ("logs/keep.log", ""),
+                ("logs/other.log", ""),
+            ],
+        );
+
+        let selection: Selection = selection(&root, |_| {});
+
+        assert_eq!(walked(&selection, &root), vec!["a.txt", "logs/keep.log"]);
+    }
+
+    #[test]
+    fn sandmanignore_takes_precedence_over_gitignore() {
+        let root: TempDir = TempDir::new().unwrap();
+        create(
+            root.path(),
+            &[
+                (".gitignore", "*.txt\n"),
+                (".sandmanignore", "!important.txt\n"),
+                ("important.txt", ""),
+                ("other.txt", ""),
+            ],
+        );
+
+        let honoring: Selection = selection(&root, |args| args.honor_gitignore = true);
+        let ignoring: Selection = selection(&root, |_| {});
+
+        assert_eq!(walked(&honoring, &root), vec!["important.txt"]);
+        assert_eq!(walked(&ignoring, &root), vec!["important.txt", "other.txt"]);
+    }
+
+    #[test]
+    fn tagged_cache_directories_are_skipped() {
+        let root: TempDir = TempDir::new().unwrap();
+        let signature: String = String::from_utf8(CACHEDIR_SIGNATURE.to_vec()).unwrap();
+        create(
+            root.path(),
+            &[
+                ("cache/CACHEDIR.TAG", &format!("{}\n# a cache\n", signature)),
+                ("cache/blob", ""),
+                ("fake/CACHEDIR.TAG", "Signature: wrong"),
+                ("fake/blob", ""),
+            ],
+        );
+
+        let honoring: Selection = selection(&root, |args| args.honor_cachedir_tag = true);
+        let ignoring: Selection = selection(&root, |_| {});
+
+        assert_eq!(walked(&honoring, &root), vec!["fake/blob"]);
+        assert_eq!(walked(&ignoring, &root), vec!["cache/blob", "fake/blob"]);
+        let explanation: Explanation = honoring.explain(Path::new("cache/blob")).unwrap().unwrap();
+        assert_eq!(explanation.pattern, "CACHEDIR.TAG");
+        assert!(explanation.excluded);
+    }
+
+    #[test]
+    fn include_globs_match_names_and_paths() {
+        let root: TempDir = TempDir::new().unwrap();
+        create(
+            root.path(),
+            &[
+                ("a.pdf", ""),
+                ("sub/b.pdf", ""),
+                ("docs/c.txt", ""),
+                ("docs/deeper/d.txt", ""),
+                ("e.txt", ""),
+            ],
+        );
+
+        let selection: Selection = selection(&root, |args| {
+            args.include = vec!["*.pdf".to_string(), "docs/*.txt".to_string()]
+        });
+
+        assert_eq!(
+            walked(&selection, &root),
+            vec!["a.pdf", "docs/c.txt", "sub/b.pdf"]
+        );
+    }
+
+    #[test]
+    fn size_filters_bound_selected_files() {
+        let root: TempDir = TempDir::new().unwrap();
+        create(
+            root.path(),
+            &[("small", "1"), ("medium", "12345"), ("large", "1234567890")],
+        );
+
+        let selection: Selection = selection(&root, |args| {
+            args.min_size = Some(2);
+            args.max_size = Some(9);
+        });
+
+        assert_eq!(walked(&selection, &root), vec!["medium"]);
+        let explanation: Explanation = selection.explain(Path::new("large")).unwrap().unwrap();
+        assert_eq!(explanation.source, "filters");
+    }
+
+    #[test]
+    fn explanations_name_the_deciding_rule() {
+        let root: TempDir = TempDir::new().unwrap();
+        create(
+            root.path(),
+            &[
+                (".sandmanignore", "# logs\n*.log\nbuild/\n!keep.log\n"),
+                ("a.log", ""),
+                ("keep.log", ""),
+                ("build/out/x.o", ""),
+                ("a.txt", ""),
+            ],
+        );
+        let selection: Selection = selection(&root, |_| {});
+        let ignore_file: String = root.path().join(SANDMAN_IGNORE).display().to_string();
+
+        let ignored: Explanation = selection.explain(Path::new("a.log")).unwrap().unwrap();
+        assert_eq!(
+            (
+                ignored.source.as_str(),
+                ignored.line,
+                ignored.pattern.as_str()
+            ),
+            (ignore_file.as_str(), Some(2), "*.log")
+        );
+        assert!(ignored.excluded);
+
+        let kept: Explanation = selection.explain(Path::new("keep.log")).unwrap().unwrap();
+        assert_eq!((kept.line, kept.pattern.as_str()), (Some(4), "!keep.log"));
+        assert!(!kept.excluded);
+
+        let nested: Explanation = selection
+            .explain(&root.path().join("build/out/x.o"))
+            .unwrap()
+            .unwrap();
+        assert_eq!((nested.line, nested.pattern.as_str()), (Some(3), "build/"));
+
+        assert!(selection.explain(Path::new("a.txt")).unwrap().is_none());
+        assert!(selection.explain(Path::new("")).unwrap().is_none());
+    }
+
+    #[test]
+    fn paths_outside_the_root_cannot_be_explained() {
+        let root: TempDir = TempDir::new().unwrap();
+        let selection: Selection = selection(&root, |_| {});
+
+        assert!(selection.explain(Path::new("../elsewhere.txt")).is_err());
+        assert!(selection
+            .explain(Path::new("sub/../../elsewhere.txt"))
+            .is_err());
+        assert!(selection.explain(Path::new("/elsewhere.txt")).is_err());
+    }
+
+    #[test]
+    fn walking_a_path_applies_the_rules_above_it() {
+        let root: TempDir = TempDir::new().unwrap();
+        create(
+            root.path(),
+            &[
+                (".sandmanignore", "*.tmp\n"),
+                ("sub/a.txt", ""),
+                ("sub/b.tmp", ""),
+            ],
+        );
+        let selection: Selection = selection(&root, |_| {});
+        let mut visited: Vec<PathBuf> = vec![];
+
+        selection.walk_path(&root.path().join("sub"), &mut |path, _| {
+            visited.push(path.to_path_buf())
+        });
+        selection.walk_path(&root.path().join("sub/b.tmp"), &mut |path, _| {
+            visited.push(path.to_path_buf())
+        });
+
+        assert_eq!(visited, vec![root.path().join("sub/a.txt")]);
+    }
+}
@@ -1,19 +1,28 @@
-use ignore::gitignore::Gitignore;
-use ignore::Match;
-use log::error;
+use crate::entry::{link_identity, EntryKind};
+use crate::keys::{decode_key, encode_path};
+use crate::selection::Selection;
+use sandman_share::config::HashAlgorithm;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use std::collections::HashMap;
-use std::fs;
-use std::path::PathBuf;
+use std::fs::{self, Metadata};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
 use std::string::String;
 use std::time::{SystemTime, UNIX_EPOCH};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info};
+use xxhash_rust::xxh3::{xxh3_128, Xxh3};
 
 /// Struct representing SHA file information with a map of file paths to SHA values and a timestamp.
 #[derive(Serialize, Deserialize, Eq, PartialEq, Debug, Clone)]
 pub(crate) struct ShaFile {
     pub(crate) files: HashMap<String, String>,
     pub(crate) timestamp: u64,
+    /// Algorithm which produced the digests in `files`, histories written before this was
+    /// recorded are SHA-256.
+    #[serde(default)]
+    pub(crate) algorithm: HashAlgorithm,
 }
 
 impl ShaFile {
@@ -28,85 +37,240 @@ impl ShaFile {
         ShaFile {
             files: HashMap::new(),
             timestamp,
+            algorithm: HashAlgorithm::default(),
+        }
+    }
+
+    /// Creates a new, empty `ShaFile` whose digests will be produced by `algorithm`.
+    pub(crate) fn with_algorithm(algorithm: HashAlgorithm) -> Self {
+        ShaFile {
+            algorithm,
+            ..ShaFile::new()
         }
     }
 }
 
-/// Generates SHA-256 hashes for files in the given directory, ignoring files specified in the ignore list.
+/// Computes the hex encoded digest of `bytes` with the given algorithm.
 ///
 /// # Arguments
 ///
-/// * `directory` - The directory to scan for files.
-/// * `sha_file` - A mutable reference to a `ShaFile` to store the hashes.
-/// * `ignore_pattern` - A list of file patterns to ignore.
-pub(crate) fn generate_shas(directory: String, sha_file: &mut ShaFile, ignore_file: &Gitignore) {
-    let paths = match fs::read_dir(directory) {
-        Ok(paths) => paths,
-        Err(e) => {
-            error!("Unable to open directory: {}", e);
-            return;
+/// * `algorithm` - The `HashAlgorithm` to digest with.
+/// * `bytes` - The file contents to digest.
+///
+/// # Returns
+///
+/// A `String` containing the lowercase hex digest.
+pub(crate) fn digest(algorithm: HashAlgorithm, bytes: &[u8]) -> String {
+    match algorithm {
+        HashAlgorithm::Sha256 => {
+            let mut hasher = Sha256::new();
+            hasher.update(bytes);
+            format!("{:x}", hasher.finalize())
         }
-    };
-
-    for entry in paths {
-        println!("{:?}", entry);
-        let path = match entry {
-            Ok(entry) => entry.path(),
-            Err(e) => {
-                error!("Error reading directory entry: {}", e);
-                continue;
-            }
-        };
-
-        let is_dir: bool = path.is_dir();
+        HashAlgorithm::Blake3 => blake3::hash(bytes).to_hex().to_string(),
+        HashAlgorithm::Xxh3 => format!("{:032x}", xxh3_128(bytes)),
+    }
+}
 
-        let path_str = match path.to_str() {
-            Some(p) => p.to_string().replace('\\', "/"),
-            None => {
-                error!("Invalid path: {:?}", path);
-                continue;
+/// Computes the hex encoded digest of everything `reader` yields, reading it a chunk at a time.
+/// The digest equals that of `digest` over the same bytes.
+///
+/// # Arguments
+///
+/// * `algorithm` - The `HashAlgorithm` to digest with.
+/// * `reader` - The contents to digest.
+///
+/// # Returns
+///
+/// A `String` containing the lowercase hex digest, or the error reading the contents failed with.
+pub(crate) fn digest_reader(algorithm: HashAlgorithm, mut reader: impl Read) -> io::Result<String> {
+    let mut buffer: Vec<u8> = vec![0; 1 << 16];
+    let mut feed = |update: &mut dyn FnMut(&[u8])| -> io::Result<()> {
+        loop {
+            match reader.read(&mut buffer)? {
+                0 => return Ok(()),
+                read => update(&buffer[..read]),
             }
-        };
-        if let Match::Ignore(_) = ignore_file.matched(&*path, is_dir) {
-            continue;
         }
-
-        if is_dir {
-            generate_shas(path_str, sha_file, ignore_file);
-        } else {
-            let bytes = match fs::read(&path) {
-                Ok(bytes) => bytes,
-                Err(e) => {
-                    error!("Error while opening file: {:?} {}", path, e);
-                    continue;
-                }
-            };
-
+    };
+    Ok(match algorithm {
+        HashAlgorithm::Sha256 => {
             let mut hasher = Sha256::new();
-            hasher.update(&bytes);
-            let hash = format!("{:x}", hasher.finalize());
-            sha_file.files.insert(path_str, hash);
+            feed(&mut |bytes| hasher.update(bytes))?;
+            format!("{:x}", hasher.finalize())
         }
-    }
+        HashAlgorithm::Blake3 => {
+            let mut hasher = blake3::Hasher::new();
+            feed(&mut |bytes| {
+                hasher.update(bytes);
+            })?;
+            hasher.finalize().to_hex().to_string()
+        }
+        HashAlgorithm::Xxh3 => {
+            let mut hasher = Xxh3::new();
+            feed(&mut |bytes| hasher.update(bytes))?;
+            format!("{:032x}", hasher.digest128())
+        }
+    })
 }
 
-/// Retrieves the prior SHA file information from the given location.
+/// Converts a path below `root` into the portable key stored in histories and remote keys, relative
+/// to `root` and encoded with `encode_path`.
 ///
 /// # Arguments
 ///
-/// * `sha_location` - The file path to the SHA file.
+/// * `root` - The root of the backup.
+/// * `path` - A path inside `root`.
 ///
 /// # Returns
 ///
-/// A `ShaFile` instance with the previously stored SHA information.
-pub(crate) fn get_prior_shas(sha_location: &PathBuf) -> ShaFile {
-    match fs::read_to_string(sha_location) {
-        Ok(json) => serde_json::from_str(&json).expect("Unable to deserialize ShaFile"),
-        Err(e) => {
-            error!("Unable to find ShaFile: {}. Defaulting to empty.", e);
-            ShaFile::new()
+/// The encoded relative path, or `None` if `path` is not below `root`.
+pub(crate) fn relative_key(root: &Path, path: &Path) -> Option<String> {
+    let relative: &Path = path.strip_prefix(root).ok()?;
+    Some(encode_path(relative))
+}
+
+/// An entry sharing its inode with other entries, as its key, path and kind.
+type LinkedEntry = (String, PathBuf, EntryKind);
+
+/// Generates hashes for the entries of a backup, ignoring entries excluded by the selection. The
+/// digests are produced with the algorithm recorded on `sha_file` and keyed by their path relative
+/// to `root`. Stored symbolic links and special files are recorded as described by `EntryKind`.
+/// Files hard linked within the backup are hashed once, under the path the history stores them
+/// under, or the first of their paths in key order when it stores none, and their other paths are
+/// recorded as links to it. A scan of some paths thereby keeps the path a full scan chose, also
+/// when that path lies outside the scanned paths.
+///
+/// # Arguments
+///
+/// * `root` - The root of the backup, which recorded paths are relative to.
+/// * `sha_file` - A mutable reference to a `ShaFile` to store the hashes.
+/// * `selection` - The `Selection` deciding which files are ignored or filtered out.
+/// * `paths` - When given only these paths, and what lies below them, are scanned instead of the
+///   whole of `root`. They must not overlap.
+/// * `recorded` - Looks up the records the history holds for the given keys of hard linked files,
+///   leaving out keys it does not record.
+/// * `stop` - Token cancelled to stop hashing, leaving `sha_file` incomplete.
+pub(crate) fn generate_shas(
+    root: &Path,
+    sha_file: &mut ShaFile,
+    selection: &Selection,
+    paths: Option<&[PathBuf]>,
+    recorded: impl FnOnce(Vec<&String>) -> HashMap<String, String>,
+    stop: &CancellationToken,
+) {
+    let mut linked: HashMap<(u64, u64), Vec<LinkedEntry>> = HashMap::new();
+
+    let mut visit = |path: &Path, metadata: &Metadata| {
+        if stop.is_cancelled() {
+            return;
+        }
+        let path_str = match relative_key(root, path) {
+            Some(p) => p,
+            None => {
+                error!(path = %path.display(), "Invalid path");
+                return;
+            }
+        };
+
+        let kind: EntryKind = EntryKind::of_file(path, metadata);
+        match link_identity(metadata) {
+            Some(identity) => {
+                linked
+                    .entry(identity)
+                    .or_default()
+                    .push((path_str, path.to_path_buf(), kind))
+            }
+            None => record_entry(sha_file, path_str, path, &kind),
         }
+    };
+    match paths {
+        Some(paths) => {
+            for path in paths {
+                selection.walk_path(path, &mut visit);
+            }
+        }
+        None => selection.walk(root, &mut visit),
     }
+
+    if stop.is_cancelled() || linked.is_empty() {
+        return;
+    }
+    let recorded: HashMap<String, String> =
+        recorded(linked.values().flatten().map(|(key, _, _)| key).collect());
+    for (identity, mut links) in linked {
+        links.sort_by(|a, b| a.0.cmp(&b.0));
+        let index: usize = match recorded_primary(&links, &recorded) {
+            Some(key) => match links.iter().position(|(path_str, _, _)| *path_str == key) {
+                Some(index) => index,
+                None => match linked_entry(root, &key, identity) {
+                    Some(entry) => {
+                        links.push(entry);
+                        links.len() - 1
+                    }
+                    None => 0,
+                },
+            },
+            None => 0,
+        };
+        let (primary, path, kind) = links.remove(index);
+        for (path_str, _, _) in links {
+            let link: EntryKind = EntryKind::Hardlink(primary.clone());
+            sha_file
+                .files
+                .insert(path_str, link.record(sha_file.algorithm, &[]));
+        }
+        record_entry(sha_file, primary, &path, &kind);
+    }
+}
+
+/// Finds the key the history stores a group of hard linked files under: the key one of them is
+/// recorded as linking to, otherwise the first of them recorded as a file of its own.
+fn recorded_primary(links: &[LinkedEntry], recorded: &HashMap<String, String>) -> Option<String> {
+    let kinds: Vec<(&String, EntryKind)> = links
+        .iter()
+        .filter_map(|(path_str, _, _)| {
+            let record: &String = recorded.get(path_str)?;
+            Some((path_str, EntryKind::of_record(record)))
+        })
+        .collect();
+    kinds
+        .iter()
+        .find_map(|(_, kind)| match kind {
+            EntryKind::Hardlink(key) => Some(key.clone()),
+            _ => None,
+        })
+        .or_else(|| {
+            kinds
+                .iter()
+                .find(|(_, kind)| kind.is_removable())
+                .map(|(path_str, _)| path_str.to_string())
+        })
+}
+
+/// Returns the entry stored under `key` if it is still one of the hard linked files of `identity`,
+/// for scans of some paths which did not reach it.
+fn linked_entry(root: &Path, key: &str, identity: (u64, u64)) -> Option<LinkedEntry> {
+    let path: PathBuf = root.join(decode_key(key));
+    let metadata: Metadata = fs::symlink_metadata(&path).ok()?;
+    (link_identity(&metadata) == Some(identity)).then(|| {
+        let kind: EntryKind = EntryKind::of_file(&path, &metadata);
+        (key.to_string(), path, kind)
+    })
+}
+
+/// Reads an entry and records it in `sha_file` under `path_str`, logging entries which could not
+/// be read.
+fn record_entry(sha_file: &mut ShaFile, path_str: String, path: &Path, kind: &EntryKind) {
+    let hash = match kind.record_of(sha_file.algorithm, path) {
+        Ok(hash) => hash,
+        Err(e) => {
+            error!(path = %path.display(), "Error while opening file: {}", e);
+            return;
+        }
+    };
+
+    sha_file.files.insert(path_str, hash);
 }
 
 /// Computes the difference in SHA values between old and new SHA files.
@@ -120,7 +284,7 @@ pub(crate) fn get_prior_shas(sha_location: &PathBuf) -> ShaFile {
 ///
 /// A `ShaFile` containing the differences in SHA values.
 pub(crate) fn get_sha_diff(old: &ShaFile, new: ShaFile) -> ShaFile {
-    let mut diff = ShaFile::new();
+    let mut diff = ShaFile::with_algorithm(new.algorithm);
 
     for (k, v) in &new.files {
         if old.files.get(k) != Some(v) {
@@ -146,16 +310,188 @@ pub(crate) fn merge_diff_old(mut old: ShaFile, new: &ShaFile) -> ShaFile {
         old.files.insert(k.clone(), v.clone());
     }
     old.timestamp = new.timestamp;
+    old.algorithm = new.algorithm;
     old
 }
 
-/// Writes the SHA file information to the specified output path.
+/// Re-baselines a history produced with a different algorithm onto `algorithm`. Every file still
+/// matching its recorded digest is re-hashed with the new algorithm, so unchanged files are not
+/// uploaded again. Files which changed or disappeared are dropped from the history and will be
+/// picked up as changes by the next diff.
 ///
 /// # Arguments
 ///
-/// * `shas` - The `ShaFile` containing SHA information.
-/// * `output_path` - The file path to write the SHA file to.
-pub(crate) fn write_file_shas(shas: &ShaFile, output_path: &PathBuf) {
-    let shas_json = serde_json::to_string_pretty(shas).expect("Failed to serialize ShaFile");
-    fs::write(output_path, shas_json).expect("Failed to write ShaFile");
+/// * `old` - The prior `ShaFile`, hashed with `old.algorithm`.
+/// * `root` - The root of the backup, which recorded paths are relative to.
+/// * `algorithm` - The `HashAlgorithm` the history should be migrated to.
+///
+/// # Returns
+///
+/// A `ShaFile` whose digests were all produced by `algorithm`.
+pub(crate) fn rebaseline_shas(old: ShaFile, root: &Path, algorithm: HashAlgorithm) -> ShaFile {
+    let mut rebaselined = ShaFile::with_algorithm(algorithm);
+    rebaselined.timestamp = old.timestamp;
+
+    for (path, hash) in old.files {
+        let kind: EntryKind = EntryKind::of_record(&hash);
+        let local_path: PathBuf = root.join(decode_key(&path));
+        if !kind
+            .record_of(old.algorithm, &local_path)
+            .is_ok_and(|record| record == hash)
+        {
+            continue;
+        }
+        if let Ok(record) = kind.record_of(algorithm, &local_path) {
+            rebaselined.files.insert(path, record);
+        }
+    }
+
+    info!(
+        "Re-baselined history from {} to {}, {} unchanged files carried over",
+        old.algorithm,
+        algorithm,
+        rebaselined.files.len()
+    );
+    rebaselined
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::args::GatherArgs;
+    use tempfile::TempDir;
+
+    const ALGORITHMS: [HashAlgorithm; 3] = [
+        HashAlgorithm::Sha256,
+        HashAlgorithm::Blake3,
+        HashAlgorithm::Xxh3,
+    ];
+
+    /// Yields its bytes a few at a time, as readers are free to.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+            let read: usize = self.0.len().min(buffer.len()).min(7);
+            buffer[..read].copy_from_slice(&self.0[..read]);
+            self.0 = &self.0[read..];
+            Ok(read)
+        }
+    }
+
+    #[test]
+    fn digests_are_lowercase_hex() {
+        assert_eq!(
+            digest(HashAlgorithm::Sha256, b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(digest(HashAlgorithm::Blake3, b"abc").len(), 64);
+        assert_eq!(digest(HashAlgorithm::Xxh3, b"abc").len(), 32);
+    }
+
+    #[test]
+    fn streamed_digests_equal_digests_of_the_whole() {
+        let bytes: Vec<u8> = (0..200_000u32).map(|i| (i * 31 % 251) as u8).collect();
+        for algorithm in ALGORITHMS {
+            for length in [0, 1, 65_536, 65_537, bytes.len()] {
+                let expected: String = digest(algorithm, &bytes[..length]);
+
+                assert_eq!(
+                    digest_reader(algorithm, &bytes[..length]).unwrap(),
+                    expected
+                );
+                assert_eq!(
+                    digest_reader(algorithm, Trickle(&bytes[..length])).unwrap(),
+                    expected
+                );
+            }
+        }
+    }
+
+    /// Returns the selection of a backup of `root`.
+    fn selection(root: &Path) -> Selection {
+        Selection::new(&GatherArgs::new(
+            "test".to_string(),
+            root.display().to_string(),
+            String::new(),
+            String::new(),
+            0,
+            0,
+            false,
+        ))
+    }
+
+    /// Scans `paths` of `root`, or all of it, against the records of `history`.
+    fn scan(root: &Path, paths: Option<&[PathBuf]>, history: &ShaFile) -> ShaFile {
+        let mut shas: ShaFile = ShaFile::with_algorithm(HashAlgorithm::Blake3);
+        generate_shas(
+            root,
+            &mut shas,
+            &selection(root),
+            paths,
+            |keys| {
+                keys.into_iter()
+                    .filter_map(|key| Some((key.clone(), history.files.get(key)?.clone())))
+                    .collect()
+            },
+            &CancellationToken::new(),
+        );
+        shas
+    }
+
+    fn link_to(key: &str) -> String {
+        EntryKind::Hardlink(key.to_string()).record(HashAlgorithm::Blake3, &[])
+    }
+
+    #[test]
+    fn hard_links_are_stored_under_their_first_path() {
+        let directory: TempDir = TempDir::new().unwrap();
+        let root: &Path = directory.path();
+        fs::write(root.join("b.txt"), "shared").unwrap();
+        fs::hard_link(root.join("b.txt"), root.join("a.txt")).unwrap();
+
+        let shas: ShaFile = scan(root, None, &ShaFile::new());
+
+        assert_eq!(
+            shas.files["a.txt"],
+            digest(HashAlgorithm::Blake3, b"shared")
+        );
+        assert_eq!(shas.files["b.txt"], link_to("a.txt"));
+    }
+
+    #[test]
+    fn scans_of_some_paths_keep_the_recorded_primary() {
+        let directory: TempDir = TempDir::new().unwrap();
+        let root: &Path = directory.path();
+        fs::write(root.join("a.txt"), "shared").unwrap();
+        fs::hard_link(root.join("a.txt"), root.join("b.txt")).unwrap();
+        let history: ShaFile = scan(root, None, &ShaFile::new());
+
+        // Writing through one path changes every path of the file
+        fs::write(root.join("b.txt"), "changed").unwrap();
+        let shas: ShaFile = scan(root, Some(&[root.join("b.txt")]), &history);
+
+        assert_eq!(
+            shas.files["a.txt"],
+            digest(HashAlgorithm::Blake3, b"changed")
+        );
+        assert_eq!(shas.files["b.txt"], link_to("a.txt"));
+    }
+
+    #[test]
+    fn new_links_keep_the_recorded_primary() {
+        let directory: TempDir = TempDir::new().unwrap();
+        let root: &Path = directory.path();
+        fs::write(root.join("b.txt"), "shared").unwrap();
+        let history: ShaFile = scan(root, None, &ShaFile::new());
+
+        fs::hard_link(root.join("b.txt"), root.join("a.txt")).unwrap();
+        let shas: ShaFile = scan(root, None, &history);
+
+        assert_eq!(
+            shas.files["b.txt"],
+            digest(HashAlgorithm::Blake3, b"shared")
+        );
+        assert_eq!(shas.files["a.txt"], link_to("b.txt"));
+    }
 }
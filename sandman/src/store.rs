@@ -0,0 +1,803 @@
+use crate::args::GatherArgs;
+use crate::clock::now;
+use crate::history::{
+    get_prior_shas, quarantine, read_history, relative_legacy_path, write_file_shas,
+};
+use crate::keys::{decode_key, escape_legacy_key};
+use crate::sha::ShaFile;
+use rusqlite::{params, Connection, ErrorCode, OpenFlags, OptionalExtension};
+use sandman_share::config::{HashAlgorithm, HistoryBackend};
+use sandman_share::consts::{SANDMAN_HISTORY, SANDMAN_HISTORY_DB};
+use sandman_share::paths::{backup_state_dir, backup_state_path};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use tracing::{error, info};
+
+/// Version of the SQLite history schema, stored in the database's `user_version`. Version 1
+/// recorded full local paths, version 2 recorded paths relative to the backup root verbatim and
+/// version 3 records them encoded with `encode_path`.
+const SQLITE_SCHEMA_VERSION: i64 = 3;
+
+const SQLITE_SCHEMA: &str = r#"
+CREATE TABLE IF NOT EXISTS meta (
+    key TEXT PRIMARY KEY,
+    value TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS files (
+    path TEXT PRIMARY KEY,
+    hash TEXT NOT NULL,
+    size INTEGER,
+    mtime INTEGER,
+    last_snapshot TEXT,
+    uploaded_at INTEGER
+);
+"#;
+
+/// Persistent record of the files previously seen for a backup.
+pub(crate) enum HistoryStore {
+    /// The whole history serialized as a single JSON document.
+    Json { path: PathBuf, root: PathBuf },
+
+    /// Per-file rows in an embedded SQLite database, updated incrementally, alongside the path
+    /// of the JSON history it may be seeded from.
+    Sqlite {
+        connection: Connection,
+        json_path: PathBuf,
+        root: PathBuf,
+    },
+}
+
+impl HistoryStore {
+    /// Opens the history store configured for a backup. Unless an explicit path was given the
+    /// history is kept in the backup's state directory, and a history left inside the backed up
+    /// directory by earlier versions is adopted.
+    ///
+    /// # Arguments
+    ///
+    /// * `gather_args` - `GatherArgs` of the backup, selecting the backend and its location.
+    ///
+    /// # Returns
+    ///
+    /// The store, or a description of why its database could not be opened.
+    pub(crate) fn open(gather_args: &GatherArgs) -> Result<Self, String> {
+        let directory: &Path = Path::new(&gather_args.local_directory);
+        let path: PathBuf = HistoryStore::location(gather_args);
+
+        match gather_args.history_backend {
+            HistoryBackend::Json => {
+                adopt_legacy(&directory.join(SANDMAN_HISTORY), &path);
+                Ok(HistoryStore::Json {
+                    path,
+                    root: directory.to_path_buf(),
+                })
+            }
+            HistoryBackend::Sqlite => {
+                let json_path: PathBuf = backup_state_dir(&gather_args.name).join(SANDMAN_HISTORY);
+                adopt_legacy(&directory.join(SANDMAN_HISTORY_DB), &path);
+                adopt_legacy(&directory.join(SANDMAN_HISTORY), &json_path);
+                Ok(HistoryStore::Sqlite {
+                    connection: open_sqlite(&path, directory)?,
+                    json_path,
+                    root: directory.to_path_buf(),
+                })
+            }
+        }
+    }
+
+    /// Reads the history of a backup without changing anything on disk: nothing is created,
+    /// migrated, adopted or quarantined. A history earlier versions left inside the backed up
+    /// directory is read where it is, and a SQLite history still to be seeded is read from its
+    /// JSON history.
+    ///
+    /// # Arguments
+    ///
+    /// * `gather_args` - `GatherArgs` of the backup, selecting the backend and its location.
+    ///
+    /// # Returns
+    ///
+    /// The history, `None` if the backup has none yet, or a description of why it could not be
+    /// read.
+    pub(crate) fn peek(gather_args: &GatherArgs) -> Result<Option<ShaFile>, String> {
+        let directory: &Path = Path::new(&gather_args.local_directory);
+        let existing = |location: PathBuf, file_name: &str| -> Option<PathBuf> {
+            if location.exists() {
+                return Some(location);
+            }
+            let legacy: PathBuf = directory.join(file_name);
+            legacy.is_file().then_some(legacy)
+        };
+        let state_dir: PathBuf = backup_state_path(&gather_args.name);
+        let location: PathBuf = gather_args
+            .history_path
+            .clone()
+            .unwrap_or_else(|| state_dir.join(history_file(gather_args)));
+        let json_path: Option<PathBuf> = match gather_args.history_backend {
+            HistoryBackend::Json => existing(location, SANDMAN_HISTORY),
+            HistoryBackend::Sqlite => {
+                let database: Option<PathBuf> = existing(location, SANDMAN_HISTORY_DB);
+                if let Some(history) = database
+                    .map(|path| peek_sqlite(&path, directory))
+                    .transpose()?
+                    .flatten()
+                {
+                    return Ok(Some(history));
+                }
+                existing(state_dir.join(SANDMAN_HISTORY), SANDMAN_HISTORY)
+            }
+        };
+        json_path
+            .map(|path| read_history(&path, directory))
+            .transpose()
+    }
+
+    /// Returns the path the history of a backup is kept at, its explicit `history_path` or a file in
+    /// the backup's state directory.
+    pub(crate) fn location(gather_args: &GatherArgs) -> PathBuf {
+        gather_args
+            .history_path
+            .clone()
+            .unwrap_or_else(|| backup_state_dir(&gather_args.name).join(history_file(gather_args)))
+    }
+
+    /// Returns the time of the last recorded run, in seconds since the Unix epoch.
+    pub(crate) fn timestamp(&self) -> u64 {
+        match self {
+            HistoryStore::Sqlite { connection, .. } => get_meta(connection, "timestamp")
+                .ok()
+                .flatten()
+                .and_then(|timestamp| timestamp.parse().ok())
+                .or_else(|| self.load().ok().map(|history| history.timestamp))
+                .unwrap_or(0),
+            HistoryStore::Json { path, root } => get_prior_shas(path, root).timestamp,
+        }
+    }
+
+    /// Loads the full history of previously recorded digests. A new SQLite history is seeded
+    /// from an existing JSON history, so switching backends does not re-upload every file.
+    ///
+    /// # Returns
+    ///
+    /// The history, or a description of why the database could not be read. Unreadable JSON
+    /// histories are quarantined and replaced by an empty history instead.
+    pub(crate) fn load(&self) -> Result<ShaFile, String> {
+        match self {
+            HistoryStore::Json { path, root } => Ok(get_prior_shas(path, root)),
+            HistoryStore::Sqlite {
+                connection,
+                json_path,
+                root,
+            } => {
+                if self.needs_seed() {
+                    info!("Seeding history database from {:?}", json_path);
+                    return Ok(get_prior_shas(json_path, root));
+                }
+                load_sqlite(connection)
+                    .map_err(|e| format!("Unable to read history database: {}", e))
+            }
+        }
+    }
+
+    /// Loads the recorded digests of the given files only. A SQLite history looks up just these
+    /// rows, so a run does not read the digest of every file ever backed up. JSON histories, and a
+    /// SQLite history still to be seeded from one, are read whole.
+    ///
+    /// # Arguments
+    ///
+    /// * `keys` - Encoded paths, relative to the backup root, of the files to look up.
+    ///
+    /// # Returns
+    ///
+    /// The history restricted to `keys`, or a description of why the database could not be read.
+    pub(crate) fn load_keys<'a>(
+        &self,
+        keys: impl IntoIterator<Item = &'a String>,
+    ) -> Result<ShaFile, String> {
+        match self {
+            HistoryStore::Sqlite { connection, .. } if !self.needs_seed() => {
+                load_sqlite_keys(connection, keys)
+                    .map_err(|e| format!("Unable to read history database: {}", e))
+            }
+            _ => self.load(),
+        }
+    }
+
+    /// Returns what the history recorded about a single file.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - Encoded path of the file, relative to the backup root.
+    ///
+    /// # Returns
+    ///
+    /// The `FileRecord`, `None` if the file is not in the history, or a description of why it
+    /// could not be read. JSON histories do not record when files were uploaded.
+    pub(crate) fn file_record(&self, key: &str) -> Result<Option<FileRecord>, String> {
+        match self {
+            HistoryStore::Json { .. } => Err(
+                "The json history backend does not record uploads, use history_backend = \"sqlite\""
+                    .to_string(),
+            ),
+            HistoryStore::Sqlite { connection, .. } => connection
+                .query_row(
+                    "SELECT last_snapshot, uploaded_at FROM files WHERE path = ?1",
+                    [key],
+                    |row| {
+                        Ok(FileRecord {
+                            snapshot: row.get(0)?,
+                            uploaded_at: row.get::<_, Option<i64>>(1)?.map(|time| time as u64),
+                        })
+                    },
+                )
+                .optional()
+                .map_err(|e| format!("Unable to read history database: {}", e)),
+        }
+    }
+
+    /// Persists the outcome of a run.
+    ///
+    /// # Arguments
+    ///
+    /// * `merged` - The history after this run. For a SQLite history this only needs to hold the
+    ///   files loaded for the run, unless it was re-baselined.
+    /// * `diff` - The files which changed in this run.
+    /// * `snapshot` - Name of the snapshot the changed files are uploaded under.
+    ///
+    /// # Returns
+    ///
+    /// The number of files recorded in the history, or a description of why it could not be
+    /// written.
+    pub(crate) fn save(
+        &mut self,
+        merged: &ShaFile,
+        diff: &ShaFile,
+        snapshot: &str,
+    ) -> Result<usize, String> {
+        match self {
+            HistoryStore::Json { path, .. } => write_file_shas(merged, path)
+                .map(|_| merged.files.len())
+                .map_err(|e| format!("Unable to write history {:?}: {}", path, e)),
+            HistoryStore::Sqlite {
+                connection, root, ..
+            } => save_sqlite(connection, root, merged, diff, snapshot)
+                .map_err(|e| format!("Unable to write history database: {}", e)),
+        }
+    }
+
+    /// Whether this is a new SQLite history which is still to be seeded from a JSON history.
+    fn needs_seed(&self) -> bool {
+        match self {
+            HistoryStore::Sqlite {
+                connection,
+                json_path,
+                ..
+            } => matches!(get_meta(connection, "timestamp"), Ok(None) if json_path.is_file()),
+            HistoryStore::Json { .. } => false,
+        }
+    }
+}
+
+/// Returns the file name of a backup's history in its state directory.
+fn history_file(gather_args: &GatherArgs) -> &'static str {
+    match gather_args.history_backend {
+        HistoryBackend::Json => SANDMAN_HISTORY,
+        HistoryBackend::Sqlite => SANDMAN_HISTORY_DB,
+    }
+}
+
+/// What a SQLite history recorded about a file.
+pub(crate) struct FileRecord {
+    /// Name of the snapshot the file was last uploaded in, unknown for files carried over from a
+    /// JSON history or a re-baselined one.
+    pub(crate) snapshot: Option<String>,
+
+    /// When the file was last uploaded, in seconds since the Unix epoch.
+    pub(crate) uploaded_at: Option<u64>,
+}
+
+/// Moves a history written inside the backed up directory by earlier versions to its new
+/// location. If the source cannot be modified the history is copied instead.
+///
+/// # Arguments
+///
+/// * `legacy` - Path of the history inside the backed up directory.
+/// * `location` - Path the history is now kept at.
+fn adopt_legacy(legacy: &Path, location: &Path) {
+    if location.exists() || !legacy.is_file() {
+        return;
+    }
+    if let Some(parent) = location.parent() {
+        let _ = fs::create_dir_all(parent);
+    }
+    let adopted: std::io::Result<()> =
+        fs::rename(legacy, location).or_else(|_| fs::copy(legacy, location).map(|_| ()));
+    match adopted {
+        Ok(_) => info!("Moved history {:?} to {:?}", legacy, location),
+        Err(e) => error!(
+            "Unable to move history {:?} to {:?}: {}",
+            legacy, location, e
+        ),
+    }
+}
+
+/// Opens, creating if necessary, the SQLite history database at `path`, upgrading the schema of
+/// databases written by earlier versions. A file which is not a readable database is quarantined,
+/// as unreadable JSON histories are, and a new database is created in its place.
+///
+/// # Arguments
+///
+/// * `path` - Location of the database.
+/// * `root` - The root of the backup the history belongs to.
+///
+/// # Returns
+///
+/// The connection, or a description of why the database could not be opened, such as it being
+/// locked or not writable.
+fn open_sqlite(path: &Path, root: &Path) -> Result<Connection, String> {
+    let opened: rusqlite::Result<Connection> = match init_sqlite(path, root) {
+        Err(e) if is_corrupt(&e) => {
+            error!(
+                "Unable to read history database {:?}: {}. Rebuilding.",
+                path, e
+            );
+            quarantine(path);
+            init_sqlite(path, root)
+        }
+        opened => opened,
+    };
+    opened.map_err(|e| format!("Unable to open history database {:?}: {}", path, e))
+}
+
+/// Opens the database at `path`, creating its schema and migrating it to the current version.
+fn init_sqlite(path: &Path, root: &Path) -> rusqlite::Result<Connection> {
+    let mut connection: Connection = Connection::open(path)?;
+    connection.execute_batch(SQLITE_SCHEMA)?;
+    let version: i64 = connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    if version == 1 {
+        rewrite_sqlite_paths(&mut connection, |path| relative_legacy_path(path, root))?;
+        info!("Migrated history database {:?} to version 2", path);
+    }
+    if (1..=2).contains(&version) {
+        rewrite_sqlite_paths(&mut connection, escape_legacy_key)?;
+        info!("Migrated history database {:?} to version 3", path);
+    }
+    connection.pragma_update(None, "user_version", SQLITE_SCHEMA_VERSION)?;
+    Ok(connection)
+}
+
+/// Reads a SQLite history without writing to it, rewriting the paths recorded by earlier schema
+/// versions in memory only.
+///
+/// # Returns
+///
+/// The history, `None` if the database never recorded a run, or a description of why it could not
+/// be read.
+fn peek_sqlite(path: &Path, root: &Path) -> Result<Option<ShaFile>, String> {
+    let read = || -> rusqlite::Result<Option<(i64, ShaFile)>> {
+        let connection: Connection = Connection::open_with_flags(
+            path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )?;
+        let version: i64 = connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        if version == 0 || get_meta(&connection, "timestamp")?.is_none() {
+            return Ok(None);
+        }
+        Ok(Some((version, load_sqlite(&connection)?)))
+    };
+    let Some((version, mut shas)) =
+        read().map_err(|e| format!("Unable to read history database {:?}: {}", path, e))?
+    else {
+        return Ok(None);
+    };
+    if version > SQLITE_SCHEMA_VERSION {
+        return Err(format!(
+            "History database version {} is newer than the supported version {}",
+            version, SQLITE_SCHEMA_VERSION
+        ));
+    }
+    if version < SQLITE_SCHEMA_VERSION {
+        shas.files = std::mem::take(&mut shas.files)
+            .into_iter()
+            .map(|(key, hash)| {
+                let key: String = match version {
+                    1 => relative_legacy_path(&key, root),
+                    _ => key,
+                };
+                (escape_legacy_key(&key), hash)
+            })
+            .collect();
+    }
+    Ok(Some(shas))
+}
+
+/// Whether an error means the file is not a usable SQLite database, rather than one which cannot
+/// be accessed at the moment.
+fn is_corrupt(error: &rusqlite::Error) -> bool {
+    matches!(
+        error.sqlite_error_code(),
+        Some(ErrorCode::NotADatabase) | Some(ErrorCode::DatabaseCorrupt)
+    )
+}
+
+/// Rewrites the recorded path of every file, used to upgrade databases written by earlier versions.
+fn rewrite_sqlite_paths(
+    connection: &mut Connection,
+    rewrite: impl Fn(&str) -> String,
+) -> rusqlite::Result<()> {
+    let transaction = connection.transaction()?;
+    let paths: Vec<String> = {
+        let mut select = transaction.prepare("SELECT path FROM files")?;
+        let paths = select.query_map([], |row| row.get(0))?;
+        paths.collect::<rusqlite::Result<Vec<String>>>()?
+    };
+    for path in paths {
+        transaction.execute(
+            "UPDATE files SET path = ?1 WHERE path = ?2",
+            params![rewrite(&path), path],
+        )?;
+    }
+    transaction.commit()
+}
+
+fn get_meta(connection: &Connection, key: &str) -> rusqlite::Result<Option<String>> {
+    connection
+        .query_row("SELECT value FROM meta WHERE key = ?1", [key], |row| {
+            row.get(0)
+        })
+        .optional()
+}
+
+fn set_meta(connection: &Connection, key: &str, value: &str) -> rusqlite::Result<usize> {
+    connection.execute(
+        "INSERT INTO meta (key, value) VALUES (?1, ?2)
+         ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+        params![key, value],
+    )
+}
+
+/// Returns an empty `ShaFile` carrying the timestamp and algorithm recorded in the database.
+fn load_sqlite_meta(connection: &Connection) -> rusqlite::Result<ShaFile> {
+    let mut shas: ShaFile = ShaFile::new();
+    if let Some(timestamp) = get_meta(connection, "timestamp")? {
+        shas.timestamp = timestamp.parse().unwrap_or(0);
+    }
+    if let Some(algorithm) = get_meta(connection, "algorithm")? {
+        shas.algorithm = algorithm.parse().unwrap_or_default();
+    }
+    Ok(shas)
+}
+
+/// Reads the recorded digests of the given files into a `ShaFile`, one row lookup per file.
+fn load_sqlite_keys<'a>(
+    connection: &Connection,
+    keys: impl IntoIterator<Item = &'a String>,
+) -> rusqlite::Result<ShaFile> {
+    let mut shas: ShaFile = load_sqlite_meta(connection)?;
+    let transaction = connection.unchecked_transaction()?;
+    {
+        let mut select = transaction.prepare("SELECT hash FROM files WHERE path = ?1")?;
+        for key in keys {
+            if let Some(hash) = select
+                .query_row([key], |row| row.get::<_, String>(0))
+                .optional()?
+            {
+                shas.files.insert(key.clone(), hash);
+            }
+        }
+    }
+    transaction.commit()?;
+    Ok(shas)
+}
+
+/// Reads every recorded file digest into a `ShaFile`.
+fn load_sqlite(connection: &Connection) -> rusqlite::Result<ShaFile> {
+    let mut shas: ShaFile = load_sqlite_meta(connection)?;
+
+    let mut statement = connection.prepare("SELECT path, hash FROM files")?;
+    let rows = statement.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    for row in rows {
+        let (path, hash): (String, String) = row?;
+        shas.files.insert(path, hash);
+    }
+    Ok(shas)
+}
+
+/// Returns the size and modification time (seconds since the Unix epoch) of a file, if available.
+fn file_stats(path: &Path) -> (Option<i64>, Option<i64>) {
+    match fs::metadata(path) {
+        Ok(metadata) => {
+            let mtime: Option<i64> = metadata
+                .modified()
+                .ok()
+                .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                .map(|duration| duration.as_secs() as i64);
+            (Some(metadata.len() as i64), mtime)
+        }
+        Err(_) => (None, None),
+    }
+}
+
+/// Records the changed files of a run. Only the rows of changed files are written, unless the
+/// database is new or the hash algorithm changed, in which case every row is synchronised with the
+/// re-baselined history while keeping the snapshot each file was last uploaded in.
+///
+/// # Returns
+///
+/// The number of files recorded in the database.
+fn save_sqlite(
+    connection: &mut Connection,
+    root: &Path,
+    merged: &ShaFile,
+    diff: &ShaFile,
+    snapshot: &str,
+) -> rusqlite::Result<usize> {
+    let stored_algorithm: Option<HashAlgorithm> =
+        get_meta(connection, "algorithm")?.and_then(|algorithm| algorithm.parse().ok());
+    let transaction = connection.transaction()?;
+
+    if stored_algorithm != Some(merged.algorithm) {
+        let stale: Vec<String> = {
+            let mut select = transaction.prepare("SELECT path FROM files")?;
+            let paths = select.query_map([], |row| row.get(0))?;
+            paths
+                .collect::<rusqlite::Result<Vec<String>>>()?
+                .into_iter()
+                .filter(|path| !merged.files.contains_key(path))
+                .collect()
+        };
+        for path in stale {
+            transaction.execute("DELETE FROM files WHERE path = ?1", [path])?;
+        }
+
+        let mut sync = transaction.prepare(
+            "INSERT INTO files (path, hash, size, mtime) VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT(path) DO UPDATE SET
+                hash = excluded.hash,
+                size = excluded.size,
+                mtime = excluded.mtime",
+        )?;
+        for (path, hash) in &merged.files {
+            let (size, mtime) = file_stats(&root.join(decode_key(path)));
+            sync.execute(params![path, hash, size, mtime])?;
+        }
+    }
+
+    let uploaded_at: i64 = now() as i64;
+    {
+        let mut upsert = transaction.prepare(
+            "INSERT INTO files (path, hash, size, mtime, last_snapshot, uploaded_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+             ON CONFLICT(path) DO UPDATE SET
+                hash = excluded.hash,
+                size = excluded.size,
+                mtime = excluded.mtime,
+                last_snapshot = excluded.last_snapshot,
+                uploaded_at = excluded.uploaded_at",
+        )?;
+        for (path, hash) in &diff.files {
+            let (size, mtime) = file_stats(&root.join(decode_key(path)));
+            upsert.execute(params![path, hash, size, mtime, snapshot, uploaded_at])?;
+        }
+    }
+
+    set_meta(&transaction, "timestamp", &merged.timestamp.to_string())?;
+    set_meta(&transaction, "algorithm", &merged.algorithm.to_string())?;
+    let entries: i64 = transaction.query_row("SELECT COUNT(*) FROM files", [], |row| row.get(0))?;
+    transaction.commit()?;
+    Ok(entries as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use tempfile::TempDir;
+
+    fn shas(algorithm: HashAlgorithm, entries: &[(&str, &str)]) -> ShaFile {
+        let mut shas: ShaFile = ShaFile::with_algorithm(algorithm);
+        shas.files = entries
+            .iter()
+            .map(|(key, hash)| (key.to_string(), hash.to_string()))
+            .collect();
+        shas
+    }
+
+    fn sqlite_store(directory: &TempDir) -> HistoryStore {
+        let path: PathBuf = directory.path().join(SANDMAN_HISTORY_DB);
+        HistoryStore::Sqlite {
+            connection: open_sqlite(&path, directory.path()).unwrap(),
+            json_path: directory.path().join(SANDMAN_HISTORY),
+            root: directory.path().to_path_buf(),
+        }
+    }
+
+    fn keys(keys: &[&str]) -> Vec<String> {
+        keys.iter().map(|key| key.to_string()).collect()
+    }
+
+    #[test]
+    fn saved_runs_are_loaded_back() {
+        let directory: TempDir = TempDir::new().unwrap();
+        let mut store: HistoryStore = sqlite_store(&directory);
+        let merged: ShaFile = shas(HashAlgorithm::Blake3, &[("a.txt", "aa"), ("b.txt", "bb")]);
+
+        assert_eq!(store.save(&merged, &merged, "s1").unwrap(), 2);
+
+        let loaded: ShaFile = store.load().unwrap();
+        assert_eq!(loaded.files, merged.files);
+        assert_eq!(loaded.algorithm, HashAlgorithm::Blake3);
+        assert_eq!(loaded.timestamp, merged.timestamp);
+        assert_eq!(store.timestamp(), merged.timestamp);
+    }
+
+    #[test]
+    fn only_requested_keys_are_loaded() {
+        let directory: TempDir = TempDir::new().unwrap();
+        let mut store: HistoryStore = sqlite_store(&directory);
+        let merged: ShaFile = shas(HashAlgorithm::Sha256, &[("a.txt", "aa"), ("b.txt", "bb")]);
+        store.save(&merged, &merged, "s1").unwrap();
+
+        let loaded: ShaFile = store.load_keys(&keys(&["b.txt", "new.txt"])).unwrap();
+
+        assert_eq!(
+            loaded.files,
+            HashMap::from([("b.txt".to_string(), "bb".to_string())])
+        );
+        assert_eq!(loaded.timestamp, merged.timestamp);
+    }
+
+    #[test]
+    fn uploads_are_recorded_per_file() {
+        let directory: TempDir = TempDir::new().unwrap();
+        let mut store: HistoryStore = sqlite_store(&directory);
+        let first: ShaFile = shas(HashAlgorithm::Sha256, &[("a.txt", "aa"), ("b.txt", "bb")]);
+        store.save(&first, &first, "s1").unwrap();
+        let changed: ShaFile = shas(HashAlgorithm::Sha256, &[("b.txt", "b2")]);
+        let merged: ShaFile = shas(HashAlgorithm::Sha256, &[("a.txt", "aa"), ("b.txt", "b2")]);
+
+        assert_eq!(store.save(&merged, &changed, "s2").unwrap(), 2);
+
+        let a: FileRecord = store.file_record("a.txt").unwrap().unwrap();
+        let b: FileRecord = store.file_record("b.txt").unwrap().unwrap();
+        assert_eq!(a.snapshot.as_deref(), Some("s1"));
+        assert_eq!(b.snapshot.as_deref(), Some("s2"));
+        assert!(b.uploaded_at.unwrap() >= a.uploaded_at.unwrap());
+        assert!(store.file_record("missing.txt").unwrap().is_none());
+        assert_eq!(store.load().unwrap().files, merged.files);
+    }
+
+    #[test]
+    fn changing_the_algorithm_replaces_every_row() {
+        let directory: TempDir = TempDir::new().unwrap();
+        let mut store: HistoryStore = sqlite_store(&directory);
+        let first: ShaFile = shas(
+            HashAlgorithm::Sha256,
+            &[("a.txt", "aa"), ("gone.txt", "gg")],
+        );
+        store.save(&first, &first, "s1").unwrap();
+        let rebaselined: ShaFile = shas(HashAlgorithm::Xxh3, &[("a.txt", "a3")]);
+        let empty: ShaFile = shas(HashAlgorithm::Xxh3, &[]);
+
+        assert_eq!(store.save(&rebaselined, &empty, "s2").unwrap(), 1);
+
+        assert_eq!(store.load().unwrap(), rebaselined);
+        let a: FileRecord = store.file_record("a.txt").unwrap().unwrap();
+        assert_eq!(a.snapshot.as_deref(), Some("s1"));
+    }
+
+    #[test]
+    fn new_databases_are_seeded_from_the_json_history() {
+        let directory: TempDir = TempDir::new().unwrap();
+        let json: ShaFile = shas(HashAlgorithm::Sha256, &[("a.txt", "aa"), ("b.txt", "bb")]);
+        write_file_shas(&json, &directory.path().join(SANDMAN_HISTORY)).unwrap();
+        let mut store: HistoryStore = sqlite_store(&directory);
+
+        assert_eq!(store.load().unwrap(), json);
+        assert_eq!(store.load_keys(&keys(&["a.txt"])).unwrap(), json);
+
+        let merged: ShaFile = shas(HashAlgorithm::Sha256, &[("a.txt", "a2"), ("b.txt", "bb")]);
+        let changed: ShaFile = shas(HashAlgorithm::Sha256, &[("a.txt", "a2")]);
+        store.save(&merged, &changed, "s1").unwrap();
+        assert_eq!(store.load_keys(&keys(&["a.txt"])).unwrap().files.len(), 1);
+        assert_eq!(store.load().unwrap().files, merged.files);
+    }
+
+    #[test]
+    fn json_histories_do_not_record_uploads() {
+        let directory: TempDir = TempDir::new().unwrap();
+        let mut store: HistoryStore = HistoryStore::Json {
+            path: directory.path().join(SANDMAN_HISTORY),
+            root: directory.path().to_path_buf(),
+        };
+        let merged: ShaFile = shas(HashAlgorithm::Sha256, &[("a.txt", "aa")]);
+
+        assert_eq!(store.save(&merged, &merged, "s1").unwrap(), 1);
+
+        assert_eq!(store.load_keys(&keys(&["a.txt"])).unwrap(), merged);
+        assert!(store.file_record("a.txt").is_err());
+    }
+
+    /// Creates a database as written by schema version 1, recording full local paths.
+    fn version_1_database(directory: &TempDir) -> PathBuf {
+        let path: PathBuf = directory.path().join(SANDMAN_HISTORY_DB);
+        let connection: Connection = Connection::open(&path).unwrap();
+        connection.execute_batch(SQLITE_SCHEMA).unwrap();
+        connection.pragma_update(None, "user_version", 1).unwrap();
+        set_meta(&connection, "timestamp", "7").unwrap();
+        let absolute: String = format!("{}/50%.txt", directory.path().display());
+        connection
+            .execute(
+                "INSERT INTO files (path, hash) VALUES (?1, 'aa')",
+                [absolute],
+            )
+            .unwrap();
+        path
+    }
+
+    #[test]
+    fn old_databases_are_migrated() {
+        let directory: TempDir = TempDir::new().unwrap();
+        let path: PathBuf = version_1_database(&directory);
+
+        let connection: Connection = open_sqlite(&path, directory.path()).unwrap();
+
+        let version: i64 = connection
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, SQLITE_SCHEMA_VERSION);
+        assert_eq!(
+            load_sqlite(&connection).unwrap().files,
+            HashMap::from([("50%25.txt".to_string(), "aa".to_string())])
+        );
+    }
+
+    #[test]
+    fn peeking_migrates_in_memory_only() {
+        let directory: TempDir = TempDir::new().unwrap();
+        let path: PathBuf = version_1_database(&directory);
+        let before: Vec<u8> = fs::read(&path).unwrap();
+
+        let peeked: ShaFile = peek_sqlite(&path, directory.path()).unwrap().unwrap();
+
+        assert_eq!(peeked.timestamp, 7);
+        assert_eq!(
+            peeked.files,
+            HashMap::from([("50%25.txt".to_string(), "aa".to_string())])
+        );
+        assert_eq!(fs::read(&path).unwrap(), before);
+    }
+
+    #[test]
+    fn peeking_unused_databases_finds_no_history() {
+        let directory: TempDir = TempDir::new().unwrap();
+        let path: PathBuf = directory.path().join(SANDMAN_HISTORY_DB);
+        drop(open_sqlite(&path, directory.path()).unwrap());
+
+        assert!(peek_sqlite(&path, directory.path()).unwrap().is_none());
+    }
+
+    #[test]
+    fn corrupt_databases_are_quarantined_and_rebuilt() {
+        let directory: TempDir = TempDir::new().unwrap();
+        let path: PathBuf = directory.path().join(SANDMAN_HISTORY_DB);
+        fs::write(&path, vec![b'x'; 4096]).unwrap();
+
+        let connection: Connection = open_sqlite(&path, directory.path()).unwrap();
+
+        assert!(load_sqlite(&connection).unwrap().files.is_empty());
+        assert!(peek_sqlite(&path, directory.path()).unwrap().is_none());
+        let quarantined: usize = fs::read_dir(directory.path())
+            .unwrap()
+            .filter(|entry| {
+                let name: String = entry
+                    .as_ref()
+                    .unwrap()
+                    .file_name()
+                    .to_string_lossy()
+                    .into_owned();
+                name.starts_with(".sandman_history.sqlite.corrupt-")
+            })
+            .count();
+        assert_eq!(quarantined, 1);
+    }
+}